@@ -1,7 +1,16 @@
 use std::path::PathBuf;
 use img_reader::LabelType;
-use ans::{augment_split, SplitOffset};
-use ans::return_type::ImgFormat;
+use img_reader::stain_norm::StainNormalization;
+use ans::SplitOffset;
+use ans::augment_split::{AugmentOptions, AugmentSplit, InputOptions, OutputOptions, TileOptions};
+use ans::color_values::ColorValues;
+use ans::return_type::{BorderPolicy, ImgFormat, OutputLayout, OutputPolicy, RunMode, ShardSize};
+use ans::augment::{Augmentation, Cutout};
+use ans::config;
+use ans::tissue::TissueDetection;
+use ans::coco::Segmentation;
+use ans::boxes::BoxExport;
+use error::{AnsError, AnsResult, ErrorPolicy};
 
 use image;
 
@@ -13,9 +22,30 @@ pub struct AugmentSplitBuilder {
     split_size: Option<(u32, u32)>,
     split_offset: (Option<SplitOffset>, Option<SplitOffset>),
     img_format: Option<ImgFormat>,
+    background: Option<(ColorValues, f32)>,
     rotation: bool,
     output_real: Option<PathBuf>,
     output_mask: Option<PathBuf>,
+    stain_normalization: Option<StainNormalization>,
+    scales: Vec<f32>,
+    context_scales: Vec<f32>,
+    random_zoom: Option<(f32, f32)>,
+    augmentations: Vec<Augmentation>,
+    cutout: Option<Cutout>,
+    augmentation_config: Option<PathBuf>,
+    partitions: Option<(f32, f32, f32)>,
+    output_layout: OutputLayout,
+    coco: Option<Segmentation>,
+    boxes: Option<BoxExport>,
+    output_policy: OutputPolicy,
+    error_policy: ErrorPolicy,
+    run_mode: RunMode,
+    preview: Option<(PathBuf, u32)>,
+    border_policy: BorderPolicy,
+    tissue_detection: Option<TissueDetection>,
+    tissue_masks: Option<PathBuf>,
+    slide_level: usize,
+    slide_region: u32,
 }
 
 impl AugmentSplitBuilder {
@@ -26,9 +56,30 @@ impl AugmentSplitBuilder {
             split_size: None,
             split_offset: (None, None),
             img_format: None,
+            background: None,
             rotation: false,
             output_real: None,
             output_mask: None,
+            stain_normalization: None,
+            scales: vec![1.0],
+            context_scales: vec![],
+            random_zoom: None,
+            augmentations: vec![],
+            cutout: None,
+            augmentation_config: None,
+            partitions: None,
+            output_layout: OutputLayout::Flat,
+            coco: None,
+            boxes: None,
+            output_policy: OutputPolicy::Fail,
+            error_policy: ErrorPolicy::Abort,
+            run_mode: RunMode::Write,
+            preview: None,
+            border_policy: BorderPolicy::Drop,
+            tissue_detection: None,
+            tissue_masks: None,
+            slide_level: 0,
+            slide_region: 4096,
         }
     }
     pub fn set_img_dir(mut self, path: PathBuf) -> AugmentSplitBuilder {
@@ -43,15 +94,114 @@ impl AugmentSplitBuilder {
         self.output_mask = Some(PathBuf::from(path));
         self
     }
+    pub fn set_output_policy(mut self, policy: OutputPolicy) -> AugmentSplitBuilder {
+        self.output_policy = policy;
+        self
+    }
+    pub fn set_error_policy(mut self, policy: ErrorPolicy) -> AugmentSplitBuilder {
+        self.error_policy = policy;
+        self
+    }
+    pub fn set_run_mode(mut self, mode: RunMode) -> AugmentSplitBuilder {
+        self.run_mode = mode;
+        self
+    }
+    pub fn with_preview(mut self, path: &str, max_size: u32) -> AugmentSplitBuilder {
+        self.preview = Some((PathBuf::from(path), max_size));
+        self
+    }
 
     pub fn set_label_type(mut self, label_type: LabelType) -> AugmentSplitBuilder {
         self.label_type = Some(label_type);
         self
     }
+    pub fn set_stain_normalization(mut self,
+                                   normalization: StainNormalization)
+                                   -> AugmentSplitBuilder {
+        self.stain_normalization = Some(normalization);
+        self
+    }
     pub fn set_split_size(mut self, size: Option<(u32, u32)>) -> AugmentSplitBuilder {
         self.split_size = size;
         self
     }
+    // Tiled TIFF sources are only cut at scale 1.0
+    pub fn set_scales(mut self, scales: Vec<f32>) -> AugmentSplitBuilder {
+        self.scales = scales;
+        self
+    }
+    pub fn set_context_scales(mut self, scales: Vec<f32>) -> AugmentSplitBuilder {
+        self.context_scales = scales;
+        self
+    }
+    pub fn with_random_zoom(mut self, min: f32, max: f32) -> AugmentSplitBuilder {
+        self.random_zoom = Some((min, max));
+        self
+    }
+    pub fn set_augmentations(mut self, augmentations: Vec<Augmentation>) -> AugmentSplitBuilder {
+        self.augmentations = augmentations;
+        self
+    }
+    pub fn set_cutout(mut self, cutout: Cutout) -> AugmentSplitBuilder {
+        self.cutout = Some(cutout);
+        self
+    }
+    // Reads the augmentations and the cutout of the <augment> element of an XML config file when
+    // the builder is built, in addition to the ones set directly
+    pub fn set_augmentation_config(mut self, path: &str) -> AugmentSplitBuilder {
+        self.augmentation_config = Some(PathBuf::from(path));
+        self
+    }
+    pub fn set_partitions(mut self, train: f32, val: f32, test: f32) -> AugmentSplitBuilder {
+        self.partitions = Some((train, val, test));
+        self
+    }
+    pub fn set_output_layout(mut self, layout: OutputLayout) -> AugmentSplitBuilder {
+        self.output_layout = layout;
+        self
+    }
+    // Writes the instances in the masks of the tiles into annotations_<pass>.json, rewritten after
+    // every source image. On resume the tiles of the finished source images are kept.
+    pub fn with_coco(mut self, segmentation: Segmentation) -> AugmentSplitBuilder {
+        self.coco = Some(segmentation);
+        self
+    }
+    // Writes the bounding boxes of the instances in the mask of every tile beside the real tile,
+    // YOLO class names go into classes.txt in the directory of the real tiles
+    pub fn with_boxes(mut self, boxes: BoxExport) -> AugmentSplitBuilder {
+        self.boxes = Some(boxes);
+        self
+    }
+    pub fn set_border_policy(mut self, policy: BorderPolicy) -> AugmentSplitBuilder {
+        self.border_policy = policy;
+        self
+    }
+    // Split discards tiles with at least fraction of their pixels matching color, by default
+    // black pixels with the channels of the source image and 0.2. The channels of color have to
+    // match the source images.
+    pub fn set_background(mut self, color: ColorValues, fraction: f32) -> AugmentSplitBuilder {
+        self.background = Some((color, fraction));
+        self
+    }
+    pub fn set_tissue_detection(mut self, detection: TissueDetection) -> AugmentSplitBuilder {
+        self.tissue_detection = Some(detection);
+        self
+    }
+    // Slides get a single mask at the resolution the tissue is detected on
+    pub fn save_tissue_masks(mut self, path: &str) -> AugmentSplitBuilder {
+        self.tissue_masks = Some(PathBuf::from(path));
+        self
+    }
+    // Pyramid level tiled TIFF sources are cut at, 0 is the full resolution
+    pub fn set_slide_level(mut self, level: usize) -> AugmentSplitBuilder {
+        self.slide_level = level;
+        self
+    }
+    // Side length of the regions read from tiled TIFF sources at once
+    pub fn set_slide_region(mut self, size: u32) -> AugmentSplitBuilder {
+        self.slide_region = size;
+        self
+    }
     pub fn with_rotation(mut self) -> AugmentSplitBuilder {
         self.rotation = true;
         self
@@ -72,20 +222,103 @@ impl AugmentSplitBuilder {
         self.img_format = Some(ImgFormat::Img(format));
         self
     }
+    pub fn set_tfrecord(mut self, shard_size: usize) -> AugmentSplitBuilder {
+        self.img_format = Some(ImgFormat::TfRecord { shard_size: shard_size });
+        self
+    }
+    pub fn set_webdataset(mut self, shard_size: ShardSize) -> AugmentSplitBuilder {
+        self.img_format = Some(ImgFormat::WebDataset(shard_size));
+        self
+    }
 
-    pub fn build(self) -> augment_split::AugmentSplit {
-        augment_split::AugmentSplit::build(self.img_dir
-                                               .expect("Called AugmentSplitBuilder.build() \
-                                                        without setting img_dir"),
-                                           self.label_type
-                                               .expect("Called AugmentSplitBuilder.build() \
-                                                        without setting label_type"),
-                                           self.split_size,
-                                           self.split_offset,
-                                           self.img_format.unwrap(),
-                                           None,
-                                           self.rotation,
-                                           self.output_real.unwrap(),
-                                           self.output_mask)
+    pub fn build(mut self) -> AnsResult<AugmentSplit> {
+        if let Some(path) = self.augmentation_config.take() {
+            self.augmentations.extend(try!(config::read_augmentations(&path)));
+            if let Some(cutout) = try!(config::read_cutout(&path)) {
+                self.cutout = Some(cutout);
+            }
+        }
+        if self.scales.iter().chain(self.context_scales.iter()).any(|&s| s <= 0.0) {
+            return Err(AnsError::InvalidSetting(String::from("scale factor <= 0.0")));
+        }
+        if let Some((train, val, test)) = self.partitions {
+            if train < 0.0 || val < 0.0 || test < 0.0 || train + val + test <= 0.0 {
+                return Err(AnsError::InvalidSetting(String::from("partition fractions")));
+            }
+        }
+        if let Some((min, max)) = self.random_zoom {
+            if min <= 0.0 || min > max {
+                return Err(AnsError::InvalidSetting(String::from("random zoom range")));
+            }
+        }
+        if let Some((_, fraction)) = self.background {
+            if fraction < 0.0 || fraction > 1.0 {
+                return Err(AnsError::InvalidSetting(String::from("background fraction")));
+            }
+        }
+        if self.slide_region == 0 {
+            return Err(AnsError::InvalidSetting(String::from("slide region size of 0")));
+        }
+        match self.img_format {
+            Some(ImgFormat::TfRecord { shard_size: 0 }) |
+            Some(ImgFormat::WebDataset(ShardSize::Samples(0))) |
+            Some(ImgFormat::WebDataset(ShardSize::Bytes(0))) => {
+                return Err(AnsError::InvalidSetting(String::from("shard size of 0")));
+            }
+            _ => {}
+        }
+        match self.img_format {
+            Some(ImgFormat::Img(_)) => {}
+            _ if self.output_layout != OutputLayout::Flat => {
+                return Err(AnsError::InvalidSetting(String::from("output layout requires an \
+                                                                  image format")))
+            }
+            _ if self.coco.is_some() => {
+                return Err(AnsError::InvalidSetting(String::from("COCO export requires an image \
+                                                                  format")))
+            }
+            _ if self.boxes.is_some() => {
+                return Err(AnsError::InvalidSetting(String::from("box export requires an image \
+                                                                  format")))
+            }
+            _ => {}
+        }
+        let input = InputOptions {
+            img_dir: try!(self.img_dir.ok_or(AnsError::MissingSetting("img_dir"))),
+            label_type: try!(self.label_type.ok_or(AnsError::MissingSetting("label_type"))),
+            stain_normalization: self.stain_normalization,
+            error_policy: self.error_policy,
+            slide_level: self.slide_level,
+            slide_region: self.slide_region,
+        };
+        let tiles = TileOptions {
+            split_size: self.split_size,
+            split_offset: self.split_offset,
+            border_policy: self.border_policy,
+            background: self.background,
+            tissue_detection: self.tissue_detection,
+            tissue_masks: self.tissue_masks,
+        };
+        let augment = AugmentOptions {
+            rotation: self.rotation,
+            scales: self.scales,
+            context_scales: self.context_scales,
+            random_zoom: self.random_zoom,
+            augmentations: self.augmentations,
+            cutout: self.cutout,
+        };
+        let output = OutputOptions {
+            img_format: try!(self.img_format.ok_or(AnsError::MissingSetting("img_type"))),
+            output_real: try!(self.output_real.ok_or(AnsError::MissingSetting("output_real"))),
+            output_mask: self.output_mask,
+            output_layout: self.output_layout,
+            partitions: self.partitions,
+            coco: self.coco,
+            boxes: self.boxes,
+            output_policy: self.output_policy,
+            run_mode: self.run_mode,
+            preview: self.preview,
+        };
+        Ok(AugmentSplit::build(input, tiles, augment, output))
     }
 }
@@ -0,0 +1,642 @@
+use image::*;
+use rand::*;
+use rand::distributions::{Normal, IndependentSample};
+
+use ans::split_image::SplitImage;
+use img_reader::raster::Raster;
+
+// Photometric augmentations only ever touch the real image, the mask stays as it is
+#[derive(Clone, Debug)]
+pub enum Photometric {
+    GaussianNoise {
+        sigma: (f32, f32),
+    },
+    SaltAndPepper {
+        amount: (f32, f32),
+    },
+    GaussianBlur {
+        sigma: (f32, f32),
+    },
+    MotionBlur {
+        length: (u32, u32),
+    },
+    // A simulation of JPEG re-compression, as the JPEG encoder of image has neither a quality
+    // setting nor chroma subsampling. Chroma is subsampled 4:2:0 and the DCT coefficients of every
+    // 8x8 block are quantized with the IJG tables for the quality, leaving out the lossless
+    // entropy coding. quality is between 1 and 100.
+    Jpeg {
+        quality: (u8, u8),
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct Augmentation {
+    pub kind: Photometric,
+    pub probability: f32,
+}
+
+impl Augmentation {
+    pub fn new(kind: Photometric, probability: f32) -> Augmentation {
+        Augmentation {
+            kind: kind,
+            probability: probability,
+        }
+    }
+
+    // Returns the augmented image and a short description for the file name, or None if the
+    // augmentation was not drawn for this image
+    pub fn apply(&self, image: &DynamicImage, rng: &mut StdRng) -> Option<(DynamicImage, String)> {
+        if rng.next_f32() >= self.probability {
+            return None;
+        }
+        let mut image = image.clone();
+
+        let description = match self.kind {
+            Photometric::GaussianNoise { sigma } => {
+                let sigma = sample_f32(rng, sigma);
+                let normal = Normal::new(0.0, sigma as f64);
+                map_color_channels(&mut image, |channels| {
+                    for c in channels.iter_mut() {
+                        *c = clamp_u8(*c as f32 + normal.ind_sample(rng) as f32);
+                    }
+                });
+                format!("gnoise{:.1}", sigma)
+            }
+            Photometric::SaltAndPepper { amount } => {
+                let amount = sample_f32(rng, amount);
+                map_color_channels(&mut image, |channels| {
+                    if rng.next_f32() < amount {
+                        let value = if rng.gen() { 255 } else { 0 };
+                        for c in channels.iter_mut() {
+                            *c = value;
+                        }
+                    }
+                });
+                format!("snp{:.3}", amount)
+            }
+            Photometric::GaussianBlur { sigma } => {
+                let sigma = sample_f32(rng, sigma);
+                image = image.blur(sigma);
+                format!("blur{:.1}", sigma)
+            }
+            Photometric::MotionBlur { length } => {
+                let length = if length.0 < length.1 {
+                    rng.gen_range(length.0, length.1 + 1)
+                } else {
+                    length.0
+                };
+                let angle = rng.gen_range(0.0, ::std::f32::consts::PI);
+                image = motion_blur(&image, length, angle);
+                format!("mblur{}", length)
+            }
+            Photometric::Jpeg { quality } => {
+                let quality = if quality.0 < quality.1 {
+                    rng.gen_range(quality.0 as u32, quality.1 as u32 + 1) as u8
+                } else {
+                    quality.0
+                };
+                image = simulate_jpeg(&image, quality);
+                format!("jpeg{}", quality)
+            }
+        };
+        Some((image, description))
+    }
+}
+
+// Applies every augmentation with its own probability to the real image of split, returns None
+// if none of them has been drawn
+pub fn augment(augmentations: &[Augmentation],
+               split: &SplitImage,
+               rng: &mut StdRng)
+               -> Option<SplitImage> {
+    let mut augmented = split.clone();
+    let mut applied = false;
+
+    if let Some(mut real) = split.real.clone() {
+        for augmentation in augmentations {
+            if let Some((image, description)) = augmentation.apply(&real, rng) {
+                real = image;
+                augmented.add_augmentation(description);
+                applied = true;
+            }
+        }
+        augmented.set_real(real);
+    }
+
+    if applied {
+        Some(augmented)
+    } else {
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum CutoutMask {
+    // Only the real image is erased, the mask and label stay as they are
+    Keep,
+    // The erased rectangles are zeroed in the mask as well, the tile is labelled again if most of
+    // its positive pixels are erased
+    Erase,
+}
+
+// Blanks between count.0 and count.1 rectangles in the real image, their side lengths are drawn
+// as a fraction of the tile size from size
+#[derive(Clone, Debug)]
+pub struct Cutout {
+    pub count: (u32, u32),
+    pub size: (f32, f32),
+    pub probability: f32,
+    pub mask: CutoutMask,
+}
+
+impl Cutout {
+    pub fn new(count: (u32, u32), size: (f32, f32), probability: f32, mask: CutoutMask) -> Cutout {
+        Cutout {
+            count: count,
+            size: size,
+            probability: probability,
+            mask: mask,
+        }
+    }
+
+    pub fn apply(&self, split: &SplitImage, rng: &mut StdRng) -> Option<SplitImage> {
+        if rng.next_f32() >= self.probability {
+            return None;
+        }
+        let (width, height) = (split.get_x_dim(), split.get_y_dim());
+        let count = if self.count.0 < self.count.1 {
+            rng.gen_range(self.count.0, self.count.1 + 1)
+        } else {
+            self.count.0
+        };
+
+        let mut rects = vec![];
+        for _ in 0..count {
+            let w = ((sample_f32(rng, self.size) * width as f32).round() as u32).max(1).min(width);
+            let h = ((sample_f32(rng, self.size) * height as f32).round() as u32).max(1).min(height);
+            let x = rng.gen_range(0, width - w + 1);
+            let y = rng.gen_range(0, height - h + 1);
+            rects.push((x, y, w, h));
+        }
+
+        let mut erased = split.clone();
+        if let Some(ref mut real) = erased.real {
+            erase(real, &rects);
+        }
+        if let Some(ref mut deep) = erased.deep {
+            erase_raster(deep, &rects);
+        }
+        if let CutoutMask::Erase = self.mask {
+            if let Some(ref mut mask) = erased.mask {
+                erase(mask, &rects);
+            }
+        }
+        erased.add_augmentation(format!("cutout{}", count));
+        Some(erased)
+    }
+}
+
+fn erase(image: &mut DynamicImage, rects: &[(u32, u32, u32, u32)]) {
+    let (width, height) = image.dimensions();
+    for &(x, y, w, h) in rects {
+        for j in y..(y + h).min(height) {
+            for i in x..(x + w).min(width) {
+                match *image {
+                    DynamicImage::ImageLuma8(ref mut img) => img.put_pixel(i, j, Luma { data: [0] }),
+                    DynamicImage::ImageLumaA8(ref mut img) => {
+                        img.get_pixel_mut(i, j).data[0] = 0;
+                    }
+                    DynamicImage::ImageRgb8(ref mut img) => {
+                        img.put_pixel(i, j, Rgb { data: [0, 0, 0] })
+                    }
+                    DynamicImage::ImageRgba8(ref mut img) => {
+                        let p = img.get_pixel_mut(i, j);
+                        p.data[0] = 0;
+                        p.data[1] = 0;
+                        p.data[2] = 0;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn erase_raster(raster: &mut Raster, rects: &[(u32, u32, u32, u32)]) {
+    let (width, height) = raster.dimensions();
+    for &(x, y, w, h) in rects {
+        for j in y..(y + h).min(height) {
+            for i in x..(x + w).min(width) {
+                for v in raster.pixel_mut(i, j) {
+                    *v = 0;
+                }
+            }
+        }
+    }
+}
+
+fn sample_f32(rng: &mut StdRng, range: (f32, f32)) -> f32 {
+    if range.0 < range.1 {
+        rng.gen_range(range.0, range.1)
+    } else {
+        range.0
+    }
+}
+
+// Calls f with the color channels of every pixel, alpha channels are left untouched
+fn map_color_channels<F>(image: &mut DynamicImage, mut f: F)
+    where F: FnMut(&mut [u8])
+{
+    match *image {
+        DynamicImage::ImageLuma8(ref mut img) => {
+            for p in img.pixels_mut() {
+                f(&mut p.data[..]);
+            }
+        }
+        DynamicImage::ImageLumaA8(ref mut img) => {
+            for p in img.pixels_mut() {
+                f(&mut p.data[..1]);
+            }
+        }
+        DynamicImage::ImageRgb8(ref mut img) => {
+            for p in img.pixels_mut() {
+                f(&mut p.data[..]);
+            }
+        }
+        DynamicImage::ImageRgba8(ref mut img) => {
+            for p in img.pixels_mut() {
+                f(&mut p.data[..3]);
+            }
+        }
+    }
+}
+
+fn motion_blur(image: &DynamicImage, length: u32, angle: f32) -> DynamicImage {
+    let source = image.to_rgba();
+    let (width, height) = source.dimensions();
+    let length = length.max(1);
+    let (dx, dy) = (angle.cos(), angle.sin());
+
+    let blurred = ImageBuffer::from_fn(width, height, |x, y| {
+        let mut sum = [0f32; 4];
+        for k in 0..length {
+            let t = k as f32 - (length - 1) as f32 / 2.0;
+            let sx = (x as f32 + t * dx).round().max(0.0).min((width - 1) as f32) as u32;
+            let sy = (y as f32 + t * dy).round().max(0.0).min((height - 1) as f32) as u32;
+            let p = source.get_pixel(sx, sy);
+            for c in 0..4 {
+                sum[c] += p.data[c] as f32;
+            }
+        }
+        let n = length as f32;
+        Rgba {
+            data: [clamp_u8(sum[0] / n),
+                   clamp_u8(sum[1] / n),
+                   clamp_u8(sum[2] / n),
+                   clamp_u8(sum[3] / n)],
+        }
+    });
+
+    match *image {
+        DynamicImage::ImageRgba8(_) => DynamicImage::ImageRgba8(blurred),
+        DynamicImage::ImageRgb8(_) => {
+            DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(blurred).to_rgb())
+        }
+        DynamicImage::ImageLumaA8(_) => {
+            DynamicImage::ImageLumaA8(DynamicImage::ImageRgba8(blurred).to_luma_alpha())
+        }
+        DynamicImage::ImageLuma8(_) => {
+            DynamicImage::ImageLuma8(DynamicImage::ImageRgba8(blurred).to_luma())
+        }
+    }
+}
+
+const JPEG_LUMA_QTABLE: [u8; 64] = [16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60,
+                                    55, 14, 13, 16, 24, 40, 57, 69, 56, 14, 17, 22, 29, 51, 87,
+                                    80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81,
+                                    104, 113, 92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92, 95,
+                                    98, 112, 100, 103, 99];
+
+const JPEG_CHROMA_QTABLE: [u8; 64] = [17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99,
+                                      99, 24, 26, 56, 99, 99, 99, 99, 99, 47, 66, 99, 99, 99, 99,
+                                      99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+                                      99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+                                      99, 99, 99, 99];
+
+fn simulate_jpeg(image: &DynamicImage, quality: u8) -> DynamicImage {
+    let luma_table = scale_qtable(&JPEG_LUMA_QTABLE, quality);
+    let chroma_table = scale_qtable(&JPEG_CHROMA_QTABLE, quality);
+
+    match *image {
+        DynamicImage::ImageLuma8(ref img) => {
+            let (width, height) = img.dimensions();
+            let mut plane = img.pixels().map(|p| p.data[0] as f32).collect::<Vec<_>>();
+            quantize_plane(&mut plane, width, height, &luma_table);
+
+            let mut buffer = img.clone();
+            for (p, v) in buffer.pixels_mut().zip(plane) {
+                p.data[0] = clamp_u8(v);
+            }
+            DynamicImage::ImageLuma8(buffer)
+        }
+        _ => {
+            let rgb = image.to_rgb();
+            let (width, height) = rgb.dimensions();
+            let mut planes = [Vec::new(), Vec::new(), Vec::new()];
+            for p in rgb.pixels() {
+                let ycbcr = rgb_to_ycbcr(p.data);
+                for c in 0..3 {
+                    planes[c].push(ycbcr[c]);
+                }
+            }
+            quantize_plane(&mut planes[0], width, height, &luma_table);
+            for plane in planes[1..].iter_mut() {
+                let (mut subsampled, sub_width, sub_height) = subsample(plane, width, height);
+                quantize_plane(&mut subsampled, sub_width, sub_height, &chroma_table);
+                for (i, v) in plane.iter_mut().enumerate() {
+                    let (x, y) = ((i % width as usize) / 2, (i / width as usize) / 2);
+                    *v = subsampled[y * sub_width as usize + x];
+                }
+            }
+
+            let mut buffer = rgb.clone();
+            for (i, p) in buffer.pixels_mut().enumerate() {
+                p.data = ycbcr_to_rgb([planes[0][i], planes[1][i], planes[2][i]]);
+            }
+
+            match *image {
+                DynamicImage::ImageRgba8(ref img) => {
+                    let mut rgba = img.clone();
+                    for (p, q) in rgba.pixels_mut().zip(buffer.pixels()) {
+                        p.data[0] = q.data[0];
+                        p.data[1] = q.data[1];
+                        p.data[2] = q.data[2];
+                    }
+                    DynamicImage::ImageRgba8(rgba)
+                }
+                DynamicImage::ImageLumaA8(_) => {
+                    DynamicImage::ImageLumaA8(DynamicImage::ImageRgb8(buffer).to_luma_alpha())
+                }
+                _ => DynamicImage::ImageRgb8(buffer),
+            }
+        }
+    }
+}
+
+// Scaling of the quantization tables as done by the IJG encoder
+fn scale_qtable(table: &[u8; 64], quality: u8) -> [f32; 64] {
+    let quality = (quality.max(1).min(100)) as u32;
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - 2 * quality
+    };
+    let mut scaled = [0f32; 64];
+    for i in 0..64 {
+        scaled[i] = ((table[i] as u32 * scale + 50) / 100).max(1).min(255) as f32;
+    }
+    scaled
+}
+
+// Averages every 2x2 block of a plane, returns the plane and its dimensions
+fn subsample(plane: &[f32], width: u32, height: u32) -> (Vec<f32>, u32, u32) {
+    let (sub_width, sub_height) = ((width + 1) / 2, (height + 1) / 2);
+    let mut subsampled = Vec::with_capacity((sub_width * sub_height) as usize);
+    for y in 0..sub_height {
+        for x in 0..sub_width {
+            let mut sum = 0.0;
+            let mut n = 0.0;
+            for py in y * 2..(y * 2 + 2).min(height) {
+                for px in x * 2..(x * 2 + 2).min(width) {
+                    sum += plane[(py * width + px) as usize];
+                    n += 1.0;
+                }
+            }
+            subsampled.push(sum / n);
+        }
+    }
+    (subsampled, sub_width, sub_height)
+}
+
+fn quantize_plane(plane: &mut Vec<f32>, width: u32, height: u32, table: &[f32; 64]) {
+    let (width, height) = (width as usize, height as usize);
+    let mut cos = [[0f32; 8]; 8];
+    for x in 0..8 {
+        for u in 0..8 {
+            cos[x][u] = ((2 * x + 1) as f32 * u as f32 * ::std::f32::consts::PI / 16.0).cos();
+        }
+    }
+    let alpha = |u: usize| if u == 0 { 1.0 / 2f32.sqrt() } else { 1.0 };
+
+    for by in 0..(height + 7) / 8 {
+        for bx in 0..(width + 7) / 8 {
+            // Blocks at the border are filled up by repeating the last row and column
+            let mut block = [[0f32; 8]; 8];
+            for y in 0..8 {
+                for x in 0..8 {
+                    let py = (by * 8 + y).min(height - 1);
+                    let px = (bx * 8 + x).min(width - 1);
+                    block[y][x] = plane[py * width + px] - 128.0;
+                }
+            }
+
+            let mut coefficients = [[0f32; 8]; 8];
+            for v in 0..8 {
+                for u in 0..8 {
+                    let mut sum = 0.0;
+                    for y in 0..8 {
+                        for x in 0..8 {
+                            sum += block[y][x] * cos[x][u] * cos[y][v];
+                        }
+                    }
+                    let c = 0.25 * alpha(u) * alpha(v) * sum;
+                    let q = table[v * 8 + u];
+                    coefficients[v][u] = (c / q).round() * q;
+                }
+            }
+
+            for y in 0..8 {
+                for x in 0..8 {
+                    let py = by * 8 + y;
+                    let px = bx * 8 + x;
+                    if py >= height || px >= width {
+                        continue;
+                    }
+                    let mut sum = 0.0;
+                    for v in 0..8 {
+                        for u in 0..8 {
+                            sum += alpha(u) * alpha(v) * coefficients[v][u] * cos[x][u] * cos[y][v];
+                        }
+                    }
+                    plane[py * width + px] = 0.25 * sum + 128.0;
+                }
+            }
+        }
+    }
+}
+
+fn rgb_to_ycbcr(rgb: [u8; 3]) -> [f32; 3] {
+    let (r, g, b) = (rgb[0] as f32, rgb[1] as f32, rgb[2] as f32);
+    [0.299 * r + 0.587 * g + 0.114 * b,
+     128.0 - 0.168736 * r - 0.331264 * g + 0.5 * b,
+     128.0 + 0.5 * r - 0.418688 * g - 0.081312 * b]
+}
+
+fn ycbcr_to_rgb(ycbcr: [f32; 3]) -> [u8; 3] {
+    let (y, cb, cr) = (ycbcr[0], ycbcr[1] - 128.0, ycbcr[2] - 128.0);
+    [clamp_u8(y + 1.402 * cr),
+     clamp_u8(y - 0.344136 * cb - 0.714136 * cr),
+     clamp_u8(y + 1.772 * cb)]
+}
+
+fn clamp_u8(v: f32) -> u8 {
+    v.max(0.0).min(255.0).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GenericImage, ImageBuffer, Luma, Rgb};
+    use rand::{SeedableRng, StdRng};
+
+    use ans::label::Label;
+    use ans::split_image::SplitImage;
+    use super::{augment, motion_blur, simulate_jpeg, Augmentation, Cutout, CutoutMask,
+                Photometric};
+
+    fn rng() -> StdRng {
+        StdRng::from_seed(&[1, 2, 3])
+    }
+
+    fn texture(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(ImageBuffer::from_fn(width, height, |x, y| {
+            Rgb { data: [(x * 13 + y * 7) as u8, (x * y) as u8, (200 - y * 3) as u8] }
+        }))
+    }
+
+    fn split() -> SplitImage {
+        let mask = DynamicImage::ImageLuma8(ImageBuffer::from_fn(32, 32, |x, _| {
+            Luma { data: [if x < 16 { 255 } else { 0 }] }
+        }));
+        SplitImage::new(&String::from("source"),
+                        texture(32, 32),
+                        mask,
+                        Label::Healthy,
+                        (32, 32),
+                        0,
+                        0,
+                        0)
+    }
+
+    fn max_difference(a: &DynamicImage, b: &DynamicImage) -> u8 {
+        a.raw_pixels()
+            .iter()
+            .zip(b.raw_pixels().iter())
+            .map(|(&a, &b)| if a > b { a - b } else { b - a })
+            .max()
+            .unwrap()
+    }
+
+    fn all() -> Vec<Augmentation> {
+        vec![Augmentation::new(Photometric::GaussianNoise { sigma: (5.0, 10.0) }, 1.0),
+             Augmentation::new(Photometric::SaltAndPepper { amount: (0.01, 0.05) }, 1.0),
+             Augmentation::new(Photometric::GaussianBlur { sigma: (0.5, 1.5) }, 1.0),
+             Augmentation::new(Photometric::MotionBlur { length: (3, 7) }, 1.0),
+             Augmentation::new(Photometric::Jpeg { quality: (10, 90) }, 1.0)]
+    }
+
+    #[test]
+    fn touches_only_the_real_image() {
+        let split = split();
+        let augmented = augment(&all(), &split, &mut rng()).unwrap();
+
+        assert_eq!(augmented.mask.as_ref().unwrap().raw_pixels(),
+                   split.mask.as_ref().unwrap().raw_pixels());
+        let real = augmented.real.as_ref().unwrap();
+        assert_eq!(real.dimensions(), (32, 32));
+        assert!(max_difference(real, split.real.as_ref().unwrap()) > 0);
+    }
+
+    #[test]
+    fn draws_with_the_probability() {
+        let split = split();
+        let never = vec![Augmentation::new(Photometric::GaussianBlur { sigma: (1.0, 1.0) }, 0.0)];
+        let mut rng = rng();
+        for _ in 0..20 {
+            assert!(augment(&never, &split, &mut rng).is_none());
+        }
+
+        let half = vec![Augmentation::new(Photometric::GaussianBlur { sigma: (1.0, 1.0) }, 0.5)];
+        let drawn = (0..200).filter(|_| augment(&half, &split, &mut rng).is_some()).count();
+        assert!(drawn > 70 && drawn < 130);
+    }
+
+    #[test]
+    fn reproducible_with_the_seed() {
+        let split = split();
+        let first = augment(&all(), &split, &mut rng()).unwrap();
+        let second = augment(&all(), &split, &mut rng()).unwrap();
+        assert_eq!(first.real.unwrap().raw_pixels(), second.real.unwrap().raw_pixels());
+    }
+
+    #[test]
+    fn motion_blur_averages_along_the_line() {
+        let stripe = DynamicImage::ImageLuma8(ImageBuffer::from_fn(9, 3, |x, _| {
+            Luma { data: [if x == 4 { 240 } else { 0 }] }
+        }));
+        let blurred = motion_blur(&stripe, 3, 0.0);
+        let row = (0..9).map(|x| blurred.get_pixel(x, 1).data[0]).collect::<Vec<_>>();
+        assert_eq!(row, vec![0, 0, 0, 80, 80, 80, 0, 0, 0]);
+    }
+
+    #[test]
+    fn jpeg_loses_more_at_lower_quality() {
+        // Only the DC coefficient of a flat image is quantized, it stays flat
+        let flat = ImageBuffer::from_pixel(20, 20, Rgb { data: [90, 140, 60] });
+        let flat = DynamicImage::ImageRgb8(flat);
+        let compressed = simulate_jpeg(&flat, 10).to_rgb();
+        assert!(compressed.pixels().all(|p| p == compressed.get_pixel(0, 0)));
+        assert!(max_difference(&DynamicImage::ImageRgb8(compressed), &flat) <= 8);
+
+        let image = texture(24, 24);
+        let fine = max_difference(&simulate_jpeg(&image, 95), &image);
+        let coarse = max_difference(&simulate_jpeg(&image, 5), &image);
+        assert!(fine < coarse);
+        assert!(coarse > 20);
+
+        let luma = DynamicImage::ImageLuma8(image.to_luma());
+        let compressed = simulate_jpeg(&luma, 50);
+        assert_eq!(compressed.color(), luma.color());
+        assert_eq!(compressed.dimensions(), (24, 24));
+    }
+
+    #[test]
+    fn cutout_erases_the_mask_if_asked() {
+        let split = split();
+        let keep = Cutout::new((2, 2), (0.25, 0.5), 1.0, CutoutMask::Keep);
+        let kept = keep.apply(&split, &mut rng()).unwrap();
+        assert_eq!(kept.mask.as_ref().unwrap().raw_pixels(),
+                   split.mask.as_ref().unwrap().raw_pixels());
+
+        let erase = Cutout::new((2, 2), (0.25, 0.5), 1.0, CutoutMask::Erase);
+        let erased = erase.apply(&split, &mut rng()).unwrap();
+        let real = erased.real.as_ref().unwrap().to_rgb();
+        let mask = erased.mask.as_ref().unwrap().to_luma();
+        let original = split.real.as_ref().unwrap().to_rgb();
+        let mut blanked = 0;
+        for (x, y, p) in real.enumerate_pixels() {
+            if p.data == [0, 0, 0] && original.get_pixel(x, y).data != [0, 0, 0] {
+                blanked += 1;
+                assert_eq!(mask.get_pixel(x, y).data[0], 0);
+            } else {
+                assert_eq!(p, original.get_pixel(x, y));
+            }
+        }
+        // Two rectangles of at least 8 x 8 pixels
+        assert!(blanked >= 64);
+        assert_eq!(erased.real.as_ref().unwrap().raw_pixels(),
+                   kept.real.as_ref().unwrap().raw_pixels());
+        assert_eq!(erased.label.map(|l| l.name()), Some("Healthy"));
+
+        let never = Cutout::new((1, 1), (0.5, 0.5), 0.0, CutoutMask::Erase);
+        assert!(never.apply(&split, &mut rng()).is_none());
+    }
+}
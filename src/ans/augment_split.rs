@@ -1,12 +1,13 @@
 // use std::fs::File;
-use std::io::prelude::*;
-use std::fs::OpenOptions;
 // use std::ffi::OsString;
 // use std::io::BufReader;
+use std::fs;
 use std::fs::DirBuilder;
+use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map;
 // use std::collections::hash_map::Entry;
 use rand::*;
 
@@ -14,6 +15,10 @@ use rand::*;
 // use xml::reader::{EventReader, XmlEvent, Error};
 
 use img_reader::{ImgReader, LabelType};
+use img_reader::slide::Slide;
+use img_reader::raster::Raster;
+use img_reader::tiff;
+use img_reader::stain_norm::StainNormalization;
 use image::*;
 
 use ans::label::*;
@@ -23,12 +28,69 @@ use ans::split_image::*;
 use ans::color_values::ColorValues;
 use ans::SplitOffset;
 use ans::ImageKind;
+use ans::manifest::{self, Manifest, ManifestEntry};
+use ans::resample::{self, Interpolation, Padding};
+use ans::augment::{self, Augmentation, Cutout, CutoutMask};
+use ans::stats::Stats;
+use ans::contact_sheet::ContactSheet;
+use ans::preview::{Outcome, Preview};
+use ans::tissue::{self, TissueDetection};
+use ans::integral::IntegralImage;
+use ans::visitor::TileVisitor;
+use ans::shards::ShardPosition;
+use ans::tfrecord::{Example, TfRecordWriter};
+use ans::webdataset::TarShardWriter;
+use ans::coco::{CocoWriter, Segmentation};
+use ans::boxes::BoxExport;
+use error::{AnsError, AnsResult, ErrorPolicy, ErrorReport};
 
 pub trait FindLabel {
     fn label(&mut self, r: f32) -> Option<Label>;
     fn label_fn(&self) -> Option<Label>;
 }
 
+// Options of AugmentSplit::build, grouped by the part of a run they belong to. The fields are
+// documented on AugmentSplit.
+pub struct InputOptions {
+    pub img_dir: PathBuf,
+    pub label_type: LabelType,
+    pub stain_normalization: Option<StainNormalization>,
+    pub error_policy: ErrorPolicy,
+    pub slide_level: usize,
+    pub slide_region: u32,
+}
+
+pub struct TileOptions {
+    pub split_size: Option<(u32, u32)>,
+    pub split_offset: (Option<SplitOffset>, Option<SplitOffset>),
+    pub border_policy: BorderPolicy,
+    pub background: Option<(ColorValues, f32)>,
+    pub tissue_detection: Option<TissueDetection>,
+    pub tissue_masks: Option<PathBuf>,
+}
+
+pub struct AugmentOptions {
+    pub rotation: bool,
+    pub scales: Vec<f32>,
+    pub context_scales: Vec<f32>,
+    pub random_zoom: Option<(f32, f32)>,
+    pub augmentations: Vec<Augmentation>,
+    pub cutout: Option<Cutout>,
+}
+
+pub struct OutputOptions {
+    pub img_format: ImgFormat,
+    pub output_real: PathBuf,
+    pub output_mask: Option<PathBuf>,
+    pub output_layout: OutputLayout,
+    pub partitions: Option<(f32, f32, f32)>,
+    pub coco: Option<Segmentation>,
+    pub boxes: Option<BoxExport>,
+    pub output_policy: OutputPolicy,
+    pub run_mode: RunMode,
+    pub preview: Option<(PathBuf, u32)>,
+}
+
 pub struct AugmentSplit {
     img_dir: PathBuf,
     label_type: LabelType,
@@ -38,37 +100,94 @@ pub struct AugmentSplit {
     split_offset: (Option<SplitOffset>, Option<SplitOffset>),
 
     img_format: ImgFormat,
-    // Discribes a color and a percentage (f32 beeing between 0.0 and 1.0), for discarding Images
-    // which contain more than x percent of color pixels
-    discard_barrier: Option<([u8; 3], f32)>,
+    // Color of background pixels and the fraction of them from which on split discards a tile,
+    // black with the channels of the source image and 0.2 if it is None
+    background: Option<(ColorValues, f32)>,
 
     rotation: bool,
 
     output_real: PathBuf,
     output_mask: Option<PathBuf>,
+
+    stain_normalization: Option<StainNormalization>,
+
+    // Every scale factor is tiled separately, context tiles are cut around every native tile
+    scales: Vec<f32>,
+    context_scales: Vec<f32>,
+
+    // Range of the zoom factor sampled for every tile, > 1.0 zooms into the tile
+    random_zoom: Option<(f32, f32)>,
+    // Photometric augmentations, every tile gets an additional augmented copy if any is drawn
+    augmentations: Vec<Augmentation>,
+    cutout: Option<Cutout>,
+
+    // Fractions of source images assigned to the train, val and test partition
+    partitions: Option<(f32, f32, f32)>,
+    output_layout: OutputLayout,
+    // Segmentation of the COCO annotations written beside the manifest
+    coco: Option<Segmentation>,
+    boxes: Option<BoxExport>,
+
+    output_policy: OutputPolicy,
+    // The policy is only applied before the first pass, later passes add to the same output
+    output_prepared: bool,
+
+    error_policy: ErrorPolicy,
+    // Source images left out of a pass because of the ErrorPolicy::Skip
+    report: ErrorReport,
+
+    run_mode: RunMode,
+    // Directory and maximum side length of the tile-grid previews
+    preview: Option<(PathBuf, u32)>,
+
+    border_policy: BorderPolicy,
+
+    // Replaces the background threshold of split, the masks are optionally saved into the path
+    tissue_detection: Option<TissueDetection>,
+    tissue_masks: Option<PathBuf>,
+
+    // Pyramid level slides are cut at and side length of the regions read from them at once
+    slide_level: usize,
+    slide_region: u32,
 }
 
-impl<'a> AugmentSplit {
-    pub fn build(img_dir: PathBuf,
-                 label_type: LabelType,
-                 split_size: Option<(u32, u32)>,
-                 split_offset: (Option<SplitOffset>, Option<SplitOffset>),
-                 img_format: ImgFormat,
-                 discard_barrier: Option<([u8; 3], f32)>,
-                 rotation: bool,
-                 output_real: PathBuf,
-                 output_mask: Option<PathBuf>)
+impl AugmentSplit {
+    pub fn build(input: InputOptions,
+                 tiles: TileOptions,
+                 augment: AugmentOptions,
+                 output: OutputOptions)
                  -> AugmentSplit {
         AugmentSplit {
-            img_dir: img_dir,
-            label_type: label_type,
-            split_size: split_size,
-            split_offset: split_offset,
-            img_format: img_format,
-            discard_barrier: discard_barrier,
-            rotation: rotation,
-            output_real: output_real,
-            output_mask: output_mask,
+            img_dir: input.img_dir,
+            label_type: input.label_type,
+            split_size: tiles.split_size,
+            split_offset: tiles.split_offset,
+            img_format: output.img_format,
+            background: tiles.background,
+            rotation: augment.rotation,
+            output_real: output.output_real,
+            output_mask: output.output_mask,
+            stain_normalization: input.stain_normalization,
+            scales: augment.scales,
+            context_scales: augment.context_scales,
+            random_zoom: augment.random_zoom,
+            augmentations: augment.augmentations,
+            cutout: augment.cutout,
+            partitions: output.partitions,
+            output_layout: output.output_layout,
+            coco: output.coco,
+            boxes: output.boxes,
+            output_policy: output.output_policy,
+            output_prepared: false,
+            error_policy: input.error_policy,
+            report: ErrorReport::new(),
+            run_mode: output.run_mode,
+            preview: output.preview,
+            border_policy: tiles.border_policy,
+            tissue_detection: tiles.tissue_detection,
+            tissue_masks: tiles.tissue_masks,
+            slide_level: input.slide_level,
+            slide_region: input.slide_region,
         }
     }
 
@@ -79,80 +198,698 @@ impl<'a> AugmentSplit {
     pub fn get_label_type(&self) -> LabelType {
         self.label_type.clone()
     }
-    fn save(&self,
-            split: SplitImage,
-            mut line_file: &mut String,
-            mut cnt: u32,
-            mut rng: &mut StdRng)
-            -> u32 {
-        self.write_to_file(&split, &mut line_file);
-        cnt += 1;
+
+    pub fn get_stain_normalization(&self) -> Option<StainNormalization> {
+        self.stain_normalization.clone()
+    }
+
+    pub fn get_error_policy(&self) -> ErrorPolicy {
+        self.error_policy
+    }
+
+    pub fn get_report(&self) -> &ErrorReport {
+        &self.report
+    }
+
+    pub fn get_run_mode(&self) -> &RunMode {
+        &self.run_mode
+    }
+
+    // Directory for manifest, statistics and contact sheet of a dry run
+    fn plan_dir(&self) -> Option<PathBuf> {
+        match self.run_mode {
+            RunMode::Write => None,
+            RunMode::DryRun(ref dir) |
+            RunMode::ContactSheet(ref dir, _) => Some(self.output_root().join(dir)),
+        }
+    }
+
+    fn contact_sheet(&self) -> Option<ContactSheet> {
+        match self.run_mode {
+            RunMode::ContactSheet(_, per_label) => Some(ContactSheet::new(per_label)),
+            _ => None,
+        }
+    }
+
+    fn preview(&self,
+               img_tuple: &(DynamicImage, DynamicImage),
+               cv: &ColorValues)
+               -> Option<Preview> {
+        self.preview
+            .as_ref()
+            .map(|&(_, max_size)| Preview::new(&img_tuple.0, &img_tuple.1, cv, max_size))
+    }
+
+    fn save_preview(&self, preview: Option<Preview>, name: &str, pass: &str) -> AnsResult<()> {
+        if let (Some(preview), Some(&(ref dir, _))) = (preview, self.preview.as_ref()) {
+            let dir = self.output_root().join(dir);
+            try!(DirBuilder::new().recursive(true).create(&dir));
+            let stem = Path::new(name).file_stem().map_or(String::from(name),
+                                                          |s| s.to_string_lossy().into_owned());
+            try!(preview.save(&dir.join(format!("{}_{}.png", stem, pass))));
+        }
+        Ok(())
+    }
+
+    // real has scale times the resolution of the level which is cut, the radius of the detection
+    // is scaled along
+    fn tissue_mask(&self,
+                   real: &DynamicImage,
+                   name: &str,
+                   scale: f32)
+                   -> AnsResult<Option<GrayImage>> {
+        let mut detection = match self.tissue_detection {
+            Some(ref detection) => detection.clone(),
+            None => return Ok(None),
+        };
+        if detection.radius > 0 {
+            detection.radius = ((detection.radius as f32 * scale).round() as u32).max(1);
+        }
+        let mask = detection.detect(real);
+        if let Some(ref dir) = self.tissue_masks {
+            let dir = self.output_root().join(dir);
+            try!(DirBuilder::new().recursive(true).create(&dir));
+            let stem = Path::new(name).file_stem().map_or(String::from(name),
+                                                          |s| s.to_string_lossy().into_owned());
+            try!(mask.save(&dir.join(format!("{}_tissue.png", stem))));
+        }
+        Ok(Some(mask))
+    }
+
+    // Tissue of a whole slide, detected once on the coarsest pyramid level with at least
+    // 1 / tissue::SLIDE_DOWNSAMPLE of the resolution of the cut level, so every region is
+    // thresholded alike
+    fn slide_tissue(&self, name: &str, slide: &Slide) -> AnsResult<Option<GrayImage>> {
+        if self.tissue_detection.is_none() {
+            return Ok(None);
+        }
+        let width = slide.dimensions(self.slide_level).map_or(0, |d| d.0);
+        let level = (self.slide_level..slide.real.get_num_of_levels())
+            .filter(|&l| {
+                slide.dimensions(l).map_or(false, |d| d.0 * tissue::SLIDE_DOWNSAMPLE >= width)
+            })
+            .last()
+            .unwrap_or(self.slide_level);
+        let (level_width, level_height) = slide.dimensions(level).unwrap_or((0, 0));
+        let (real, _) = try!(slide.read_real(level, 0, 0, level_width, level_height));
+        self.tissue_mask(&real, name, level_width as f32 / width.max(1) as f32)
+    }
+
+    // Statistics of the source images finished in earlier runs of pass, on resume
+    fn earlier_stats(&self, pass: &str, done: &HashSet<String>) -> AnsResult<Stats> {
+        if self.output_policy == OutputPolicy::Resume && self.plan_dir().is_none() {
+            if let Some(mut stats) = try!(Stats::read(&self.manifest_dir(), pass)) {
+                stats.retain(|name| done.contains(name));
+                return Ok(stats);
+            }
+        }
+        Ok(Stats::new(pass))
+    }
+    // Writes the statistics of earlier runs together with those of this run, after every source
+    // image so they stay complete if the run is interrupted
+    fn write_stats(&self, earlier: &Stats, stats: &Stats) -> AnsResult<()> {
+        let mut all = Stats::new(stats.get_pass());
+        all.extend(earlier);
+        all.extend(stats);
+        try!(all.write(&self.manifest_dir()));
+        Ok(())
+    }
+    // Saves the contact sheet and statistics of a finished pass
+    fn finish_pass(&self,
+                   earlier: &Stats,
+                   stats: &Stats,
+                   sheet: Option<ContactSheet>)
+                   -> AnsResult<()> {
+        try!(self.write_stats(earlier, stats));
+        if let Some((file, classes)) = self.boxes.and_then(|boxes| boxes.classes()) {
+            if self.run_mode == RunMode::Write {
+                let mut file = try!(fs::File::create(self.manifest_dir().join(file)));
+                try!(file.write_all(classes.as_bytes()));
+            }
+        }
+        if let Some(sheet) = sheet {
+            let file = format!("contact_sheet_{}.png", stats.get_pass());
+            try!(sheet.save(&self.manifest_dir().join(file)));
+        }
+        Ok(())
+    }
+    fn save<T: FindLabel, V: TileVisitor>(&self,
+                                          split: SplitImage,
+                                          state: &mut PassState,
+                                          visitor: &mut V,
+                                          cv: &ColorValues,
+                                          label_fn: &mut T)
+                                          -> AnsResult<()> {
+        try!(state.emit(visitor, &split));
+        if let Some(augmented) = augment::augment(&self.augmentations, &split, &mut state.rng) {
+            try!(state.emit(visitor, &augmented));
+        }
+        if let Some(erased) = self.cutout(&split, cv, label_fn, state) {
+            try!(state.emit(visitor, &erased));
+        }
         if self.rotation {
-            if let Some(rotated) = split.random_rotation(&mut rng) {
-                self.write_to_file(&rotated, &mut line_file);
-                cnt += 1;
+            if let Some(rotated) = split.random_rotation(&mut state.rng) {
+                try!(state.emit(visitor, &rotated));
+            }
+        }
+        Ok(())
+    }
+    // Erased masks are labelled again with the same FindLabel used for the tile once more than
+    // half of its positive pixels have been erased, smaller cutouts keep the label. A copy which
+    // doesn't satisfy any label anymore is counted as rejected.
+    fn cutout<T: FindLabel>(&self,
+                            split: &SplitImage,
+                            cv: &ColorValues,
+                            label_fn: &mut T,
+                            state: &mut PassState)
+                            -> Option<SplitImage> {
+        if let Some(ref cutout) = self.cutout {
+            if let Some(mut erased) = cutout.apply(split, &mut state.rng) {
+                self.update_ratios(&mut erased, cv);
+                if let CutoutMask::Erase = cutout.mask {
+                    let removed = match (split.get_mask_ratio(), erased.get_mask_ratio()) {
+                        (Some(before), Some(after)) => before > 0.0 && after < before * 0.5,
+                        _ => false,
+                    };
+                    if removed {
+                        erased.label = erased.get_mask_ratio().and_then(|r| label_fn.label(r));
+                        if erased.label.is_none() {
+                            state.stats.image(split.get_name()).rejected += 1;
+                            return None;
+                        }
+                    }
+                }
+                return Some(erased);
             }
         }
-        cnt
+        None
     }
-    fn write_to_file(&self, split_image: &SplitImage, line_file: &mut String) {
-        let name = self.create_name(&split_image);
+    fn save_context<V: TileVisitor>(&self,
+                                    split: &SplitImage,
+                                    (real, mask, deep): (&DynamicImage,
+                                                         &DynamicImage,
+                                                         Option<&Raster>),
+                                    centre: (u32, u32),
+                                    cv: &ColorValues,
+                                    state: &mut PassState,
+                                    visitor: &mut V)
+                                    -> AnsResult<()> {
+        let size = (split.get_x_dim(), split.get_y_dim());
+
+        for &scale in &self.context_scales {
+            let mut context = split.clone();
+            context.set_padding((0, 0));
+            context.set_real(resample::concentric_crop(real,
+                                                       centre,
+                                                       size,
+                                                       scale,
+                                                       Interpolation::Filter(FilterType::Triangle)));
+            context.set_mask(resample::concentric_crop(mask,
+                                                       centre,
+                                                       size,
+                                                       scale,
+                                                       Interpolation::NearestNeighbor));
+            context.deep =
+                deep.map(|deep| resample::concentric_crop_raster(deep, centre, size, scale));
+            context.set_scale(scale);
+            context.set_context(true);
+            self.update_ratios(&mut context, cv);
+            try!(state.emit(visitor, &context));
+        }
+        Ok(())
+    }
+    // Crops a larger or smaller region around the tile from the source image and resizes it back
+    // to the tile size, the label is determined again on the zoomed mask
+    fn random_zoom<T: FindLabel>(&self,
+                                 split: &SplitImage,
+                                 (real, mask, deep): (&DynamicImage,
+                                                      &DynamicImage,
+                                                      Option<&Raster>),
+                                 centre: (u32, u32),
+                                 cv: &ColorValues,
+                                 label_fn: &mut T,
+                                 rng: &mut StdRng)
+                                 -> Option<SplitImage> {
+        if let Some((min, max)) = self.random_zoom {
+            let zoom = if min < max {
+                rng.gen_range(min, max)
+            } else {
+                min
+            };
+            let size = (split.get_x_dim(), split.get_y_dim());
+            let zoomed_mask =
+                resample::concentric_crop(mask, centre, size, zoom, Interpolation::NearestNeighbor);
+
+            let mut zoomed = split.clone();
+            zoomed.set_padding((0, 0));
+            zoomed.set_real(resample::concentric_crop(real,
+                                                      centre,
+                                                      size,
+                                                      zoom,
+                                                      Interpolation::Filter(FilterType::Triangle)));
+            zoomed.set_mask(zoomed_mask);
+            zoomed.deep =
+                deep.map(|deep| resample::concentric_crop_raster(deep, centre, size, zoom));
+            self.update_ratios(&mut zoomed, cv);
+
+            if let Some(ratio) = zoomed.get_mask_ratio() {
+                if let Some(label) = label_fn.label(ratio) {
+                    zoomed.label = Some(label);
+                    zoomed.add_augmentation(format!("zoom{:.2}", zoom));
+                    return Some(zoomed);
+                }
+            }
+        }
+        None
+    }
+    // Recomputes mask and background ratio of a tile whose images have been replaced
+    fn update_ratios(&self, split: &mut SplitImage, cv: &ColorValues) {
+        let padding = split.get_padding();
+        let pixels = ((split.get_x_dim() - padding.0) * (split.get_y_dim() - padding.1)) as f32;
+        let mask_ratio = split.mask
+            .as_ref()
+            .and_then(|mask| AugmentSplit::count_color(cv, mask, padding))
+            .map(|cnt| cnt / pixels);
+        let background_ratio = split.real
+            .as_ref()
+            .and_then(|real| AugmentSplit::count_color(&self.background(real).0, real, padding))
+            .map(|cnt| cnt / pixels);
+
+        split.set_mask_ratio(mask_ratio);
+        split.set_background_ratio(background_ratio);
+    }
+    fn write_to_file(&self, split_image: &SplitImage, manifest: &mut Manifest) -> AnsResult<()> {
+        let mut real_path = None;
+        let mut mask_path = None;
+
         if let Some(ref image) = split_image.real {
-            if let DynamicImage::ImageRgb8(ref image) = *image {
-                let image_path = self.create_path(&name, ImageKind::Real);
-                let _ = image.save(&image_path);
+            let name = self.create_name(&split_image, ImageKind::Real);
+            let image_path = try!(self.create_path(split_image, &name, ImageKind::Real));
+            if self.run_mode == RunMode::Write {
+                match split_image.deep {
+                    Some(ref deep) => {
+                        let mut file = try!(fs::File::create(&image_path));
+                        try!(file.write_all(&tiff::encode(deep)));
+                    }
+                    None => try!(AugmentSplit::save_image(image, &image_path)),
+                }
+                try!(self.write_boxes(split_image, &image_path));
+            }
+            real_path = Some(manifest.relative(&image_path));
+        };
+        if let Some(buffer) = AugmentSplit::label_mask(split_image) {
+            let name = self.create_name(&split_image, ImageKind::Mask);
+            let image_path = try!(self.create_path(split_image, &name, ImageKind::Mask));
+            if self.run_mode == RunMode::Write {
+                try!(buffer.save(&image_path));
+            }
+            mask_path = Some(manifest.relative(&image_path));
+        }
 
-                line_file.push_str("/");
-                line_file.push_str(&name);
-                line_file.push_str("\n");
+        let entry = self.manifest_entry(split_image, manifest.get_pass(), real_path, mask_path);
+        manifest.push(entry);
+        Ok(())
+    }
+    // Gray and alpha channels are saved as they are, formats which can't hold them fail
+    fn save_image(image: &DynamicImage, path: &Path) -> AnsResult<()> {
+        match *image {
+            DynamicImage::ImageLuma8(ref image) => try!(image.save(path)),
+            DynamicImage::ImageLumaA8(ref image) => try!(image.save(path)),
+            DynamicImage::ImageRgb8(ref image) => try!(image.save(path)),
+            DynamicImage::ImageRgba8(ref image) => try!(image.save(path)),
+        }
+        Ok(())
+    }
+    // Channels of the saved tile, including alpha
+    fn channels(image: &DynamicImage) -> usize {
+        match *image {
+            DynamicImage::ImageLuma8(_) => 1,
+            DynamicImage::ImageLumaA8(_) => 2,
+            DynamicImage::ImageRgb8(_) => 3,
+            DynamicImage::ImageRgba8(_) => 4,
+        }
+    }
+    // Annotation file with the same name as the real tile
+    fn write_boxes(&self, split_image: &SplitImage, image_path: &Path) -> AnsResult<()> {
+        if let (Some(export), Some(mask)) = (self.boxes, AugmentSplit::label_mask(split_image)) {
+            let file_name = image_path.file_name()
+                .map_or(String::new(), |name| name.to_string_lossy().into_owned());
+            let depth = match (&split_image.deep, &split_image.real) {
+                (&Some(ref deep), _) => deep.channels(),
+                (_, &Some(ref real)) => AugmentSplit::channels(real),
+                _ => 0,
             };
+            let annotation =
+                export.annotation(&file_name, depth, split_image.label.as_ref(), &mask);
+            let mut file = try!(fs::File::create(image_path.with_extension(export.extension())));
+            try!(file.write_all(annotation.as_bytes()));
+        }
+        Ok(())
+    }
+    // Appends the tile as tf.train.Example to the current shard, the manifest points to the shard
+    fn write_record(&self,
+                    split_image: &SplitImage,
+                    records: &mut TfRecordWriter,
+                    manifest: &mut Manifest)
+                    -> AnsResult<()> {
+        let mut example = Example::new();
+        example.bytes("key", self.create_name(split_image, ImageKind::Real).as_bytes());
+        if let Some((encoded, format)) = try!(AugmentSplit::encode_real(split_image)) {
+            example.bytes("image/encoded", &encoded);
+            example.bytes("image/format", format.as_bytes());
+        }
+        if let Some(encoded) = try!(AugmentSplit::encode_mask(split_image)) {
+            example.bytes("mask/encoded", &encoded);
+        }
+        example.int64("image/width", split_image.get_x_dim() as i64);
+        example.int64("image/height", split_image.get_y_dim() as i64);
+        if let Some(ref label) = split_image.label {
+            example.int64("label/index", label.index() as i64);
+            example.bytes("label/name", label.name().as_bytes());
+        }
+        example.bytes("source", split_image.get_name().as_bytes());
+        example.int64("x_offset", split_image.get_x_offset() as i64);
+        example.int64("y_offset", split_image.get_y_offset() as i64);
+        example.float("scale", split_image.get_scale());
+        example.int64("rotation", split_image.get_rotation() as i64 * 90);
+
+        let shard = try!(records.write(&example.encode()));
+        let entry = self.manifest_entry(split_image,
+                                        manifest.get_pass(),
+                                        Some(manifest.relative(&shard)),
+                                        None);
+        manifest.push(entry);
+        Ok(())
+    }
+    // Appends the tile as WebDataset sample to the current tar shard, the manifest points to the
+    // shard and the JSON member holds the manifest entry with the member names as paths
+    fn write_sample(&self,
+                    split_image: &SplitImage,
+                    shards: &mut TarShardWriter,
+                    manifest: &mut Manifest)
+                    -> AnsResult<()> {
+        // WebDataset splits member names at the first dot into key and extension
+        let key = self.create_name(split_image, ImageKind::Real).replace('.', "_");
+        let mut members = vec![];
+        let mut real_member = None;
+        let mut mask_member = None;
+        if let Some((encoded, format)) = try!(AugmentSplit::encode_real(split_image)) {
+            let member = format!("{}.{}", key, if format == "tiff" { "tif" } else { format });
+            real_member = Some(member.clone());
+            members.push((member, encoded));
+        }
+        if let Some(encoded) = try!(AugmentSplit::encode_mask(split_image)) {
+            mask_member = Some(format!("{}.mask.png", key));
+            members.push((format!("{}.mask.png", key), encoded));
+        }
+        let metadata = self.manifest_entry(split_image,
+                                           manifest.get_pass(),
+                                           real_member,
+                                           mask_member)
+            .to_json();
+        members.push((format!("{}.json", key), metadata.into_bytes()));
+
+        let shard = try!(shards.write(&members));
+        let entry = self.manifest_entry(split_image,
+                                        manifest.get_pass(),
+                                        Some(manifest.relative(&shard)),
+                                        None);
+        manifest.push(entry);
+        Ok(())
+    }
+    // PNG encoded real tile and its format, deep tiles are encoded as TIFF
+    fn encode_real(split_image: &SplitImage) -> AnsResult<Option<(Vec<u8>, &'static str)>> {
+        if let Some(ref deep) = split_image.deep {
+            return Ok(Some((tiff::encode(deep), "tiff")));
+        }
+        if let Some(ref real) = split_image.real {
+            let mut encoded = vec![];
+            try!(real.save(&mut encoded, ImageFormat::PNG));
+            Ok(Some((encoded, "png")))
+        } else {
+            Ok(None)
+        }
+    }
+    // PNG encoded label_mask
+    fn encode_mask(split_image: &SplitImage) -> AnsResult<Option<Vec<u8>>> {
+        if let Some(buffer) = AugmentSplit::label_mask(split_image) {
+            let mut encoded = vec![];
+            try!(DynamicImage::ImageLuma8(buffer).save(&mut encoded, ImageFormat::PNG));
+            Ok(Some(encoded))
+        } else {
+            Ok(None)
+        }
+    }
+    // Mask with 255 for sick and 127 for fuzzy label pixels, None unless the mask is Luma8
+    fn label_mask(split_image: &SplitImage) -> Option<GrayImage> {
+        if let Some(DynamicImage::ImageLuma8(ref image)) = split_image.mask {
+            let mut buffer = ImageBuffer::<Luma<u8>, Vec<u8>>::new(image.width(), image.height());
+
+            for (x, y, pixel) in image.enumerate_pixels().filter(|p| p.2.data != [0]) {
+                let mut pixel = pixel.to_luma();
+                match split_image.label {
+                    Some(Label::Sick) => {
+                        pixel.data = [255];
+                        buffer.put_pixel(x, y, pixel);
+                    }
+                    Some(Label::Fuzzy) => {
+                        pixel.data = [127];
+                        buffer.put_pixel(x, y, pixel);
+                    }
+                    _ => {}
+                }
+            }
+            Some(buffer)
+        } else {
+            None
+        }
+    }
+    fn manifest_entry(&self,
+                      split_image: &SplitImage,
+                      pass: &str,
+                      real_path: Option<String>,
+                      mask_path: Option<String>)
+                      -> ManifestEntry {
+        ManifestEntry {
+            source: split_image.get_name().to_string(),
+            pass: pass.to_string(),
+            x_offset: split_image.get_x_offset(),
+            y_offset: split_image.get_y_offset(),
+            width: split_image.get_x_dim(),
+            height: split_image.get_y_dim(),
+            pad_right: split_image.get_padding().0,
+            pad_bottom: split_image.get_padding().1,
+            scale: split_image.get_scale(),
+            context: split_image.is_context(),
+            rotation: split_image.get_rotation() as u32 * 90,
+            augmentations: split_image.get_augmentations().clone(),
+            label: split_image.label.as_ref().map(|l| l.name().to_string()),
+            mask_ratio: split_image.get_mask_ratio(),
+            background_ratio: split_image.get_background_ratio(),
+            partition: self.partition(split_image.get_name()),
+            real_path: real_path,
+            mask_path: mask_path,
+            source_size: Some(split_image.get_source_size()),
+        }
+    }
+    // Only set up for formats packing many tiles into a file, shards go into the directory of the
+    // real tiles. A resumed run continues after the last finished source image.
+    fn shard_writer(&self, pass: &str) -> AnsResult<Option<ShardWriter>> {
+        let dir = self.output_root().join(&self.output_real);
+        let write = self.run_mode == RunMode::Write;
+        let mut shards = match self.img_format {
+            ImgFormat::TfRecord { shard_size } => {
+                ShardWriter::TfRecord(TfRecordWriter::new(dir, pass, shard_size, write))
+            }
+            ImgFormat::WebDataset(shard_size) => {
+                ShardWriter::WebDataset(TarShardWriter::new(dir, pass, shard_size, write))
+            }
+            _ => return Ok(None),
         };
-        if let Some(ref image) = split_image.mask {
-            if let DynamicImage::ImageLuma8(ref image) = *image {
-                let image_path = self.create_path(&name, ImageKind::Mask);
-
-                let dim = self.split_size.unwrap();
-                let mut buffer = ImageBuffer::<Luma<u8>, Vec<u8>>::new(dim.0, dim.1);
-
-                for (x, y, pixel) in image.enumerate_pixels().filter(|p| p.2.data != [0]) {
-                    let mut pixel = pixel.to_luma();
-                    match split_image.label {
-                        Some(Label::Sick) => {
-                            pixel.data = [255];
-                            buffer.put_pixel(x, y, pixel);
+        if let Some(position) = try!(self.shard_position(pass)) {
+            match shards {
+                ShardWriter::TfRecord(ref mut records) => try!(records.resume(position)),
+                ShardWriter::WebDataset(ref mut shards) => try!(shards.resume(position)),
+            }
+        }
+        Ok(Some(shards))
+    }
+
+    // A resumed run keeps the images of the finished source images
+    fn coco_writer(&self, pass: &str) -> AnsResult<Option<CocoWriter>> {
+        let mut coco = match self.coco {
+            Some(segmentation) => CocoWriter::new(segmentation),
+            None => return Ok(None),
+        };
+        let dir = self.manifest_dir();
+        let manifest_path = dir.join(manifest::MANIFEST_CSV);
+        if self.output_policy == OutputPolicy::Resume && self.plan_dir().is_none() &&
+           manifest_path.exists() {
+            let files = try!(manifest::read_csv(&manifest_path))
+                .into_iter()
+                .filter(|entry| entry.pass == pass)
+                .filter_map(|entry| entry.real_path)
+                .collect();
+            try!(coco.read(&dir.join(coco_file(pass)), &files));
+        }
+        Ok(Some(coco))
+    }
+
+    // Shard position to resume pass at, None to continue after the existing shards
+    fn shard_position(&self, pass: &str) -> AnsResult<Option<ShardPosition>> {
+        if self.output_policy != OutputPolicy::Resume || self.plan_dir().is_some() {
+            return Ok(None);
+        }
+        let dir = self.manifest_dir();
+        if dir.join(manifest::sources_file(pass)).exists() {
+            manifest::read_shard_position(&dir, pass)
+        } else if dir.join(manifest::MANIFEST_CSV).exists() {
+            // Output of a version without the list of finished sources
+            Ok(None)
+        } else {
+            // No source image was finished, the shards of the interrupted one are dropped
+            Ok(Some(ShardPosition::start()))
+        }
+    }
+
+    // Applies the output policy and returns the source images which are already done for pass
+    fn prepare_output(&mut self, pass: &str) -> AnsResult<HashSet<String>> {
+        let manifest_path = self.manifest_dir().join(manifest::MANIFEST_CSV);
+        let output_dirs = {
+            let root = self.output_root();
+            let mut dirs = vec![root.join(&self.output_real)];
+            if let Some(ref out) = self.output_mask {
+                dirs.push(root.join(out));
+            }
+            dirs
+        };
+
+        // A dry run leaves the output directories alone and only starts a fresh plan
+        if let Some(dir) = self.plan_dir() {
+            if !self.output_prepared {
+                self.output_prepared = true;
+                let files = vec![String::from(manifest::MANIFEST_CSV),
+                                 String::from(manifest::MANIFEST_JSONL),
+                                 manifest::sources_file(Pass::Split.name()),
+                                 manifest::sources_file(Pass::Oversample(0.0).name())];
+                for file in &files {
+                    if dir.join(file).exists() {
+                        try!(fs::remove_file(dir.join(file)));
+                    }
+                }
+            }
+            return Ok(HashSet::new());
+        }
+
+        if !self.output_prepared {
+            self.output_prepared = true;
+            match self.output_policy {
+                OutputPolicy::Fail => {
+                    for dir in &output_dirs {
+                        if let Ok(mut entries) = fs::read_dir(dir) {
+                            if entries.next().is_some() {
+                                return Err(AnsError::OutputExists(dir.clone()));
+                            }
                         }
-                        Some(Label::Fuzzy) => {
-                            pixel.data = [127];
-                            buffer.put_pixel(x, y, pixel);
+                    }
+                }
+                OutputPolicy::Overwrite => {
+                    for dir in output_dirs.iter().filter(|d| d.exists()) {
+                        if self.img_dir.starts_with(dir) {
+                            return Err(AnsError::InvalidSetting(format!("refusing to clear \
+                                                                         output directory \
+                                                                         {:?}, it contains \
+                                                                         the source images",
+                                                                        dir)));
                         }
-                        _ => {}
+                        let label_dir = match self.label_type {
+                            LabelType::Img(ref label_dir) |
+                            LabelType::Polygons(ref label_dir, _) => Some(label_dir),
+                            _ => None,
+                        };
+                        if let Some(label_dir) = label_dir {
+                            if label_dir.starts_with(dir) {
+                                return Err(AnsError::InvalidSetting(format!("refusing to clear \
+                                                                             output directory \
+                                                                             {:?}, it contains \
+                                                                             the labels",
+                                                                            dir)));
+                            }
+                        }
+                        try!(fs::remove_dir_all(dir));
                     }
                 }
-                let _ = buffer.save(&image_path);
+                OutputPolicy::Resume => {}
+            }
+        }
+
+        if self.output_policy != OutputPolicy::Resume {
+            return Ok(HashSet::new());
+        }
+        let dir = self.manifest_dir();
+        match try!(manifest::read_sources(&dir, pass)) {
+            // Tiles of a source image which was interrupted are cut again
+            Some(done) => {
+                try!(manifest::retain(&dir,
+                                      |entry| entry.pass != pass || done.contains(&entry.source)));
+                Ok(done)
             }
+            // Output of a version without the list of finished sources
+            None if manifest_path.exists() => {
+                Ok(try!(manifest::read_csv(&manifest_path))
+                    .into_iter()
+                    .filter(|entry| entry.pass == pass)
+                    .map(|entry| entry.source)
+                    .collect())
+            }
+            None => Ok(HashSet::new()),
         }
     }
 
-    fn write_line_file(&self, line_file: String) {
-        let path = self.img_dir.clone();
-        let mut file_path = path.parent().unwrap().to_path_buf();
-        file_path.push(self.output_real.clone());
+    // Output directories are relative to the parent of the image directory
+    fn output_root(&self) -> PathBuf {
+        match self.img_dir.parent() {
+            Some(parent) => parent.to_path_buf(),
+            None => PathBuf::new(),
+        }
+    }
 
-        DirBuilder::new().recursive(true).create(&file_path).unwrap();
+    fn manifest_dir(&self) -> PathBuf {
+        if let Some(dir) = self.plan_dir() {
+            return dir;
+        }
+        let mut manifest_dir = self.output_root();
+        manifest_dir.push(self.output_real.clone());
+        manifest_dir
+    }
 
-        let mut file = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .create(true)
-            .open(file_path.join("image_description.txt"))
-            .unwrap();
+    // Source images are assigned to a partition by a hash of their name, so every tile of an image
+    // ends up in the same partition, independent of the order the images are processed in
+    fn partition(&self, source: &str) -> Option<String> {
+        if let Some((train, val, test)) = self.partitions {
+            let hash = fnv1a(source);
+            let total = train + val + test;
+            let fraction = (hash % 1000000) as f32 / 1000000.0 * total;
 
-        let _ = file.write(&line_file.into_bytes()[..]);
+            let partition = if fraction < train {
+                "train"
+            } else if fraction < train + val {
+                "val"
+            } else {
+                "test"
+            };
+            Some(String::from(partition))
+        } else {
+            None
+        }
     }
 
-    fn create_path(&self, name: &str, image_kind: ImageKind) -> PathBuf {
-        let path = self.img_dir.clone();
-        let mut image_path = path.parent().unwrap().to_path_buf();
+    fn create_path(&self,
+                   split_image: &SplitImage,
+                   name: &str,
+                   image_kind: ImageKind)
+                   -> AnsResult<PathBuf> {
+        let mut image_path = self.output_root();
 
         match image_kind {
             ImageKind::Real => {
@@ -162,18 +899,26 @@ impl<'a> AugmentSplit {
                 if let Some(ref out) = self.output_mask {
                     image_path.push(out.clone());
                 } else {
-                    panic!("  ...  ")
+                    return Err(AnsError::MissingSetting("output_mask"));
                 }
             }
         }
+        if let OutputLayout::ImageFolder = self.output_layout {
+            if let Some(partition) = self.partition(split_image.get_name()) {
+                image_path.push(partition);
+            }
+            image_path.push(split_image.label.as_ref().map_or("None", |l| l.name()));
+        }
 
-        DirBuilder::new().recursive(true).create(&image_path).unwrap();
+        if self.run_mode == RunMode::Write {
+            try!(DirBuilder::new().recursive(true).create(&image_path));
+        }
         image_path.push(Path::new(&name[..]));
 
-        image_path
+        Ok(image_path)
     }
 
-    fn create_name(&self, split_image: &SplitImage) -> String {
+    fn create_name(&self, split_image: &SplitImage, image_kind: ImageKind) -> String {
         let split_name = split_image.get_name();
         let mut name = if let Some(dot_index) = split_name.char_indices().find(|&c| c.1 == '.') {
             let name = String::from(split_name.split_at(dot_index.0).0);
@@ -187,6 +932,12 @@ impl<'a> AugmentSplit {
         name.push('_');
         name.push_str(&split_image.get_y_offset().to_string());
 
+        if split_image.is_context() {
+            name.push_str(&format!("_ctx{:.2}", split_image.get_scale()));
+        } else if split_image.get_scale() != 1.0 {
+            name.push_str(&format!("_s{:.2}", split_image.get_scale()));
+        }
+
         let rotation = match split_image.get_rotation() {
             0 => String::from("000deg"),
             1 => String::from("090deg"),
@@ -197,6 +948,11 @@ impl<'a> AugmentSplit {
         name.push('_');
         name.push_str(&rotation);
 
+        for augmentation in split_image.get_augmentations() {
+            name.push('_');
+            name.push_str(augmentation);
+        }
+
         if let Some(ref label) = split_image.label {
             match *label {
                 Label::Sick => name.push_str("_Sick"),
@@ -207,6 +963,11 @@ impl<'a> AugmentSplit {
 
         if let ImgFormat::Img(format) = self.img_format {
             use image::ImageFormat::*;
+            // The other formats can't hold the samples of deep tiles
+            let format = match (image_kind, &split_image.deep) {
+                (ImageKind::Real, &Some(_)) => TIFF,
+                _ => format,
+            };
             match format {
                 PNG => name.push_str(".png"),
                 JPEG => name.push_str(".jpg"),
@@ -220,190 +981,1507 @@ impl<'a> AugmentSplit {
 
     pub fn split<T: FindLabel>(&mut self,
                                img_reader: &mut ImgReader,
-                               cv: ColorValues,
-                               label_fn: &mut T) {
-        let mut rng = StdRng::new().unwrap();
-        rng.reseed(&[1, 7, 7, 6]);
-
-        if let Some((x_len, y_len)) = self.split_size {
-
-            let pixels = (x_len * y_len) as f32;
-
-            if let (Some(x_offset), Some(y_offset)) = self.split_offset.clone() {
-                let x_offset = SplitOffset::get_value(&x_offset);
-                let y_offset = SplitOffset::get_value(&y_offset);
-                let mut line_file = String::new();
-
-                for (name, mut img_tuple) in img_reader.img_map.iter_mut() {
-                    let real_dim = img_tuple.0.dimensions();
-
-                    for i in (0..real_dim.0 - x_len + 1).step_by(x_offset) {
-
-                        for j in (0..real_dim.1 - y_len + 1).step_by(y_offset) {
-                            let real_crop = img_tuple.0.crop(i, j, x_len, y_len);
-                            let mask_crop = img_tuple.1.crop(i, j, x_len, y_len);
-
-                            if let Ok(real_info) =
-                                   AugmentSplit::get_color(ColorValues::black_rgb(), &real_crop) {
-                                // Black threshold 0.35
-                                if real_info.1 / pixels < 0.20 {
-                                    if let Ok(mask_info) = AugmentSplit::get_color(cv, &mask_crop) {
-                                        let ratio = mask_info.1 / pixels;
-                                        let l = label_fn.label(ratio);
-                                        if let Some(label) = l {
-                                            let split = SplitImage::new(name,
-                                                                        real_crop,
-                                                                        mask_crop,
-                                                                        label,
-                                                                        (x_len, y_len),
-                                                                        0,
-                                                                        i,
-                                                                        j);
-                                            let _ = self.save(split, &mut line_file, 0, &mut rng);
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-                self.write_line_file(line_file)
-            }
-        }
+                               cv: &ColorValues,
+                               label_fn: &mut T)
+                               -> AnsResult<Stats> {
+        self.write_pass(Pass::Split, img_reader, cv, label_fn)
     }
     pub fn oversample<T: FindLabel>(&mut self,
                                     img_reader: &mut ImgReader,
                                     sample_mpy: f32,
-                                    cv: ColorValues,
-                                    label_fn: &mut T) {
-        let seed = &[1, 3, 3, 7];
-        let mut rng = StdRng::new().unwrap();
-        rng.reseed(seed);
-
-        if let Some((x_len, y_len)) = self.split_size {
-
-            let pixels = (x_len * y_len) as f32;
-            let mut line_file = String::new();
-
-            for (name, mut img_tuple) in img_reader.img_map.iter_mut() {
-                if let DynamicImage::ImageLuma8(ref mask) = img_tuple.1.clone() {
-                    let sick_pixel_vec = mask.enumerate_pixels()
-                        .filter(|x| cv.compare(x.2.data))
-                        .map(|x| (x.0, x.1))
-                        .collect::<Vec<_>>();
-
-                    let sample_size = (sample_mpy * sick_pixel_vec.len() as f32) as usize;
-                    let sampled_pixels = sample(&mut rng, sick_pixel_vec, sample_size);
-
-                    for s in sampled_pixels {
-                        let real_crop = img_tuple.0.crop(s.0, s.1, x_len, y_len);
-                        let mask_crop = img_tuple.1.crop(s.0, s.1, x_len, y_len);
-
-                        if real_crop.dimensions() == (x_len, y_len) {
-                            if let Ok(mask_info) = AugmentSplit::get_color(cv.clone(), &mask_crop) {
-                                // White Threshold 0.25
-                                let ratio = mask_info.1 / pixels;
-                                let l = label_fn.label(ratio);
-                                if let Some(label) = l {
-                                    let split = SplitImage::new(name,
+                                    cv: &ColorValues,
+                                    label_fn: &mut T)
+                                    -> AnsResult<Stats> {
+        self.write_pass(Pass::Oversample(sample_mpy), img_reader, cv, label_fn)
+    }
+    // Runs a pass without writing any tiles, manifest or statistics, every tile is handed to the
+    // visitor instead. Previews and tissue masks are still saved if they are set.
+    pub fn visit<T: FindLabel, V: TileVisitor>(&mut self,
+                                               img_reader: &ImgReader,
+                                               pass: Pass,
+                                               cv: &ColorValues,
+                                               label_fn: &mut T,
+                                               visitor: &mut V)
+                                               -> AnsResult<Stats> {
+        let mut state = try!(PassState::new(pass, None));
+        for (name, img_tuple) in &img_reader.img_map {
+            let deep = img_reader.deep.get(name);
+            try!(self.visit_source((name, img_tuple), deep, cv, label_fn, &mut state, visitor));
+        }
+        for (name, slide) in &img_reader.slides {
+            try!(self.visit_slide(name, slide, cv, label_fn, &mut state, visitor));
+        }
+        try!(visitor.finish());
+        self.report.append(state.report);
+        Ok(state.stats)
+    }
+    // Lazy version of visit, a source image is only cut once the tiles of the previous one are
+    // used up
+    pub fn tiles<'b, T: FindLabel>(&'b self,
+                                   img_reader: &'b ImgReader,
+                                   pass: Pass,
+                                   cv: &'b ColorValues,
+                                   label_fn: &'b mut T)
+                                   -> AnsResult<Tiles<'b, T>> {
+        Ok(Tiles {
+            augment_split: self,
+            sources: img_reader.img_map.iter(),
+            deep: &img_reader.deep,
+            slides: img_reader.slides.iter(),
+            slide: None,
+            regions: VecDeque::new(),
+            cv: cv,
+            label_fn: label_fn,
+            state: try!(PassState::new(pass, None)),
+            buffer: VecDeque::new(),
+        })
+    }
+    // Runs a pass with the DiskWriter as visitor, honouring output policy and run mode. The
+    // returned statistics only cover this run, on resume stats_<pass>.json holds earlier runs too.
+    fn write_pass<T: FindLabel>(&mut self,
+                                pass: Pass,
+                                img_reader: &ImgReader,
+                                cv: &ColorValues,
+                                label_fn: &mut T)
+                                -> AnsResult<Stats> {
+        let done = try!(self.prepare_output(pass.name()));
+        let earlier = try!(self.earlier_stats(pass.name(), &done));
+        let mut state = try!(PassState::new(pass, self.contact_sheet()));
+        {
+            let mut writer = DiskWriter {
+                augment_split: self,
+                manifest: Manifest::new(self.manifest_dir(), pass.name()),
+                shards: try!(self.shard_writer(pass.name())),
+                coco: try!(self.coco_writer(pass.name())),
+            };
+            for (name, img_tuple) in &img_reader.img_map {
+                if !done.contains(name) {
+                    try!(self.visit_source((name, img_tuple),
+                                           img_reader.deep.get(name),
+                                           cv,
+                                           label_fn,
+                                           &mut state,
+                                           &mut writer));
+                    try!(self.write_stats(&earlier, &state.stats));
+                }
+            }
+            for (name, slide) in &img_reader.slides {
+                if !done.contains(name) {
+                    try!(self.visit_slide(name, slide, cv, label_fn, &mut state, &mut writer));
+                    try!(self.write_stats(&earlier, &state.stats));
+                }
+            }
+            try!(writer.finish());
+        }
+        try!(self.finish_pass(&earlier, &state.stats, state.sheet));
+        self.report.append(state.report);
+        Ok(state.stats)
+    }
+    fn visit_source<T: FindLabel, V: TileVisitor>(&self,
+                                                  (name, img_tuple): (&String,
+                                                                      &(DynamicImage,
+                                                                        DynamicImage)),
+                                                  deep: Option<&Raster>,
+                                                  cv: &ColorValues,
+                                                  label_fn: &mut T,
+                                                  state: &mut PassState,
+                                                  visitor: &mut V)
+                                                  -> AnsResult<()> {
+        let size = match self.split_size {
+            Some(size) => size,
+            None => return Ok(()),
+        };
+        let checked = self.check_input(img_tuple, cv, state.pass != Pass::Split)
+            .and_then(|_| self.check_deep(deep));
+        if let Err(e) = checked {
+            return state.report.handle(self.error_policy, name, e);
+        }
+        let (width, height) = img_tuple.0.dimensions();
+        state.start_source(name, (width, height));
+        state.preview = self.preview(img_tuple, cv);
+        let mut source = Source {
+            name: name,
+            img_tuple: img_tuple,
+            deep: deep,
+            origin: (0, 0),
+            window: (0, 0, width, height),
+            tissue: None,
+        };
+        match state.pass {
+            Pass::Split => {
+                source.tissue = try!(self.tissue_mask(&img_tuple.0, name, 1.0));
+                try!(self.split_source(&source, size, cv, label_fn, state, visitor))
+            }
+            Pass::Oversample(_) => {
+                try!(self.oversample_source(&source, size, cv, label_fn, state, visitor))
+            }
+        }
+        try!(self.save_preview(state.preview.take(), name, state.pass.name()));
+        visitor.finish_image(name)
+    }
+    // Slides are cut region by region, so only a few regions are decoded at a time. Tile
+    // positions are relative to the whole slide level, previews are not drawn for slides.
+    fn visit_slide<T: FindLabel, V: TileVisitor>(&self,
+                                                 name: &String,
+                                                 slide: &Slide,
+                                                 cv: &ColorValues,
+                                                 label_fn: &mut T,
+                                                 state: &mut PassState,
+                                                 visitor: &mut V)
+                                                 -> AnsResult<()> {
+        let regions = match self.start_slide(name, slide, state) {
+            Ok(regions) => regions,
+            Err(e) => return state.report.handle(self.error_policy, name, e),
+        };
+        for region in regions {
+            if !try!(self.visit_region((name, slide), region, cv, label_fn, state, visitor)) {
+                break;
+            }
+        }
+        visitor.finish_image(name)
+    }
+    // Regions of a slide, the tissue of the whole slide is detected up front for the split pass
+    fn start_slide(&self,
+                   name: &str,
+                   slide: &Slide,
+                   state: &mut PassState)
+                   -> AnsResult<Vec<(u32, u32, u32, u32)>> {
+        // The tile grid of a scaled slide doesn't line up with the regions it is read in
+        if self.scales.iter().any(|&scale| scale != 1.0) {
+            return Err(AnsError::Unsupported(String::from("scales other than 1.0 for tiled \
+                                                           TIFF sources")));
+        }
+        let regions = try!(self.slide_regions(slide, state.pass));
+        state.start_source(name, slide.dimensions(self.slide_level).unwrap_or((0, 0)));
+        state.slide_tissue = match state.pass {
+            Pass::Split => try!(self.slide_tissue(name, slide)),
+            Pass::Oversample(_) => None,
+        };
+        Ok(regions)
+    }
+    // x, y, width and height of the regions of a slide, every region holds the tiles of a part
+    // of the tile grid of the whole slide level
+    fn slide_regions(&self, slide: &Slide, pass: Pass) -> AnsResult<Vec<(u32, u32, u32, u32)>> {
+        let (x_len, y_len) = match self.split_size {
+            Some(size) => size,
+            None => return Ok(vec![]),
+        };
+        let (width, height) = try!(slide.dimensions(self.slide_level)
+            .ok_or_else(|| {
+                AnsError::InvalidSetting(format!("slide has no pyramid level {}", self.slide_level))
+            }));
+        let (x_stride, y_stride) = match (pass, &self.split_offset) {
+            (Pass::Oversample(_), _) => (x_len, y_len),
+            (Pass::Split, &(Some(ref x_offset), Some(ref y_offset))) => {
+                (x_offset.get_value(), y_offset.get_value())
+            }
+            _ => return Ok(vec![]),
+        };
+        let (region, policy) = (self.slide_region, self.border_policy);
+        let columns = AugmentSplit::region_spans(width, x_len, x_stride, region, policy);
+        let rows = AugmentSplit::region_spans(height, y_len, y_stride, region, policy);
+        Ok(rows.iter()
+            .flat_map(|&(y, h)| columns.iter().map(move |&(x, w)| (x, y, w, h)))
+            .collect())
+    }
+    // Start and length of the regions along an axis of length len. Every region holds up to as
+    // many consecutive tiles of tile_positions on the whole axis as fit into region pixels.
+    fn region_spans(len: u32,
+                    tile: u32,
+                    stride: u32,
+                    region: u32,
+                    policy: BorderPolicy)
+                    -> Vec<(u32, u32)> {
+        let tiles = (region.saturating_sub(tile) / stride + 1) as usize;
+        AugmentSplit::tile_positions(len, tile, stride, policy)
+            .chunks(tiles)
+            .map(|chunk| {
+                let (first, last) = (chunk[0], chunk[chunk.len() - 1]);
+                (first, (last + tile).min(len) - first)
+            })
+            .collect()
+    }
+    // Reads a region of a slide and cuts it. Returns false if the slide could not be read and is
+    // skipped.
+    fn visit_region<T: FindLabel, V: TileVisitor>(&self,
+                                                  (name, slide): (&String, &Slide),
+                                                  (x, y, width, height): (u32, u32, u32, u32),
+                                                  cv: &ColorValues,
+                                                  label_fn: &mut T,
+                                                  state: &mut PassState,
+                                                  visitor: &mut V)
+                                                  -> AnsResult<bool> {
+        let size = match self.split_size {
+            Some(size) => size,
+            None => return Ok(true),
+        };
+        let level = self.slide_level;
+        let (slide_width, slide_height) =
+            slide.dimensions(level).unwrap_or((x + width, y + height));
+        // Oversampled tiles start in the region and reach up to a tile further
+        let (reach_x, reach_y) = match state.pass {
+            Pass::Oversample(_) => size,
+            Pass::Split => (0, 0),
+        };
+        let (margin_x, margin_y) = self.slide_margin(size);
+        let (read_x, read_y) = (x.saturating_sub(margin_x), y.saturating_sub(margin_y));
+        let read_width = (x + width + reach_x + margin_x).min(slide_width) - read_x;
+        let read_height = (y + height + reach_y + margin_y).min(slide_height) - read_y;
+        let read = slide.read_mask(level, read_x, read_y, read_width, read_height)
+            .and_then(|mask| {
+            // Oversampling only needs regions with labelled pixels
+            if let (Pass::Oversample(_), &DynamicImage::ImageLuma8(ref labels)) =
+                   (state.pass, &mask) {
+                if !labels.pixels().any(|p| cv.compare(p.data)) {
+                    return Ok(None);
+                }
+            }
+            let (real, deep) =
+                try!(slide.read_real(level, read_x, read_y, read_width, read_height));
+            Ok(Some(((real, mask), deep)))
+        });
+        let (img_tuple, deep) = match read {
+            Ok(Some(read)) => read,
+            Ok(None) => return Ok(true),
+            Err(e) => {
+                try!(state.report.handle(self.error_policy, name, e));
+                return Ok(false);
+            }
+        };
+        let checked = self.check_input(&img_tuple, cv, state.pass != Pass::Split)
+            .and_then(|_| self.check_deep(deep.as_ref()));
+        if let Err(e) = checked {
+            try!(state.report.handle(self.error_policy, name, e));
+            return Ok(false);
+        }
+
+        state.preview = None;
+        let mut source = Source {
+            name: name,
+            img_tuple: &img_tuple,
+            deep: deep.as_ref(),
+            origin: (read_x, read_y),
+            window: (x - read_x, y - read_y, width, height),
+            tissue: None,
+        };
+        match state.pass {
+            Pass::Split => {
+                source.tissue = state.slide_tissue.as_ref().map(|tissue| {
+                    slide.scale_mask(tissue, level, read_x, read_y, read_width, read_height)
+                });
+                try!(self.split_source(&source, size, cv, label_fn, state, visitor))
+            }
+            Pass::Oversample(_) => {
+                try!(self.oversample_source(&source, size, cv, label_fn, state, visitor))
+            }
+        }
+        Ok(true)
+    }
+    // Pixels read on every side of a slide region, so context tiles and zoomed crops of the tiles
+    // at its border hold the slide pixels next to it
+    fn slide_margin(&self, (x_len, y_len): (u32, u32)) -> (u32, u32) {
+        let min_scale = self.context_scales
+            .iter()
+            .cloned()
+            .chain(self.random_zoom.map(|(min, _)| min))
+            .fold(1.0f32, f32::min);
+        if min_scale >= 1.0 {
+            return (0, 0);
+        }
+        let margin = |len: u32| ((len as f32 / min_scale - len as f32) / 2.0).ceil() as u32 + 1;
+        (margin(x_len), margin(y_len))
+    }
+    // Tile positions are relative to the slide for slide regions
+    fn split_source<T: FindLabel, V: TileVisitor>(&self,
+                                                  source: &Source,
+                                                  (x_len, y_len): (u32, u32),
+                                                  cv: &ColorValues,
+                                                  label_fn: &mut T,
+                                                  state: &mut PassState,
+                                                  visitor: &mut V)
+                                                  -> AnsResult<()> {
+        let (name, img_tuple, source_deep) = (source.name, source.img_tuple, source.deep);
+        let (x_offset, y_offset) = match self.split_offset {
+            (Some(ref x_offset), Some(ref y_offset)) => {
+                (x_offset.get_value(), y_offset.get_value())
+            }
+            _ => return Ok(()),
+        };
+        let tissue = source.tissue.clone().map(DynamicImage::ImageLuma8);
+        let (ox, oy) = source.origin;
+        for &scale in &self.scales {
+            let tissue = tissue.as_ref().and_then(|tissue| {
+                let tissue = resample::resample(tissue, scale, Interpolation::NearestNeighbor);
+                IntegralImage::from_color(&tissue, &ColorValues::white_luma())
+            });
+            let scaled = if scale != 1.0 {
+                Some((resample::resample(&img_tuple.0,
+                                         scale,
+                                         Interpolation::Filter(FilterType::Triangle)),
+                      resample::resample(&img_tuple.1, scale, Interpolation::NearestNeighbor)))
+            } else {
+                None
+            };
+            let (real, mask) = match scaled {
+                Some((ref real, ref mask)) => (real, mask),
+                None => (&img_tuple.0, &img_tuple.1),
+            };
+            let scaled_deep = match source_deep {
+                Some(deep) if scale != 1.0 => Some(resample::resample_raster(deep, scale)),
+                _ => None,
+            };
+            let deep = scaled_deep.as_ref().or(source_deep);
+            let real_dim = real.dimensions();
+            // Counts for every tile come from these tables, pixels are only copied for the tiles
+            // which are kept
+            let (background_color, max_background) = self.background(real);
+            let background_table = IntegralImage::from_color(real, &background_color);
+            let mask_table = IntegralImage::from_color(mask, cv);
+            // Slides are only cut at scale 1.0, see start_slide
+            let (wx, wy, width, height) = if scale == 1.0 {
+                source.window
+            } else {
+                (0, 0, real_dim.0, real_dim.1)
+            };
+            let x_positions =
+                AugmentSplit::tile_positions(width, x_len, x_offset, self.border_policy);
+            let y_positions =
+                AugmentSplit::tile_positions(height, y_len, y_offset, self.border_policy);
+
+            for &i in &x_positions {
+                let i = wx + i;
+                for &j in &y_positions {
+                    let j = wy + j;
+                    let padding = AugmentSplit::tile_padding(real_dim, i, j, x_len, y_len);
+                    let valid = (x_len - padding.0, y_len - padding.1);
+                    let pixels = (valid.0 * valid.1) as f32;
+                    state.stats.image(name).considered += 1;
+                    // Tile in native source coordinates for the preview
+                    let native = |v: u32| (v as f32 / scale).round() as u32;
+                    let outline = (native(i), native(j), native(x_len), native(y_len));
+
+                    if let Some(background) = background_table.as_ref()
+                        .map(|t| t.count(i, j, valid.0, valid.1) as f32) {
+                        let foreground = match (&tissue, &self.tissue_detection) {
+                            (&Some(ref tissue), &Some(ref detection)) => {
+                                let cnt = tissue.count(i, j, valid.0, valid.1);
+                                cnt as f32 / pixels >= detection.min_fraction
+                            }
+                            _ => background / pixels < max_background,
+                        };
+                        if !foreground {
+                            state.stats.image(name).background += 1;
+                            if let Some(ref mut preview) = state.preview {
+                                preview.tile(outline.0,
+                                             outline.1,
+                                             outline.2,
+                                             outline.3,
+                                             &Outcome::Background);
+                            }
+                            continue;
+                        }
+                        if let Some(mask_cnt) = mask_table.as_ref()
+                            .map(|t| t.count(i, j, valid.0, valid.1) as f32) {
+                            let ratio = mask_cnt / pixels;
+                            let l = label_fn.label(ratio);
+                            let outcome = match l {
+                                Some(ref label) => Outcome::Label(label.clone()),
+                                None => Outcome::Rejected,
+                            };
+                            if let Some(ref mut preview) = state.preview {
+                                preview.tile(outline.0, outline.1, outline.2, outline.3, &outcome);
+                            }
+                            if let Some(label) = l {
+                                let (real_crop, mask_crop, _) =
+                                    self.crop_tile(real, mask, i, j, x_len, y_len);
+                                let deep_crop = self.crop_deep(deep, i, j, x_len, y_len);
+                                let mut split = SplitImage::new(name,
                                                                 real_crop,
                                                                 mask_crop,
-                                                                label,
+                                                                label.clone(),
                                                                 (x_len, y_len),
                                                                 0,
-                                                                s.0,
-                                                                s.1);
-                                    let _ = self.save(split, &mut line_file, 0, &mut rng);
+                                                                ox + native(i),
+                                                                oy + native(j));
+                                split.deep = deep_crop;
+                                split.set_source_size(state.source_size);
+                                split.set_scale(scale);
+                                split.set_padding(padding);
+                                split.set_mask_ratio(Some(ratio));
+                                split.set_background_ratio(Some(background / pixels));
+
+                                let centre = (i + x_len / 2, j + y_len / 2);
+                                if scale == 1.0 {
+                                    try!(self.save_context(&split,
+                                                           (real, mask, deep),
+                                                           centre,
+                                                           cv,
+                                                           state,
+                                                           visitor));
+                                }
+                                if let Some(zoomed) = self.random_zoom(&split,
+                                                                       (real, mask, deep),
+                                                                       centre,
+                                                                       cv,
+                                                                       label_fn,
+                                                                       &mut state.rng) {
+                                    try!(state.emit(visitor, &zoomed));
+                                }
+                                if let Some(ref mut sheet) = state.sheet {
+                                    sheet.offer(&split);
                                 }
+                                try!(self.save(split, state, visitor, cv, label_fn));
+                            } else {
+                                state.stats.image(name).rejected += 1;
                             }
                         }
                     }
                 }
             }
-            self.write_line_file(line_file)
         }
+        Ok(())
     }
-    pub fn get_color(color: ColorValues, image: &DynamicImage) -> Result<(ColorValues, f32), &str> {
+    // Only pixels in the window of the source are sampled, the image of a slide region reaches
+    // further so tiles starting there are not cut off
+    fn oversample_source<T: FindLabel, V: TileVisitor>(&self,
+                                                       source: &Source,
+                                                       (x_len, y_len): (u32, u32),
+                                                       cv: &ColorValues,
+                                                       label_fn: &mut T,
+                                                       state: &mut PassState,
+                                                       visitor: &mut V)
+                                                       -> AnsResult<()> {
+        let (name, img_tuple, deep) = (source.name, source.img_tuple, source.deep);
+        let sample_mpy = match state.pass {
+            Pass::Oversample(sample_mpy) => sample_mpy,
+            Pass::Split => return Ok(()),
+        };
+        if let DynamicImage::ImageLuma8(ref mask) = img_tuple.1 {
+            let (ox, oy) = source.origin;
+            let (wx, wy, width, height) = source.window;
+            let sick_pixel_vec = mask.enumerate_pixels()
+                .filter(|x| {
+                    x.0 >= wx && x.0 < wx + width && x.1 >= wy && x.1 < wy + height &&
+                    cv.compare(x.2.data)
+                })
+                .map(|x| (x.0, x.1))
+                .collect::<Vec<_>>();
+
+            let sample_size = (sample_mpy * sick_pixel_vec.len() as f32) as usize;
+            let sampled_pixels = sample(&mut state.rng, sick_pixel_vec, sample_size);
+
+            let real_dim = img_tuple.0.dimensions();
+            let mask_table = IntegralImage::from_color(&img_tuple.1, cv);
+            for s in sampled_pixels {
+                let placed = (AugmentSplit::place_tile(s.0, real_dim.0, x_len, self.border_policy),
+                              AugmentSplit::place_tile(s.1, real_dim.1, y_len, self.border_policy));
+
+                if let (Some(x), Some(y)) = placed {
+                    let padding = AugmentSplit::tile_padding(real_dim, x, y, x_len, y_len);
+                    let valid = (x_len - padding.0, y_len - padding.1);
+                    let pixels = (valid.0 * valid.1) as f32;
+                    state.stats.image(name).considered += 1;
+                    if let Some(mask_cnt) = mask_table.as_ref()
+                        .map(|t| t.count(x, y, valid.0, valid.1) as f32) {
+                        // White Threshold 0.25
+                        let ratio = mask_cnt / pixels;
+                        let l = label_fn.label(ratio);
+                        if let Some(ref mut preview) = state.preview {
+                            let outcome = match l {
+                                Some(ref label) => Outcome::Label(label.clone()),
+                                None => Outcome::Rejected,
+                            };
+                            preview.marker(x, y, x_len, y_len, &outcome);
+                        }
+                        if let Some(label) = l {
+                            let (real_crop, mask_crop, _) =
+                                self.crop_tile(&img_tuple.0, &img_tuple.1, x, y, x_len, y_len);
+                            let mut split = SplitImage::new(name,
+                                                            real_crop,
+                                                            mask_crop,
+                                                            label,
+                                                            (x_len, y_len),
+                                                            0,
+                                                            ox + x,
+                                                            oy + y);
+                            split.deep = self.crop_deep(deep, x, y, x_len, y_len);
+                            split.set_source_size(state.source_size);
+                            split.set_padding(padding);
+                            self.update_ratios(&mut split, cv);
+                            let images = (&img_tuple.0, &img_tuple.1, deep);
+                            if let Some(zoomed) = self.random_zoom(&split,
+                                                                   images,
+                                                                   (x + x_len / 2, y + y_len / 2),
+                                                                   cv,
+                                                                   label_fn,
+                                                                   &mut state.rng) {
+                                try!(state.emit(visitor, &zoomed));
+                            }
+                            if let Some(ref mut sheet) = state.sheet {
+                                sheet.offer(&split);
+                            }
+                            try!(self.save(split, state, visitor, cv, label_fn));
+                        } else {
+                            state.stats.image(name).rejected += 1;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    // Start positions of the tiles along an axis of length len
+    fn tile_positions(len: u32, tile: u32, stride: u32, policy: BorderPolicy) -> Vec<u32> {
+        let mut positions = if len >= tile {
+            (0..len - tile + 1).step_by(stride).collect::<Vec<_>>()
+        } else {
+            vec![]
+        };
+        let uncovered = positions.last().map_or(true, |&last| last + tile < len);
+
+        match policy {
+            BorderPolicy::Drop => {}
+            BorderPolicy::Shift => {
+                if uncovered && len >= tile {
+                    positions.push(len - tile);
+                }
+            }
+            BorderPolicy::Pad(_) => {
+                let next = positions.last().map_or(0, |&last| last + stride);
+                if uncovered && next < len {
+                    positions.push(next);
+                }
+            }
+        }
+        positions
+    }
+    // Position of a tile starting at pos along an axis of length len, None if it is dropped
+    fn place_tile(pos: u32, len: u32, tile: u32, policy: BorderPolicy) -> Option<u32> {
+        if pos + tile <= len {
+            return Some(pos);
+        }
+        match policy {
+            BorderPolicy::Drop => None,
+            BorderPolicy::Shift => if len >= tile { Some(len - tile) } else { None },
+            BorderPolicy::Pad(_) => Some(pos),
+        }
+    }
+    // Returns real and mask tile and the padding at the right and bottom border
+    fn crop_tile(&self,
+                 real: &DynamicImage,
+                 mask: &DynamicImage,
+                 x: u32,
+                 y: u32,
+                 width: u32,
+                 height: u32)
+                 -> (DynamicImage, DynamicImage, (u32, u32)) {
+        let padding = AugmentSplit::tile_padding(real.dimensions(), x, y, width, height);
+        let real_padding = match self.border_policy {
+            BorderPolicy::Pad(padding) => padding,
+            _ => Padding::Constant(0),
+        };
+        (resample::crop_border(real, x, y, width, height, real_padding),
+         resample::crop_border(mask, x, y, width, height, Padding::Constant(0)),
+         padding)
+    }
+    // Samples of a deep tile, padded like the real tile
+    fn crop_deep(&self,
+                 deep: Option<&Raster>,
+                 x: u32,
+                 y: u32,
+                 width: u32,
+                 height: u32)
+                 -> Option<Raster> {
+        let padding = match self.border_policy {
+            BorderPolicy::Pad(padding) => padding,
+            _ => Padding::Constant(0),
+        };
+        deep.map(|deep| resample::crop_border_raster(deep, x, y, width, height, padding))
+    }
+    // Pixels of a tile reaching over the right and bottom border of an image of size dim
+    fn tile_padding(dim: (u32, u32), x: u32, y: u32, width: u32, height: u32) -> (u32, u32) {
+        ((x + width).saturating_sub(dim.0), (y + height).saturating_sub(dim.1))
+    }
+    // Background color and the fraction of background pixels from which on a tile is discarded,
+    // by default black with the channels of image
+    fn background(&self, image: &DynamicImage) -> (ColorValues, f32) {
+        match self.background {
+            Some((ref color, fraction)) => (color.clone(), fraction),
+            None => {
+                match *image {
+                    DynamicImage::ImageLuma8(_) |
+                    DynamicImage::ImageLumaA8(_) => (ColorValues::black_luma(), 0.2),
+                    _ => (ColorValues::black_rgb(), 0.2),
+                }
+            }
+        }
+    }
+    // Counts the pixels of color, leaving out the padding
+    fn count_color(color: &ColorValues, image: &DynamicImage, padding: (u32, u32)) -> Option<f32> {
+        if padding == (0, 0) {
+            return AugmentSplit::get_color(color, image).ok().map(|info| info.1);
+        }
+        let (width, height) = image.dimensions();
+        let valid = resample::crop_padded(image, 0, 0, width - padding.0, height - padding.1);
+        AugmentSplit::get_color(color, &valid).ok().map(|info| info.1)
+    }
+    // Gray, gray and alpha, RGB and RGBA sources are cut and written as they are, the alpha
+    // channel is left out when counting colors
+    fn check_input(&self,
+                   img_tuple: &(DynamicImage, DynamicImage),
+                   cv: &ColorValues,
+                   luma_mask: bool)
+                   -> AnsResult<()> {
+        let channels = match img_tuple.0 {
+            DynamicImage::ImageLuma8(_) |
+            DynamicImage::ImageLumaA8(_) => 1,
+            _ => 3,
+        };
+        if self.background(&img_tuple.0).0.channels() != Some(channels) {
+            return Err(AnsError::Unsupported(String::from("source image format doesn't match \
+                                                           the background color")));
+        }
+        match (&img_tuple.1, cv.channels()) {
+            (&DynamicImage::ImageLuma8(_), Some(1)) => Ok(()),
+            (&DynamicImage::ImageRgb8(_), Some(3)) if !luma_mask => Ok(()),
+            _ => {
+                Err(AnsError::Unsupported(String::from("label image format doesn't match the \
+                                                        label color")))
+            }
+        }
+    }
+    // Photometric augmentations only change the 8 bit view of deep tiles
+    fn check_deep(&self, deep: Option<&Raster>) -> AnsResult<()> {
+        if deep.is_some() && !self.augmentations.is_empty() {
+            return Err(AnsError::Unsupported(String::from("photometric augmentations of 16 bit \
+                                                           or multichannel images")));
+        }
+        Ok(())
+    }
+    pub fn get_color(color: &ColorValues,
+                     image: &DynamicImage)
+                     -> Result<(ColorValues, f32), &'static str> {
         match *image {
             DynamicImage::ImageLuma8(ref image) => {
-                if let ColorValues::LUMA(c) = color {
-                    let color_cnt = image.pixels().filter(|x| x.data == c).count();
-                    Ok((color, color_cnt as f32))
+                if color.channels() == Some(1) {
+                    let color_cnt = image.pixels().filter(|x| color.matches(&x.data)).count();
+                    Ok((color.clone(), color_cnt as f32))
+                } else {
+                    Err("Tried to compare [u8; 3] with [u8; 1]")
+                }
+            }
+            DynamicImage::ImageLumaA8(ref image) => {
+                if color.channels() == Some(1) {
+                    let color_cnt = image.pixels().filter(|x| color.matches(&x.data[..1])).count();
+                    Ok((color.clone(), color_cnt as f32))
                 } else {
                     Err("Tried to compare [u8; 3] with [u8; 1]")
                 }
             }
             DynamicImage::ImageRgb8(ref image) => {
-                if let ColorValues::RGB(c) = color {
-                    let color_cnt = image.pixels().filter(|x| x.data == c).count();
-                    Ok((color, color_cnt as f32))
+                if color.channels() == Some(3) {
+                    let color_cnt = image.pixels().filter(|x| color.matches(&x.data)).count();
+                    Ok((color.clone(), color_cnt as f32))
+                } else {
+                    Err("Tried to compare [u8; 1] with [u8; 3]")
+                }
+            }
+            DynamicImage::ImageRgba8(ref image) => {
+                if color.channels() == Some(3) {
+                    let color_cnt = image.pixels().filter(|x| color.matches(&x.data[..3])).count();
+                    Ok((color.clone(), color_cnt as f32))
                 } else {
                     Err("Tried to compare [u8; 1] with [u8; 3]")
                 }
             }
-            _ => Err("unsuppoerted image format"),
         }
     }
-    pub fn check_color(image: &DynamicImage, color: ColorValues, percentage: f32) -> bool {
+    // True if at least percentage of the pixels of image match color
+    pub fn check_color(image: &DynamicImage, color: &ColorValues, percentage: f32) -> bool {
         let dim = {
             let (x, y) = image.dimensions();
             (x as f32, y as f32)
         };
-        if let Some(majority_color) = AugmentSplit::majority_color(image) {
-            if majority_color.0 == color &&
-                //percentage of color in given image
-               (majority_color.1 as f32 / (dim.0 * dim.1)) >= percentage {
-                true
-            } else {
-                false
-            }
-        } else {
-            false
+        match AugmentSplit::get_color(color, image) {
+            Ok((_, color_cnt)) => color_cnt / (dim.0 * dim.1) >= percentage,
+            Err(_) => false,
         }
     }
     pub fn majority_color(image: &DynamicImage) -> Option<(ColorValues, usize)> {
-        let mut color_map: HashMap<ColorValues, usize> = HashMap::new();
+        let mut color_map: HashMap<Vec<u8>, usize> = HashMap::new();
         match *image {
             DynamicImage::ImageLuma8(ref image) => {
                 for pixel in image.pixels() {
-                    let color_cnt = color_map.entry(ColorValues::luma(pixel.data)).or_insert(0);
+                    let color_cnt = color_map.entry(pixel.data.to_vec()).or_insert(0);
+                    *color_cnt += 1;
+                }
+            }
+            DynamicImage::ImageLumaA8(ref image) => {
+                for pixel in image.pixels() {
+                    let color_cnt = color_map.entry(pixel.data[..1].to_vec()).or_insert(0);
                     *color_cnt += 1;
                 }
             }
             DynamicImage::ImageRgb8(ref image) => {
                 for pixel in image.pixels() {
-                    let color_cnt = color_map.entry(ColorValues::rgb(pixel.data)).or_insert(0);
+                    let color_cnt = color_map.entry(pixel.data.to_vec()).or_insert(0);
+                    *color_cnt += 1;
+                }
+            }
+            DynamicImage::ImageRgba8(ref image) => {
+                for pixel in image.pixels() {
+                    let color_cnt = color_map.entry(pixel.data[..3].to_vec()).or_insert(0);
                     *color_cnt += 1;
                 }
             }
-            _ => {}
         }
-        None
+        color_map.into_iter().max_by_key(|&(_, cnt)| cnt).map(|(color, cnt)| {
+            if color.len() == 1 {
+                (ColorValues::luma([color[0]]), cnt)
+            } else {
+                (ColorValues::rgb([color[0], color[1], color[2]]), cnt)
+            }
+        })
     }
-    fn set_split_size(&mut self, x: u32, y: u32) {
-        self.split_size = Some((x, y));
+}
+
+fn coco_file(pass: &str) -> String {
+    format!("annotations_{}.json", pass)
+}
+
+// FNV-1a
+fn fnv1a(s: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in s.bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
     }
-    fn get_split_size(&self) -> (u32, u32) {
-        if let Some((x, y)) = self.split_size {
-            (x, y)
-        } else {
-            (0, 0)
+    hash
+}
+
+// Random numbers, statistics and skipped inputs of a running pass
+// A source image or a slide region, which is read with a margin around it
+struct Source<'s> {
+    name: &'s String,
+    img_tuple: &'s (DynamicImage, DynamicImage),
+    deep: Option<&'s Raster>,
+    // Position of the image in the slide, tile positions are relative to the slide
+    origin: (u32, u32),
+    // x, y, width and height of the part of the image the tiles are placed in
+    window: (u32, u32, u32, u32),
+    // Tissue with the size of the image for the split pass
+    tissue: Option<GrayImage>,
+}
+
+struct PassState {
+    pass: Pass,
+    rng: StdRng,
+    // Width and height of the source image being cut
+    source_size: (u32, u32),
+    // Tissue of the slide being cut, at the resolution it was detected at
+    slide_tissue: Option<GrayImage>,
+    // Tile grid preview of the source image being cut
+    preview: Option<Preview>,
+    stats: Stats,
+    sheet: Option<ContactSheet>,
+    report: ErrorReport,
+}
+
+impl PassState {
+    fn new(pass: Pass, sheet: Option<ContactSheet>) -> AnsResult<PassState> {
+        Ok(PassState {
+            pass: pass,
+            rng: try!(StdRng::new()),
+            source_size: (0, 0),
+            slide_tissue: None,
+            preview: None,
+            stats: Stats::new(pass.name()),
+            sheet: sheet,
+            report: ErrorReport::new(),
+        })
+    }
+
+    // Every source image draws its own random numbers, seeded by the pass and its name. The
+    // tiles of an image don't depend on the order the images are cut in, so a resumed run cuts
+    // the remaining images just like an uninterrupted one.
+    fn start_source(&mut self, name: &str, size: (u32, u32)) {
+        self.source_size = size;
+        let hash = fnv1a(name);
+        let (a, b) = ((hash >> 32) as usize, hash as u32 as usize);
+        match self.pass {
+            Pass::Split => self.rng.reseed(&[1, 7, 7, 6, a, b]),
+            Pass::Oversample(_) => self.rng.reseed(&[1, 3, 3, 7, a, b]),
         }
     }
-    fn set_split_offset(&mut self, x: Option<u32>, y: Option<u32>) {
-        match (x, y) {
-            (Some(x_val), Some(y_val)) => {
-                self.split_offset = (Some(SplitOffset::Val(x_val)), Some(SplitOffset::Val(y_val)))
+
+    fn emit<V: TileVisitor>(&mut self, visitor: &mut V, tile: &SplitImage) -> AnsResult<()> {
+        self.stats.add_written(tile);
+        visitor.visit(tile)
+    }
+}
+
+// Writes the visited tiles into the output directories and records them in the manifest
+struct DiskWriter<'a> {
+    augment_split: &'a AugmentSplit,
+    manifest: Manifest,
+    shards: Option<ShardWriter>,
+    coco: Option<CocoWriter>,
+}
+
+enum ShardWriter {
+    TfRecord(TfRecordWriter),
+    WebDataset(TarShardWriter),
+}
+
+impl<'a> TileVisitor for DiskWriter<'a> {
+    fn visit(&mut self, tile: &SplitImage) -> AnsResult<()> {
+        match self.shards {
+            Some(ShardWriter::TfRecord(ref mut records)) => {
+                self.augment_split.write_record(tile, records, &mut self.manifest)
+            }
+            Some(ShardWriter::WebDataset(ref mut shards)) => {
+                self.augment_split.write_sample(tile, shards, &mut self.manifest)
             }
-            (Some(x_val), None) => {
-                self.split_offset = (Some(SplitOffset::Val(x_val)), Some(SplitOffset::Random))
+            None => {
+                try!(self.augment_split.write_to_file(tile, &mut self.manifest));
+                let real_path = self.manifest
+                    .get_entries()
+                    .last()
+                    .and_then(|e| e.real_path.clone());
+                if let (Some(coco), Some(real_path)) = (self.coco.as_mut(), real_path) {
+                    if let Some(mask) = AugmentSplit::label_mask(tile) {
+                        coco.add(&real_path, tile.label.as_ref(), &mask);
+                    }
+                }
+                Ok(())
             }
-            (None, Some(y_val)) => {
-                self.split_offset = (Some(SplitOffset::Random), Some(SplitOffset::Val(y_val)))
+        }
+    }
+
+    // Written after every source image, so an interrupted run can be resumed
+    fn finish_image(&mut self, source: &str) -> AnsResult<()> {
+        if let Some(ref coco) = self.coco {
+            let path = self.augment_split.manifest_dir().join(coco_file(self.manifest.get_pass()));
+            try!(coco.write(&path));
+        }
+        let position = match self.shards {
+            Some(ShardWriter::TfRecord(ref mut records)) => {
+                try!(records.flush());
+                Some(records.position())
             }
-            (None, None) => {
-                self.split_offset = (Some(SplitOffset::Random), Some(SplitOffset::Random))
+            Some(ShardWriter::WebDataset(ref mut shards)) => {
+                try!(shards.flush());
+                Some(shards.position())
             }
+            None => None,
+        };
+        self.manifest.finish_source(source, position)
+    }
+
+    fn finish(&mut self) -> AnsResult<()> {
+        if let Some(ShardWriter::WebDataset(ref mut shards)) = self.shards {
+            try!(shards.close());
+        }
+        if let Some(ref coco) = self.coco {
+            let path = self.augment_split.manifest_dir().join(coco_file(self.manifest.get_pass()));
+            try!(coco.write(&path));
+        }
+        Ok(())
+    }
+}
+
+// Iterator returned by AugmentSplit::tiles
+pub struct Tiles<'a, T: FindLabel + 'a> {
+    augment_split: &'a AugmentSplit,
+    sources: hash_map::Iter<'a, String, (DynamicImage, DynamicImage)>,
+    deep: &'a HashMap<String, Raster>,
+    slides: hash_map::Iter<'a, String, Slide>,
+    // The slide being cut and its regions which haven't been cut yet
+    slide: Option<(&'a String, &'a Slide)>,
+    regions: VecDeque<(u32, u32, u32, u32)>,
+    cv: &'a ColorValues,
+    label_fn: &'a mut T,
+    state: PassState,
+    // Tiles of the current source image which haven't been returned yet
+    buffer: VecDeque<SplitImage>,
+}
+
+impl<'a, T: FindLabel + 'a> Tiles<'a, T> {
+    // Statistics of the source images cut so far
+    pub fn get_stats(&self) -> &Stats {
+        &self.state.stats
+    }
+
+    pub fn get_report(&self) -> &ErrorReport {
+        &self.state.report
+    }
+}
+
+impl<'a, T: FindLabel + 'a> Iterator for Tiles<'a, T> {
+    type Item = AnsResult<SplitImage>;
+
+    fn next(&mut self) -> Option<AnsResult<SplitImage>> {
+        loop {
+            if let Some(tile) = self.buffer.pop_front() {
+                return Some(Ok(tile));
+            }
+            if let Some((name, slide)) = self.slide {
+                let cut = match self.regions.pop_front() {
+                    Some(region) => {
+                        self.augment_split.visit_region((name, slide),
+                                                        region,
+                                                        self.cv,
+                                                        self.label_fn,
+                                                        &mut self.state,
+                                                        &mut self.buffer)
+                    }
+                    None => Ok(false),
+                };
+                match cut {
+                    Ok(true) => {}
+                    Ok(false) => self.slide = None,
+                    Err(e) => return Some(Err(e)),
+                }
+                continue;
+            }
+            if let Some((name, img_tuple)) = self.sources.next() {
+                if let Err(e) = self.augment_split.visit_source((name, img_tuple),
+                                                                self.deep.get(name),
+                                                                self.cv,
+                                                                self.label_fn,
+                                                                &mut self.state,
+                                                                &mut self.buffer) {
+                    return Some(Err(e));
+                }
+                continue;
+            }
+            let (name, slide) = match self.slides.next() {
+                Some(slide) => slide,
+                None => return None,
+            };
+            match self.augment_split.start_slide(name, slide, &mut self.state) {
+                Ok(regions) => {
+                    self.regions = regions.into_iter().collect();
+                    self.slide = Some((name, slide));
+                }
+                Err(e) => {
+                    if let Err(e) = self.state.report.handle(self.augment_split.error_policy,
+                                                             name,
+                                                             e) {
+                        return Some(Err(e));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet, VecDeque};
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use image::{DynamicImage, FilterType, GenericImage, ImageBuffer, ImageFormat, Luma};
+    use ans::SplitOffset;
+    use ans::augment::{Cutout, CutoutMask};
+    use ans::ans_builder::AugmentSplitBuilder;
+    use ans::color_values::ColorValues;
+    use ans::label::Label;
+    use ans::resample::{self, Interpolation, Padding};
+    use ans::manifest::{self, Manifest};
+    use ans::return_type::{BorderPolicy, OutputLayout, OutputPolicy, Pass, RunMode};
+    use ans::shards::ShardPosition;
+    use ans::split_image::SplitImage;
+    use ans::stats::Stats;
+    use ans::tissue::{TissueChannel, TissueDetection};
+    use error::{AnsError, ErrorPolicy};
+    use img_reader::{ImgReader, LabelType};
+    use img_reader::raster::Raster;
+    use img_reader::slide::{Slide, SlideLabel};
+    use img_reader::tiff::{self, TiledTiff};
+    use super::{AugmentSplit, FindLabel, PassState};
+
+    struct Everything;
+
+    impl FindLabel for Everything {
+        fn label(&mut self, _: f32) -> Option<Label> {
+            Some(Label::Healthy)
+        }
+        fn label_fn(&self) -> Option<Label> {
+            Some(Label::Healthy)
+        }
+    }
+
+    // Labels tiles like the split pass of main
+    struct Threshold;
+
+    impl FindLabel for Threshold {
+        fn label(&mut self, r: f32) -> Option<Label> {
+            match r {
+                0.0...0.2 => Some(Label::Healthy),
+                0.5...1.0 => Some(Label::Sick),
+                _ => None,
+            }
+        }
+        fn label_fn(&self) -> Option<Label> {
+            None
+        }
+    }
+
+    // 16 pixel tiles every 12 pixels
+    fn builder() -> AugmentSplitBuilder {
+        AugmentSplitBuilder::new()
+            .set_img_dir(PathBuf::from("in"))
+            .set_label_type(LabelType::Img(PathBuf::from("labels")))
+            .set_split_size(Some((16, 16)))
+            .set_split_offset((Some(SplitOffset::Val(12)), Some(SplitOffset::Val(12))))
+            .set_img_type(ImageFormat::PNG)
+            .set_output_real("out")
+    }
+
+    fn augment_split(region: u32, scales: Vec<f32>) -> AugmentSplit {
+        builder()
+            .set_border_policy(BorderPolicy::Shift)
+            .set_scales(scales)
+            .set_context_scales(vec![0.5])
+            .with_random_zoom(0.6, 0.6)
+            .set_slide_region(region)
+            .build()
+            .unwrap()
+    }
+
+    fn split(augment_split: &AugmentSplit,
+             img_tuple: &(DynamicImage, DynamicImage))
+             -> VecDeque<SplitImage> {
+        let mut state = PassState::new(Pass::Split, None).unwrap();
+        let mut tiles = VecDeque::new();
+        augment_split.visit_source((&String::from("source.png"), img_tuple),
+                          None,
+                          &ColorValues::white_luma(),
+                          &mut Everything,
+                          &mut state,
+                          &mut tiles)
+            .unwrap();
+        tiles
+    }
+
+    // Textured real image without background and a checkered mask
+    fn source() -> (DynamicImage, DynamicImage) {
+        let (width, height) = (100, 70);
+        let real = ImageBuffer::from_fn(width, height, |x, y| {
+            ::image::Rgb { data: [(50 + (x * 7 + y * 3) % 200) as u8,
+                                  (50 + (x * x + y) % 200) as u8,
+                                  (50 + y * 5 % 200) as u8] }
+        });
+        let mask = ImageBuffer::from_fn(width, height, |x, y| {
+            Luma { data: [if (x / 10 + y / 10) % 2 == 0 { 255 } else { 0 }] }
+        });
+        (DynamicImage::ImageRgb8(real), DynamicImage::ImageLuma8(mask))
+    }
+
+    fn slide(img_tuple: &(DynamicImage, DynamicImage)) -> Slide {
+        let (width, height) = img_tuple.0.dimensions();
+        let samples = img_tuple.0.raw_pixels().into_iter().map(|v| v as u16).collect();
+        let raster = Raster::from_raw(width, height, 3, 8, samples).unwrap();
+        let path = env::temp_dir().join("ans_augment_split_slide.tif");
+        File::create(&path).unwrap().write_all(&tiff::encode(&raster)).unwrap();
+        Slide::new(TiledTiff::open(&path).unwrap(),
+                   SlideLabel::Image(img_tuple.1.to_luma()))
+    }
+
+    fn describe(tiles: VecDeque<SplitImage>) -> Vec<(u32, u32, bool, String, Vec<u8>, Vec<u8>)> {
+        let mut tiles = tiles.into_iter()
+            .map(|tile| {
+                (tile.get_x_offset(),
+                 tile.get_y_offset(),
+                 tile.is_context(),
+                 tile.get_augmentations().join("_"),
+                 tile.real.as_ref().unwrap().raw_pixels(),
+                 tile.mask.as_ref().unwrap().raw_pixels())
+            })
+            .collect::<Vec<_>>();
+        tiles.sort_by(|a, b| (a.0, a.1, a.2, &a.3).cmp(&(b.0, b.1, b.2, &b.3)));
+        tiles
+    }
+
+    #[test]
+    fn region_spans_match_tile_positions() {
+        let policies =
+            [BorderPolicy::Drop, BorderPolicy::Shift, BorderPolicy::Pad(Padding::Reflect)];
+        for &policy in &policies {
+            for len in 1..90 {
+                for &(tile, stride) in &[(8, 8), (8, 5), (10, 3), (7, 12)] {
+                    for &region in &[1, 8, 20, 33] {
+                        let whole = AugmentSplit::tile_positions(len, tile, stride, policy);
+                        let mut cut = AugmentSplit::region_spans(len, tile, stride, region, policy)
+                            .into_iter()
+                            .flat_map(|(start, span)| {
+                                AugmentSplit::tile_positions(span, tile, stride, policy)
+                                    .into_iter()
+                                    .map(move |p| start + p)
+                            })
+                            .collect::<Vec<_>>();
+                        cut.dedup();
+                        assert_eq!(cut, whole, "{:?} {} {} {} {}", policy, len, tile, stride,
+                                   region);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cuts_slides_across_regions() {
+        let img_tuple = source();
+        let slide = slide(&img_tuple);
+        let name = String::from("slide.tif");
+        let cv = ColorValues::white_luma();
+
+        let expected = describe(split(&augment_split(4096, vec![1.0]), &img_tuple));
+        assert!(expected.iter().any(|t| t.2) && expected.iter().any(|t| !t.3.is_empty()));
+
+        for &region in &[4096, 40, 16] {
+            let mut state = PassState::new(Pass::Split, None).unwrap();
+            let mut tiles = VecDeque::new();
+            augment_split(region, vec![1.0])
+                .visit_slide(&name, &slide, &cv, &mut Everything, &mut state, &mut tiles)
+                .unwrap();
+            assert!(describe(tiles) == expected, "region {}", region);
+        }
+    }
+
+    #[test]
+    fn rejects_scaled_slides() {
+        let slide = slide(&source());
+        let mut state = PassState::new(Pass::Split, None).unwrap();
+        let result = augment_split(40, vec![1.0, 0.5]).visit_slide(&String::from("slide.tif"),
+                                                                    &slide,
+                                                                    &ColorValues::white_luma(),
+                                                                    &mut Everything,
+                                                                    &mut state,
+                                                                    &mut VecDeque::new());
+        match result {
+            Err(AnsError::Unsupported(_)) => {}
+            _ => panic!("scaled slide was cut"),
+        }
+    }
+
+    #[test]
+    fn tiles_every_scale() {
+        let img_tuple = source();
+        let tiles = split(&builder().set_scales(vec![1.0, 0.5]).build().unwrap(), &img_tuple);
+        let native = tiles.iter().filter(|t| t.get_scale() == 1.0).collect::<Vec<_>>();
+        let halved = tiles.iter().filter(|t| t.get_scale() == 0.5).collect::<Vec<_>>();
+        // 100 x 70 pixels hold 8 x 5 tiles, 50 x 35 pixels 3 x 2 tiles
+        assert_eq!((native.len(), halved.len()), (40, 6));
+        assert_eq!(native.len() + halved.len(), tiles.len());
+
+        // Positions of the scaled tiles are in source pixels
+        let positions = halved.iter()
+            .map(|t| (t.get_x_offset(), t.get_y_offset()))
+            .collect::<Vec<_>>();
+        assert_eq!(positions, vec![(0, 0), (0, 24), (24, 0), (24, 24), (48, 0), (48, 24)]);
+        let mut half = resample::resample(&img_tuple.0,
+                                          0.5,
+                                          Interpolation::Filter(FilterType::Triangle));
+        let tile = halved.iter()
+            .find(|t| t.get_x_offset() == 24 && t.get_y_offset() == 24)
+            .unwrap();
+        assert_eq!(tile.real.as_ref().unwrap().raw_pixels(),
+                   half.crop(12, 12, 16, 16).raw_pixels());
+        assert!(halved.iter().all(|t| t.get_x_dim() == 16 && t.get_y_dim() == 16));
+    }
+
+    #[test]
+    fn zooms_around_every_tile() {
+        let img_tuple = source();
+        let tiles = split(&builder().with_random_zoom(0.5, 0.5).build().unwrap(), &img_tuple);
+        let zoomed = tiles.iter()
+            .filter(|t| t.get_augmentations().contains(&String::from("zoom0.50")))
+            .collect::<Vec<_>>();
+        assert_eq!((zoomed.len(), tiles.len()), (40, 80));
+
+        // A zoom of 0.5 resizes the 32 pixels around the tile centre back to 16
+        let tile = zoomed.iter()
+            .find(|t| t.get_x_offset() == 24 && t.get_y_offset() == 12)
+            .unwrap();
+        let real = resample::concentric_crop(&img_tuple.0,
+                                             (32, 20),
+                                             (16, 16),
+                                             0.5,
+                                             Interpolation::Filter(FilterType::Triangle));
+        let mask = resample::concentric_crop(&img_tuple.1,
+                                             (32, 20),
+                                             (16, 16),
+                                             0.5,
+                                             Interpolation::NearestNeighbor);
+        assert_eq!(tile.real.as_ref().unwrap().raw_pixels(), real.raw_pixels());
+        assert_eq!(tile.mask.as_ref().unwrap().raw_pixels(), mask.raw_pixels());
+        assert!(zoomed.iter().all(|t| t.get_x_dim() == 16 && t.get_y_dim() == 16));
+    }
+
+    #[test]
+    fn cutout_relabels_below_the_ratio() {
+        let cv = ColorValues::white_luma();
+        let white = ImageBuffer::from_pixel(16, 16, Luma { data: [255u8] });
+        let mut tile = SplitImage::new(&String::from("source.png"),
+                                       source().0.crop(0, 0, 16, 16),
+                                       DynamicImage::ImageLuma8(white),
+                                       Label::Sick,
+                                       (16, 16),
+                                       0,
+                                       0,
+                                       0);
+        tile.set_mask_ratio(Some(1.0));
+        let cutout = |mask, size| {
+            let augment_split = builder()
+                .set_cutout(Cutout::new((1, 1), (size, size), 1.0, mask))
+                .build()
+                .unwrap();
+            let mut state = PassState::new(Pass::Split, None).unwrap();
+            let erased = augment_split.cutout(&tile, &cv, &mut Threshold, &mut state);
+            (erased.map(|t| (t.label.as_ref().map(|l| l.name()), t.get_mask_ratio().unwrap())),
+             state.stats.image("source.png").rejected)
+        };
+
+        // The label is kept while at least half of the positive pixels are left
+        assert_eq!(cutout(CutoutMask::Erase, 0.25), (Some((Some("Sick"), 0.9375)), 0));
+        assert_eq!(cutout(CutoutMask::Keep, 1.0), (Some((Some("Sick"), 1.0)), 0));
+        // and found again below
+        assert_eq!(cutout(CutoutMask::Erase, 1.0), (Some((Some("Healthy"), 0.0)), 0));
+        // A copy without a label is rejected
+        assert_eq!(cutout(CutoutMask::Erase, 0.75), (None, 1));
+    }
+
+    // Source images in root/in, tiles and manifest in root/out
+    fn output(name: &str, policy: OutputPolicy) -> (PathBuf, AugmentSplit) {
+        let root = env::temp_dir().join(format!("ans_augment_split_{}", name));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("in")).unwrap();
+        fs::create_dir_all(root.join("out")).unwrap();
+        let augment_split = builder()
+            .set_img_dir(root.join("in"))
+            .set_output_policy(policy)
+            .build()
+            .unwrap();
+        (root.join("out"), augment_split)
+    }
+
+    #[test]
+    fn resumes_a_partially_finished_pass() {
+        let (out, mut augment_split) = output("resume", OutputPolicy::Resume);
+        let row = |source: &str| {
+            format!("{0},split,0,0,16,16,0,0,1,false,0,,,,,,{0}.png,\n", source)
+        };
+        let mut file = File::create(out.join(manifest::MANIFEST_CSV)).unwrap();
+        write!(file, "header\n{}{}{}", row("a"), row("b"), row("c")).unwrap();
+        let mut manifest = Manifest::new(out.clone(), "split");
+        let position = ShardPosition {
+            shard: 2,
+            records: 5,
+            bytes: 300,
+        };
+        manifest.finish_source("a", None).unwrap();
+        manifest.finish_source("b", Some(position)).unwrap();
+
+        // c was interrupted, its tiles are dropped from the manifest and cut again
+        let done = augment_split.prepare_output("split").unwrap();
+        assert_eq!(done, ["a", "b"].iter().map(|s| String::from(*s)).collect());
+        let sources = manifest::read_csv(&out.join(manifest::MANIFEST_CSV))
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.source)
+            .collect::<Vec<_>>();
+        assert_eq!(sources, vec!["a", "b"]);
+        assert_eq!(augment_split.shard_position("split").unwrap(), Some(position));
+        // The other pass hasn't been started
+        assert!(augment_split.prepare_output("oversample").unwrap().is_empty());
+        assert_eq!(augment_split.shard_position("oversample").unwrap(), None);
+    }
+
+    #[test]
+    fn fails_on_or_clears_existing_output() {
+        let (out, mut augment_split) = output("fail", OutputPolicy::Fail);
+        File::create(out.join("tile.png")).unwrap();
+        match augment_split.prepare_output("split") {
+            Err(AnsError::OutputExists(dir)) => assert_eq!(dir, out),
+            other => panic!("expected OutputExists, got {:?}", other),
+        }
+
+        let (out, mut augment_split) = output("overwrite", OutputPolicy::Overwrite);
+        File::create(out.join("tile.png")).unwrap();
+        assert!(augment_split.prepare_output("split").unwrap().is_empty());
+        assert!(!out.exists());
+    }
+
+    // Writes source() as JPEG files named after sources into root/in and root/labels
+    fn source_dir(name: &str, sources: &[&str]) -> (PathBuf, ImgReader) {
+        let root = env::temp_dir().join(format!("ans_augment_split_{}", name));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("in")).unwrap();
+        fs::create_dir_all(root.join("labels")).unwrap();
+        let (real, mask) = source();
+        for source in sources {
+            real.to_rgb().save(root.join("in").join(source)).unwrap();
+            mask.to_luma().save(root.join("labels").join(source)).unwrap();
+        }
+        let img_reader = ImgReader::new(root.join("in"),
+                                        LabelType::Img(root.join("labels")),
+                                        None,
+                                        ErrorPolicy::Abort)
+            .unwrap();
+        (root, img_reader)
+    }
+
+    #[test]
+    fn plans_tiles_without_writing() {
+        let (root, mut img_reader) = source_dir("dry_run", &["source.jpg"]);
+        let mut augment_split = builder()
+            .set_img_dir(root.join("in"))
+            .set_output_mask("out_mask")
+            .set_run_mode(RunMode::DryRun(PathBuf::from("plan")))
+            .build()
+            .unwrap();
+        let cv = ColorValues::white_luma();
+        let stats = augment_split.split(&mut img_reader, &cv, &mut Everything).unwrap();
+
+        assert_eq!(stats.total().total_written(), 40);
+        assert!(!root.join("out").exists() && !root.join("out_mask").exists());
+        let plan = root.join("plan");
+        let entries = manifest::read_csv(&plan.join(manifest::MANIFEST_CSV)).unwrap();
+        assert_eq!(entries.len(), 40);
+        assert!(entries.iter().all(|entry| entry.real_path.is_some()));
+        assert!(Stats::read(&plan, "split").unwrap().is_some());
+    }
+
+    #[test]
+    fn places_tiles_at_the_border() {
+        let positions = |len, policy| AugmentSplit::tile_positions(len, 16, 12, policy);
+        let pad = BorderPolicy::Pad(Padding::Replicate);
+        assert_eq!(positions(40, BorderPolicy::Drop), vec![0, 12, 24]);
+        assert_eq!(positions(40, BorderPolicy::Shift), vec![0, 12, 24]);
+        assert_eq!(positions(40, pad), vec![0, 12, 24]);
+        assert_eq!(positions(45, BorderPolicy::Drop), vec![0, 12, 24]);
+        assert_eq!(positions(45, BorderPolicy::Shift), vec![0, 12, 24, 29]);
+        assert_eq!(positions(45, pad), vec![0, 12, 24, 36]);
+        // Images smaller than a tile
+        assert!(positions(10, BorderPolicy::Drop).is_empty());
+        assert!(positions(10, BorderPolicy::Shift).is_empty());
+        assert_eq!(positions(10, pad), vec![0]);
+
+        assert_eq!(AugmentSplit::place_tile(30, 45, 16, BorderPolicy::Drop), None);
+        assert_eq!(AugmentSplit::place_tile(30, 45, 16, BorderPolicy::Shift), Some(29));
+        assert_eq!(AugmentSplit::place_tile(30, 45, 16, pad), Some(30));
+        assert_eq!(AugmentSplit::place_tile(0, 10, 16, BorderPolicy::Shift), None);
+        assert_eq!(AugmentSplit::tile_padding((45, 20), 36, 12, 16, 16), (7, 8));
+    }
+
+    #[test]
+    fn pads_small_images() {
+        let real = ImageBuffer::from_fn(10, 6, |x, _| ::image::Rgb { data: [100 + x as u8; 3] });
+        let mask = ImageBuffer::from_fn(10, 6, |x, _| {
+            Luma { data: [if x < 5 { 255u8 } else { 0 }] }
+        });
+        let img_tuple = (DynamicImage::ImageRgb8(real), DynamicImage::ImageLuma8(mask));
+        assert!(split(&builder().build().unwrap(), &img_tuple).is_empty());
+
+        let augment_split = builder()
+            .set_border_policy(BorderPolicy::Pad(Padding::Replicate))
+            .build()
+            .unwrap();
+        let tiles = split(&augment_split, &img_tuple);
+        assert_eq!(tiles.len(), 1);
+        let tile = &tiles[0];
+        assert_eq!(tile.get_padding(), (6, 10));
+        // The padded pixels don't count for the mask ratio
+        assert_eq!(tile.get_mask_ratio(), Some(0.5));
+        let real = tile.real.as_ref().unwrap().to_rgb();
+        assert_eq!(real.get_pixel(15, 15).data, [109, 109, 109]);
+        let mask = tile.mask.as_ref().unwrap().to_luma();
+        assert_eq!((mask.get_pixel(4, 15).data[0], mask.get_pixel(4, 5).data[0]), (0, 255));
+    }
+
+    #[test]
+    fn discards_tiles_without_tissue() {
+        // Pink tissue from 10 to 29 on a white background
+        let real = ImageBuffer::from_fn(40, 40, |x, y| {
+            let tissue = x >= 10 && x < 30 && y >= 10 && y < 30;
+            ::image::Rgb { data: if tissue { [200, 90, 160] } else { [240, 240, 240] } }
+        });
+        let mask = ImageBuffer::from_pixel(40, 40, Luma { data: [255u8] });
+        let img_tuple = (DynamicImage::ImageRgb8(real), DynamicImage::ImageLuma8(mask));
+        let augment_split = builder()
+            .set_tissue_detection(TissueDetection::new(TissueChannel::Saturation, 0.5, 0))
+            .build()
+            .unwrap();
+        let positions = split(&augment_split, &img_tuple)
+            .iter()
+            .map(|t| (t.get_x_offset(), t.get_y_offset()))
+            .collect::<Vec<_>>();
+        assert_eq!(positions, vec![(12, 12)]);
+    }
+
+    #[test]
+    fn iterates_the_visited_tiles() {
+        let (root, img_reader) = source_dir("tiles", &["a.jpg", "b.jpg"]);
+        let mut augment_split = builder()
+            .set_img_dir(root.join("in"))
+            .with_rotation()
+            .set_context_scales(vec![0.5])
+            .build()
+            .unwrap();
+        let cv = ColorValues::white_luma();
+
+        let mut visited = VecDeque::new();
+        let stats =
+            augment_split.visit(&img_reader, Pass::Split, &cv, &mut Everything, &mut visited)
+                .unwrap();
+        let tiles = augment_split.tiles(&img_reader, Pass::Split, &cv, &mut Everything)
+            .unwrap()
+            .collect::<Result<VecDeque<_>, _>>()
+            .unwrap();
+
+        assert_eq!(tiles.len() as u32, stats.total().total_written());
+        assert!(tiles.iter().any(|t| t.is_context()));
+        let names = |tiles: &VecDeque<SplitImage>| {
+            tiles.iter().map(|t| String::from(t.get_name())).collect::<Vec<_>>()
+        };
+        assert_eq!(names(&tiles), names(&visited));
+        assert_eq!(describe(tiles), describe(visited));
+        assert!(!root.join("out").exists());
+    }
+
+    #[test]
+    fn lays_out_class_folders_per_partition() {
+        let sources = ["a.jpg", "b.jpg", "c.jpg", "d.jpg", "e.jpg", "f.jpg"];
+        let (root, mut img_reader) = source_dir("image_folder", &sources);
+        let mut augment_split = builder()
+            .set_img_dir(root.join("in"))
+            .set_output_mask("out_mask")
+            .set_output_layout(OutputLayout::ImageFolder)
+            .set_partitions(0.5, 0.25, 0.25)
+            .set_run_mode(RunMode::DryRun(PathBuf::from("plan")))
+            .build()
+            .unwrap();
+        augment_split.split(&mut img_reader, &ColorValues::white_luma(), &mut Everything).unwrap();
+
+        let entries = manifest::read_csv(&root.join("plan").join(manifest::MANIFEST_CSV)).unwrap();
+        assert_eq!(entries.len(), 40 * sources.len());
+        let mut partitions = HashMap::new();
+        for entry in &entries {
+            let partition = entry.partition.clone().unwrap();
+            let real = format!("../out/{}/Healthy/", partition);
+            let mask = format!("../out_mask/{}/Healthy/", partition);
+            assert!(entry.real_path.as_ref().unwrap().starts_with(&real));
+            assert!(entry.mask_path.as_ref().unwrap().starts_with(&mask));
+            // Every tile of a source image ends up in the same partition
+            assert_eq!(partitions.entry(entry.source.clone()).or_insert(partition.clone()),
+                       &partition);
         }
+        let used = partitions.values().collect::<HashSet<_>>();
+        assert!(used.len() > 1 && used.iter().all(|p| ["train", "val", "test"].contains(&&p[..])));
     }
 }
@@ -0,0 +1,208 @@
+use image::GrayImage;
+
+use ans::components;
+use ans::label::Label;
+
+// File written beside every real tile
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BoxFormat {
+    // <class> <cx> <cy> <w> <h> per line, relative to the tile size
+    Yolo,
+    // Pascal VOC annotation XML
+    Voc,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BoxExport {
+    pub format: BoxFormat,
+    // Components with fewer pixels are left out
+    pub min_area: u32,
+    // Added on every side of a box, clipped to the tile
+    pub padding: u32,
+}
+
+impl BoxExport {
+    pub fn new(format: BoxFormat, min_area: u32, padding: u32) -> BoxExport {
+        BoxExport {
+            format: format,
+            min_area: min_area,
+            padding: padding,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self.format {
+            BoxFormat::Yolo => "txt",
+            BoxFormat::Voc => "xml",
+        }
+    }
+
+    // x, y, width and height of the connected components of the nonzero pixels of mask
+    pub fn boxes(&self, mask: &GrayImage) -> Vec<(u32, u32, u32, u32)> {
+        let (width, height) = mask.dimensions();
+        components::components(mask)
+            .iter()
+            .filter(|c| c.area() >= self.min_area)
+            .map(|c| {
+                let (x, y, w, h) = c.bbox;
+                let x0 = x.saturating_sub(self.padding);
+                let y0 = y.saturating_sub(self.padding);
+                let x1 = (x + w + self.padding).min(width);
+                let y1 = (y + h + self.padding).min(height);
+                (x0, y0, x1 - x0, y1 - y0)
+            })
+            .collect()
+    }
+
+    // Every box gets the label of the tile, an unlabelled tile gets an empty annotation. depth is
+    // the number of channels of the real tile.
+    pub fn annotation(&self,
+                      file_name: &str,
+                      depth: usize,
+                      label: Option<&Label>,
+                      mask: &GrayImage)
+                      -> String {
+        let (width, height) = mask.dimensions();
+        let boxes = match label {
+            Some(_) => self.boxes(mask),
+            None => vec![],
+        };
+        match self.format {
+            BoxFormat::Yolo => yolo(label, &boxes, width, height),
+            BoxFormat::Voc => voc(file_name, depth, label, &boxes, width, height),
+        }
+    }
+
+    // File listing the class names, YOLO class i is the name on line i
+    pub fn classes(&self) -> Option<(&'static str, String)> {
+        match self.format {
+            BoxFormat::Yolo => {
+                let mut labels = vec![Label::Healthy, Label::Sick, Label::Fuzzy];
+                labels.sort_by_key(|l| l.index());
+                let names = labels.iter().map(|l| format!("{}\n", l.name())).collect::<String>();
+                Some(("classes.txt", names))
+            }
+            BoxFormat::Voc => None,
+        }
+    }
+}
+
+fn yolo(label: Option<&Label>,
+        boxes: &[(u32, u32, u32, u32)],
+        width: u32,
+        height: u32)
+        -> String {
+    let mut lines = String::new();
+    if let Some(label) = label {
+        for &(x, y, w, h) in boxes {
+            lines.push_str(&format!("{} {:.6} {:.6} {:.6} {:.6}\n",
+                                    label.index(),
+                                    (x as f32 + w as f32 / 2.0) / width as f32,
+                                    (y as f32 + h as f32 / 2.0) / height as f32,
+                                    w as f32 / width as f32,
+                                    h as f32 / height as f32));
+        }
+    }
+    lines
+}
+
+// Coordinates are 1-based and inclusive like in the VOC devkit, boxes touching the tile border
+// are marked as truncated
+fn voc(file_name: &str,
+       depth: usize,
+       label: Option<&Label>,
+       boxes: &[(u32, u32, u32, u32)],
+       width: u32,
+       height: u32)
+       -> String {
+    let mut xml = format!("<annotation>\n  <filename>{}</filename>\n  <size>\n    \
+                           <width>{}</width>\n    <height>{}</height>\n    \
+                           <depth>{}</depth>\n  </size>\n  <segmented>0</segmented>\n",
+                          escape(file_name),
+                          width,
+                          height,
+                          depth);
+    if let Some(label) = label {
+        for &(x, y, w, h) in boxes {
+            let truncated = x == 0 || y == 0 || x + w == width || y + h == height;
+            xml.push_str(&format!("  <object>\n    <name>{}</name>\n    \
+                                   <pose>Unspecified</pose>\n    <truncated>{}</truncated>\n    \
+                                   <difficult>0</difficult>\n    <bndbox>\n      \
+                                   <xmin>{}</xmin>\n      <ymin>{}</ymin>\n      \
+                                   <xmax>{}</xmax>\n      <ymax>{}</ymax>\n    </bndbox>\n  \
+                                   </object>\n",
+                                  label.name(),
+                                  truncated as u8,
+                                  x + 1,
+                                  y + 1,
+                                  x + w,
+                                  y + h));
+        }
+    }
+    xml.push_str("</annotation>\n");
+    xml
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, ImageBuffer, Luma};
+
+    use ans::label::Label;
+    use super::{BoxExport, BoxFormat};
+
+    // A 4 x 2 blob at (2, 3), a single pixel at (9, 0) on a 10 x 8 tile
+    fn mask() -> GrayImage {
+        ImageBuffer::from_fn(10, 8, |x, y| {
+            let inside = (x >= 2 && x < 6 && y >= 3 && y < 5) || (x, y) == (9, 0);
+            Luma { data: [if inside { 255 } else { 0 }] }
+        })
+    }
+
+    #[test]
+    fn boxes_components() {
+        assert_eq!(BoxExport::new(BoxFormat::Yolo, 1, 0).boxes(&mask()),
+                   vec![(9, 0, 1, 1), (2, 3, 4, 2)]);
+        assert_eq!(BoxExport::new(BoxFormat::Yolo, 2, 0).boxes(&mask()), vec![(2, 3, 4, 2)]);
+        // Padding is clipped to the tile
+        assert_eq!(BoxExport::new(BoxFormat::Yolo, 1, 3).boxes(&mask()),
+                   vec![(6, 0, 4, 4), (0, 0, 9, 8)]);
+    }
+
+    #[test]
+    fn writes_yolo_lines() {
+        let export = BoxExport::new(BoxFormat::Yolo, 2, 0);
+        assert_eq!(export.annotation("a.png", 3, Some(&Label::Sick), &mask()),
+                   "1 0.400000 0.500000 0.400000 0.250000\n");
+        assert_eq!(export.annotation("a.png", 3, None, &mask()), "");
+        assert_eq!(export.extension(), "txt");
+        assert_eq!(export.classes(),
+                   Some(("classes.txt", String::from("Healthy\nSick\nFuzzy\n"))));
+    }
+
+    #[test]
+    fn writes_voc_xml() {
+        let export = BoxExport::new(BoxFormat::Voc, 1, 0);
+        let xml = export.annotation("a&b.png", 1, Some(&Label::Healthy), &mask());
+        assert!(xml.starts_with("<annotation>\n  <filename>a&amp;b.png</filename>\n  <size>\n    \
+                                 <width>10</width>\n    <height>8</height>\n    \
+                                 <depth>1</depth>\n"));
+        assert_eq!(xml.matches("<object>").count(), 2);
+        assert_eq!(xml.matches("<name>Healthy</name>").count(), 2);
+        // The pixel in the corner touches the border, the blob doesn't
+        assert!(xml.contains("<truncated>1</truncated>\n    <difficult>0</difficult>\n    \
+                              <bndbox>\n      <xmin>10</xmin>\n      <ymin>1</ymin>\n      \
+                              <xmax>10</xmax>\n      <ymax>1</ymax>"));
+        assert!(xml.contains("<truncated>0</truncated>\n    <difficult>0</difficult>\n    \
+                              <bndbox>\n      <xmin>3</xmin>\n      <ymin>4</ymin>\n      \
+                              <xmax>6</xmax>\n      <ymax>5</ymax>"));
+        assert!(xml.ends_with("</annotation>\n"));
+
+        let empty = export.annotation("a.png", 3, None, &mask());
+        assert!(!empty.contains("<object>"));
+        assert_eq!(export.classes(), None);
+    }
+}
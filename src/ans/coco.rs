@@ -0,0 +1,309 @@
+use std::collections::HashSet;
+use std::fs::{DirBuilder, File};
+use std::io::prelude::*;
+use std::path::Path;
+
+use image::GrayImage;
+
+use ans::components::{self, Component};
+use ans::label::Label;
+use ans::manifest::json_string;
+use error::{AnsError, AnsResult};
+use img_reader::json::Json;
+
+// How the instances of a COCO export are outlined
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Segmentation {
+    // Outer boundary of every instance, holes are filled
+    Polygon,
+    // Uncompressed run-length encoding, exact including holes
+    Rle,
+}
+
+// Collects tiles and the connected components of their masks as COCO instances, the category id
+// of an instance is the index of the tile label plus one
+pub struct CocoWriter {
+    segmentation: Segmentation,
+    images: Vec<String>,
+    annotations: Vec<String>,
+    // Highest ids given out so far
+    image_id: u64,
+    annotation_id: u64,
+}
+
+impl CocoWriter {
+    pub fn new(segmentation: Segmentation) -> CocoWriter {
+        CocoWriter {
+            segmentation: segmentation,
+            images: vec![],
+            annotations: vec![],
+            image_id: 0,
+            annotation_id: 0,
+        }
+    }
+
+    // Takes over the images of an earlier run listed in files and their annotations from the
+    // file at path, new images and annotations continue after their ids
+    pub fn read(&mut self, path: &Path, files: &HashSet<String>) -> AnsResult<()> {
+        if !path.exists() {
+            return Ok(());
+        }
+        let mut content = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut content));
+        let malformed = |msg: &str| AnsError::Manifest(format!("Malformed {:?}: {}", path, msg));
+        let json = try!(Json::parse(&content).map_err(|e| malformed(&e)));
+
+        let mut image_ids = HashSet::new();
+        for image in json.get("images").and_then(|i| i.as_array()).unwrap_or(&vec![]) {
+            let id = try!(image.get("id")
+                .and_then(|id| id.as_f64())
+                .ok_or(malformed("image without id")));
+            let file_name = image.get("file_name").and_then(|f| f.as_str()).unwrap_or("");
+            if files.contains(file_name) {
+                image_ids.insert(id as u64);
+                self.image_id = self.image_id.max(id as u64);
+                self.images.push(to_json(image));
+            }
+        }
+        for annotation in json.get("annotations").and_then(|a| a.as_array()).unwrap_or(&vec![]) {
+            let id = try!(annotation.get("id")
+                .and_then(|id| id.as_f64())
+                .ok_or(malformed("annotation without id")));
+            let image_id = annotation.get("image_id").and_then(|id| id.as_f64());
+            if image_id.map_or(false, |image_id| image_ids.contains(&(image_id as u64))) {
+                self.annotation_id = self.annotation_id.max(id as u64);
+                self.annotations.push(to_json(annotation));
+            }
+        }
+        Ok(())
+    }
+
+    // file_name is relative to the directory of the annotations file, instances are the nonzero
+    // pixels of mask
+    pub fn add(&mut self, file_name: &str, label: Option<&Label>, mask: &GrayImage) {
+        self.image_id += 1;
+        let image_id = self.image_id;
+        let (width, height) = mask.dimensions();
+        self.images.push(format!("{{\"id\":{},\"file_name\":{},\"width\":{},\"height\":{}}}",
+                                 image_id,
+                                 json_string(file_name),
+                                 width,
+                                 height));
+        let label = match label {
+            Some(label) => label,
+            None => return,
+        };
+
+        for component in components::components(mask) {
+            let segmentation = match self.segmentation {
+                Segmentation::Polygon => {
+                    let points = component.outline()
+                        .iter()
+                        .map(|&(x, y)| format!("{},{}", x, y))
+                        .collect::<Vec<_>>();
+                    format!("[[{}]]", points.join(","))
+                }
+                Segmentation::Rle => rle(&component, width, height),
+            };
+            let (x, y, w, h) = component.bbox;
+            self.annotation_id += 1;
+            self.annotations.push(format!("{{\"id\":{},\"image_id\":{},\"category_id\":{},\
+                                           \"segmentation\":{},\"area\":{},\"bbox\":[{},{},{},\
+                                           {}],\"iscrowd\":0}}",
+                                          self.annotation_id,
+                                          image_id,
+                                          label.index() + 1,
+                                          segmentation,
+                                          component.area(),
+                                          x,
+                                          y,
+                                          w,
+                                          h));
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> AnsResult<()> {
+        if let Some(dir) = path.parent() {
+            try!(DirBuilder::new().recursive(true).create(dir));
+        }
+        let categories = [Label::Healthy, Label::Sick, Label::Fuzzy]
+            .iter()
+            .map(|l| format!("{{\"id\":{},\"name\":{}}}", l.index() + 1, json_string(l.name())))
+            .collect::<Vec<_>>();
+        let json = format!("{{\"images\":[{}],\"annotations\":[{}],\"categories\":[{}]}}\n",
+                           self.images.join(",\n"),
+                           self.annotations.join(",\n"),
+                           categories.join(","));
+        let mut file = try!(File::create(path));
+        try!(file.write_all(json.as_bytes()));
+        Ok(())
+    }
+}
+
+fn to_json(json: &Json) -> String {
+    match *json {
+        Json::Null => String::from("null"),
+        Json::Bool(b) => b.to_string(),
+        Json::Number(n) => n.to_string(),
+        Json::String(ref s) => json_string(s),
+        Json::Array(ref values) => {
+            format!("[{}]", values.iter().map(to_json).collect::<Vec<_>>().join(","))
+        }
+        Json::Object(ref members) => {
+            let members = members.iter()
+                .map(|&(ref key, ref value)| format!("{}:{}", json_string(key), to_json(value)))
+                .collect::<Vec<_>>();
+            format!("{{{}}}", members.join(","))
+        }
+    }
+}
+
+// Column major run lengths starting with the background, as in the COCO format
+fn rle(component: &Component, width: u32, height: u32) -> String {
+    let mut inside = vec![false; (width * height) as usize];
+    for &(x, y) in &component.pixels {
+        inside[(x * height + y) as usize] = true;
+    }
+    let mut counts = vec![];
+    let mut value = false;
+    let mut run = 0;
+    for &pixel in &inside {
+        if pixel != value {
+            counts.push(run.to_string());
+            value = pixel;
+            run = 0;
+        }
+        run += 1;
+    }
+    counts.push(run.to_string());
+    format!("{{\"counts\":[{}],\"size\":[{},{}]}}",
+            counts.join(","),
+            height,
+            width)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::path::Path;
+
+    use image::{GrayImage, ImageBuffer, Luma};
+
+    use ans::label::Label;
+    use img_reader::json::Json;
+    use super::{CocoWriter, Segmentation};
+
+    // Two instances, a 2 x 2 square at (1, 1) and a pixel at (4, 3)
+    fn mask() -> GrayImage {
+        ImageBuffer::from_fn(5, 4, |x, y| {
+            let inside = (x >= 1 && x < 3 && y >= 1 && y < 3) || (x, y) == (4, 3);
+            Luma { data: [if inside { 255 } else { 0 }] }
+        })
+    }
+
+    fn read(path: &Path) -> Json {
+        let mut content = String::new();
+        File::open(path).unwrap().read_to_string(&mut content).unwrap();
+        Json::parse(&content).unwrap()
+    }
+
+    fn ids(json: &Json, key: &str, field: &str) -> Vec<u64> {
+        json.get(key)
+            .and_then(|a| a.as_array())
+            .unwrap()
+            .iter()
+            .map(|v| v.get(field).and_then(|id| id.as_f64()).unwrap() as u64)
+            .collect()
+    }
+
+    #[test]
+    fn annotates_every_instance() {
+        let path = env::temp_dir().join("ans_coco_instances.json");
+        let mut coco = CocoWriter::new(Segmentation::Polygon);
+        coco.add("Sick/a.png", Some(&Label::Sick), &mask());
+        coco.add("unlabelled.png", None, &mask());
+        coco.write(&path).unwrap();
+
+        let json = read(&path);
+        assert_eq!(ids(&json, "images", "id"), vec![1, 2]);
+        assert_eq!(ids(&json, "annotations", "image_id"), vec![1, 1]);
+        assert_eq!(ids(&json, "annotations", "category_id"), vec![2, 2]);
+        assert_eq!(ids(&json, "annotations", "area"), vec![4, 1]);
+        assert_eq!(ids(&json, "categories", "id"), vec![1, 2, 3]);
+        let square = &json.get("annotations").and_then(|a| a.as_array()).unwrap()[0];
+        let coordinates = |key| {
+            square.get(key)
+                .and_then(|s| s.as_array())
+                .unwrap()
+                .iter()
+                .flat_map(|v| v.as_array().cloned().unwrap_or_else(|| vec![v.clone()]))
+                .map(|v| v.as_f64().unwrap() as u32)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(coordinates("segmentation"), vec![1, 1, 3, 1, 3, 3, 1, 3]);
+        assert_eq!(coordinates("bbox"), vec![1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn encodes_runs_column_major() {
+        let path = env::temp_dir().join("ans_coco_rle.json");
+        let mut coco = CocoWriter::new(Segmentation::Rle);
+        coco.add("a.png", Some(&Label::Healthy), &mask());
+        coco.write(&path).unwrap();
+
+        let json = read(&path);
+        let annotations = json.get("annotations").and_then(|a| a.as_array()).unwrap();
+        let counts = |i: usize| {
+            annotations[i]
+                .get("segmentation")
+                .and_then(|s| s.get("counts"))
+                .and_then(|c| c.as_array())
+                .unwrap()
+                .iter()
+                .map(|c| c.as_f64().unwrap() as u32)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(counts(0), vec![5, 2, 2, 2, 9]);
+        assert_eq!(counts(1), vec![19, 1]);
+    }
+
+    #[test]
+    fn continues_ids_on_resume() {
+        let dir = env::temp_dir().join("ans_coco_resume");
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("coco_split.json");
+        let mut coco = CocoWriter::new(Segmentation::Polygon);
+        for file in &["a.png", "b.png", "c.png"] {
+            coco.add(file, Some(&Label::Sick), &mask());
+        }
+        coco.write(&path).unwrap();
+
+        // The tiles of c weren't finished, they are cut again
+        let files = ["a.png", "b.png"].iter().map(|f| String::from(*f)).collect::<HashSet<_>>();
+        let mut resumed = CocoWriter::new(Segmentation::Polygon);
+        resumed.read(&path, &files).unwrap();
+        resumed.add("c.png", Some(&Label::Sick), &mask());
+        resumed.write(&path).unwrap();
+
+        let json = read(&path);
+        assert_eq!(ids(&json, "images", "id"), vec![1, 2, 3]);
+        assert_eq!(ids(&json, "annotations", "id"), vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(ids(&json, "annotations", "image_id"), vec![1, 1, 2, 2, 3, 3]);
+        let names = json.get("images")
+            .and_then(|i| i.as_array())
+            .unwrap()
+            .iter()
+            .map(|i| i.get("file_name").and_then(|f| f.as_str()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["a.png", "b.png", "c.png"]);
+
+        // Nothing to resume from
+        let mut fresh = CocoWriter::new(Segmentation::Polygon);
+        fresh.read(&dir.join("missing.json"), &files).unwrap();
+        fresh.add("a.png", None, &mask());
+        assert_eq!(fresh.image_id, 1);
+    }
+}
@@ -1,19 +1,94 @@
+// Color space of ColorValues::Distance
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ColorSpace {
+    Rgb,
+    // CIE L*a*b* with a D65 white point
+    Lab,
+}
 
-#[derive(Clone, Copy, Eq, PartialEq, Hash)]
+// Describes which pixels count as a color, RGB and LUMA match exactly. The other variants match
+// pixels with the same number of channels as their base colors.
+#[derive(Clone, PartialEq, Debug)]
 pub enum ColorValues {
     RGB([u8; 3]),
     LUMA([u8; 1]),
+    // Every channel within ±delta of the color
+    Delta(Box<ColorValues>, u8),
+    // Euclidean distance to the color of at most the given value
+    Distance(Box<ColorValues>, f32, ColorSpace),
+    // Every channel between the channels of both colors, inclusive
+    Range(Box<ColorValues>, Box<ColorValues>),
+    // Any of the colors
+    Set(Vec<ColorValues>),
 }
 impl ColorValues {
     pub fn compare(&self, c: [u8; 1]) -> bool {
-        if let ColorValues::LUMA(ref cv) = *self {
-            if c[0] == cv[0] {
-                true
-            } else {
-                false
+        self.matches(&c)
+    }
+    // pixel holds the channels of a Luma or Rgb pixel, a different number of channels never
+    // matches
+    pub fn matches(&self, pixel: &[u8]) -> bool {
+        match *self {
+            ColorValues::RGB(ref c) => pixel == &c[..],
+            ColorValues::LUMA(ref c) => pixel == &c[..],
+            ColorValues::Delta(ref color, delta) => {
+                let c = color.channel_values();
+                c.len() == pixel.len() &&
+                c.iter().zip(pixel).all(|(&c, &p)| (c as i16 - p as i16).abs() <= delta as i16)
             }
-        } else {
-            false
+            ColorValues::Distance(ref color, distance, space) => {
+                let c = color.channel_values();
+                if c.len() != pixel.len() {
+                    return false;
+                }
+                let (a, b) = match space {
+                    ColorSpace::Rgb => (to_f32(&c), to_f32(pixel)),
+                    ColorSpace::Lab => (lab(&c), lab(pixel)),
+                };
+                let squared = a.iter().zip(b.iter()).map(|(a, b)| (a - b) * (a - b)).sum::<f32>();
+                squared <= distance * distance
+            }
+            ColorValues::Range(ref low, ref high) => {
+                let (low, high) = (low.channel_values(), high.channel_values());
+                low.len() == pixel.len() && high.len() == pixel.len() &&
+                pixel.iter().zip(low.iter().zip(high.iter())).all(|(p, (l, h))| l <= p && p <= h)
+            }
+            ColorValues::Set(ref colors) => colors.iter().any(|c| c.matches(pixel)),
+        }
+    }
+    // Number of channels of the matched pixels, None for a set of colors with different counts
+    pub fn channels(&self) -> Option<usize> {
+        match *self {
+            ColorValues::RGB(_) => Some(3),
+            ColorValues::LUMA(_) => Some(1),
+            ColorValues::Delta(ref color, _) |
+            ColorValues::Distance(ref color, _, _) => color.channels(),
+            ColorValues::Range(ref low, ref high) => {
+                if low.channels() == high.channels() {
+                    low.channels()
+                } else {
+                    None
+                }
+            }
+            ColorValues::Set(ref colors) => {
+                let channels = colors.first().and_then(|c| c.channels());
+                if colors.iter().all(|c| c.channels() == channels) {
+                    channels
+                } else {
+                    None
+                }
+            }
+        }
+    }
+    // Channels of the base color, empty for sets
+    pub fn channel_values(&self) -> Vec<u8> {
+        match *self {
+            ColorValues::RGB(ref c) => c.to_vec(),
+            ColorValues::LUMA(ref c) => c.to_vec(),
+            ColorValues::Delta(ref color, _) |
+            ColorValues::Distance(ref color, _, _) |
+            ColorValues::Range(ref color, _) => color.channel_values(),
+            ColorValues::Set(_) => vec![],
         }
     }
     pub fn rgb(color: [u8; 3]) -> ColorValues {
@@ -22,6 +97,18 @@ impl ColorValues {
     pub fn luma(color: [u8; 1]) -> ColorValues {
         ColorValues::LUMA(color)
     }
+    pub fn with_delta(self, delta: u8) -> ColorValues {
+        ColorValues::Delta(Box::new(self), delta)
+    }
+    pub fn with_distance(self, distance: f32, space: ColorSpace) -> ColorValues {
+        ColorValues::Distance(Box::new(self), distance, space)
+    }
+    pub fn range(low: ColorValues, high: ColorValues) -> ColorValues {
+        ColorValues::Range(Box::new(low), Box::new(high))
+    }
+    pub fn set(colors: Vec<ColorValues>) -> ColorValues {
+        ColorValues::Set(colors)
+    }
     pub fn black_rgb() -> ColorValues {
         ColorValues::rgb([0, 0, 0])
     }
@@ -36,3 +123,110 @@ impl ColorValues {
         ColorValues::luma([255])
     }
 }
+
+fn to_f32(pixel: &[u8]) -> Vec<f32> {
+    pixel.iter().map(|&c| c as f32).collect()
+}
+
+// sRGB to CIE L*a*b*, luma pixels are treated as gray
+fn lab(pixel: &[u8]) -> Vec<f32> {
+    let rgb = if pixel.len() == 1 {
+        [pixel[0], pixel[0], pixel[0]]
+    } else {
+        [pixel[0], pixel[1], pixel[2]]
+    };
+    let linear = |c: u8| {
+        let c = c as f32 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    let (r, g, b) = (linear(rgb[0]), linear(rgb[1]), linear(rgb[2]));
+
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+
+    let f = |t: f32| {
+        if t > 0.008856 {
+            t.powf(1.0 / 3.0)
+        } else {
+            7.787 * t + 16.0 / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    vec![116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Luma};
+
+    use ans::augment_split::AugmentSplit;
+    use super::{lab, ColorSpace, ColorValues};
+
+    #[test]
+    fn exact_colors() {
+        assert!(ColorValues::white_rgb().matches(&[255, 255, 255]));
+        assert!(!ColorValues::white_rgb().matches(&[255, 255, 254]));
+        assert!(ColorValues::white_luma().compare([255]));
+        // Luma colors never match RGB pixels
+        assert!(!ColorValues::white_luma().matches(&[255, 255, 255]));
+    }
+
+    #[test]
+    fn tolerances() {
+        let delta = ColorValues::rgb([200, 10, 10]).with_delta(5);
+        assert!(delta.matches(&[205, 5, 14]));
+        assert!(!delta.matches(&[206, 10, 10]));
+        assert!(!delta.matches(&[200]));
+
+        let rgb = ColorValues::rgb([100, 100, 100]).with_distance(5.0, ColorSpace::Rgb);
+        assert!(rgb.matches(&[103, 104, 100]));
+        assert!(!rgb.matches(&[104, 104, 100]));
+
+        let lab_distance = ColorValues::rgb([128, 128, 128]).with_distance(3.0, ColorSpace::Lab);
+        assert!(lab_distance.matches(&[129, 128, 127]));
+        assert!(!lab_distance.matches(&[120, 128, 136]));
+        assert!(ColorValues::luma([128]).with_distance(0.5, ColorSpace::Lab).matches(&[128]));
+    }
+
+    #[test]
+    fn ranges_and_sets() {
+        let range = ColorValues::range(ColorValues::rgb([10, 20, 30]),
+                                       ColorValues::rgb([20, 20, 40]));
+        assert!(range.matches(&[10, 20, 40]));
+        assert!(!range.matches(&[21, 20, 30]));
+        assert_eq!(range.channels(), Some(3));
+
+        let set = ColorValues::set(vec![ColorValues::white_luma().with_delta(10),
+                                        ColorValues::luma([100])]);
+        assert!(set.matches(&[250]) && set.matches(&[100]) && !set.matches(&[101]));
+        assert_eq!(set.channels(), Some(1));
+        let mixed = ColorValues::set(vec![ColorValues::white_luma(), ColorValues::white_rgb()]);
+        assert_eq!(mixed.channels(), None);
+        assert!(mixed.matches(&[255]) && mixed.matches(&[255, 255, 255]));
+    }
+
+    #[test]
+    fn lab_of_white_and_black() {
+        let white = lab(&[255, 255, 255]);
+        assert!((white[0] - 100.0).abs() < 0.05 && white[1].abs() < 0.05 && white[2].abs() < 0.05);
+        assert!(lab(&[0])[0].abs() < 0.05);
+    }
+
+    #[test]
+    fn counts_with_the_same_matching() {
+        // Anti-aliased mask, half of it within 10 of white
+        let mask = DynamicImage::ImageLuma8(ImageBuffer::from_fn(4, 4, |x, _| {
+            Luma { data: [[255u8, 248, 200, 0][x as usize]] }
+        }));
+        let white = ColorValues::white_luma().with_delta(10);
+        assert_eq!(AugmentSplit::get_color(&white, &mask).unwrap().1, 8.0);
+        assert!(AugmentSplit::check_color(&mask, &white, 0.5));
+        assert!(!AugmentSplit::check_color(&mask, &ColorValues::white_luma(), 0.5));
+        assert!(AugmentSplit::get_color(&ColorValues::white_rgb(), &mask).is_err());
+    }
+}
@@ -0,0 +1,145 @@
+use image::GrayImage;
+
+// 8-connected region of nonzero mask pixels
+pub struct Component {
+    // In raster order
+    pub pixels: Vec<(u32, u32)>,
+    // x, y, width and height of the bounding box
+    pub bbox: (u32, u32, u32, u32),
+}
+
+impl Component {
+    pub fn area(&self) -> u32 {
+        self.pixels.len() as u32
+    }
+
+    // Corners of the outer boundary along the pixel edges, clockwise starting at the top left
+    // corner of the first pixel. Holes are not part of the outline.
+    pub fn outline(&self) -> Vec<(u32, u32)> {
+        let (bx, by, bw, bh) = self.bbox;
+        let mut inside = vec![false; (bw * bh) as usize];
+        for &(x, y) in &self.pixels {
+            inside[((y - by) * bw + x - bx) as usize] = true;
+        }
+        let pixel = |x: i64, y: i64| {
+            let (x, y) = (x - bx as i64, y - by as i64);
+            x >= 0 && y >= 0 && x < bw as i64 && y < bh as i64 &&
+            inside[(y * bw as i64 + x) as usize]
+        };
+        // Boundary edge leaving the corner in direction d with the component on its right
+        let edge = |(x, y): (i64, i64), d: (i64, i64)| {
+            match d {
+                (1, 0) => pixel(x, y) && !pixel(x, y - 1),
+                (0, 1) => pixel(x - 1, y) && !pixel(x, y),
+                (-1, 0) => pixel(x - 1, y - 1) && !pixel(x - 1, y),
+                _ => pixel(x, y - 1) && !pixel(x - 1, y - 1),
+            }
+        };
+
+        let start = (self.pixels[0].0 as i64, self.pixels[0].1 as i64);
+        let mut corner = start;
+        let mut direction = (1, 0);
+        let mut points = vec![self.pixels[0]];
+        loop {
+            corner = (corner.0 + direction.0, corner.1 + direction.1);
+            // Turning left first keeps diagonally touching pixels in one outline
+            let (dx, dy) = direction;
+            let next = [(dy, -dx), direction, (-dy, dx)]
+                .iter()
+                .cloned()
+                .find(|&d| edge(corner, d))
+                .unwrap_or((-dx, -dy));
+            if corner == start && next == (1, 0) {
+                break;
+            }
+            if next != direction {
+                points.push((corner.0 as u32, corner.1 as u32));
+            }
+            direction = next;
+        }
+        points
+    }
+}
+
+// Connected components of the nonzero pixels of mask, in raster order of their first pixel
+pub fn components(mask: &GrayImage) -> Vec<Component> {
+    let (width, height) = mask.dimensions();
+    let mut visited = vec![false; (width * height) as usize];
+    let mut components = vec![];
+
+    for (x, y, pixel) in mask.enumerate_pixels() {
+        if pixel.data[0] == 0 || visited[(y * width + x) as usize] {
+            continue;
+        }
+        visited[(y * width + x) as usize] = true;
+        let mut pixels = vec![];
+        let mut stack = vec![(x, y)];
+        while let Some((px, py)) = stack.pop() {
+            pixels.push((px, py));
+            for ny in py.saturating_sub(1)..(py + 2).min(height) {
+                for nx in px.saturating_sub(1)..(px + 2).min(width) {
+                    let i = (ny * width + nx) as usize;
+                    if !visited[i] && mask.get_pixel(nx, ny).data[0] != 0 {
+                        visited[i] = true;
+                        stack.push((nx, ny));
+                    }
+                }
+            }
+        }
+        pixels.sort_by_key(|&(px, py)| (py, px));
+
+        let min_x = pixels.iter().map(|p| p.0).min().unwrap_or(0);
+        let max_x = pixels.iter().map(|p| p.0).max().unwrap_or(0);
+        let min_y = pixels[0].1;
+        let max_y = pixels[pixels.len() - 1].1;
+        components.push(Component {
+            pixels: pixels,
+            bbox: (min_x, min_y, max_x - min_x + 1, max_y - min_y + 1),
+        });
+    }
+    components
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, ImageBuffer, Luma};
+
+    use super::components;
+
+    // '#' is a mask pixel
+    fn mask(rows: &[&str]) -> GrayImage {
+        ImageBuffer::from_fn(rows[0].len() as u32, rows.len() as u32, |x, y| {
+            let inside = rows[y as usize].as_bytes()[x as usize] == b'#';
+            Luma { data: [if inside { 255 } else { 0 }] }
+        })
+    }
+
+    #[test]
+    fn labels_8_connected_regions() {
+        let found = components(&mask(&["##...#", "#....#", ".#....", "....#.", "...#.."]));
+        let summary = found.iter().map(|c| (c.area(), c.bbox)).collect::<Vec<_>>();
+        assert_eq!(summary,
+                   vec![(4, (0, 0, 2, 3)), (2, (5, 0, 1, 2)), (2, (3, 3, 2, 2))]);
+        assert_eq!(found[0].pixels, vec![(0, 0), (1, 0), (0, 1), (1, 2)]);
+        assert!(components(&mask(&["...", "..."])).is_empty());
+    }
+
+    #[test]
+    fn outlines_the_outer_boundary() {
+        let square = components(&mask(&["....", ".##.", ".##.", "...."]));
+        assert_eq!(square[0].outline(), vec![(1, 1), (3, 1), (3, 3), (1, 3)]);
+
+        let l = components(&mask(&["#.", "##"]));
+        assert_eq!(l[0].outline(), vec![(0, 0), (1, 0), (1, 1), (2, 1), (2, 2), (0, 2)]);
+
+        // Holes are left out
+        let ring = components(&mask(&["###", "#.#", "###"]));
+        assert_eq!(ring[0].outline(), vec![(0, 0), (3, 0), (3, 3), (0, 3)]);
+
+        // Diagonal neighbours stay in one outline
+        let diagonal = components(&mask(&["#.", ".#"]));
+        assert_eq!(diagonal.len(), 1);
+        let outline = diagonal[0].outline();
+        assert!(outline.contains(&(2, 2)) && outline.contains(&(0, 1)));
+    }
+}
@@ -0,0 +1,232 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::f32;
+use std::str::FromStr;
+
+use xml::reader::{EventReader, XmlEvent};
+
+use error::{AnsError, AnsResult};
+use ans::augment::{Augmentation, Photometric, Cutout, CutoutMask};
+
+// Reads the text of every leaf element of the config file, keyed by its path below the root
+// element, e.g. "augment/gaussian_noise/probability"
+pub fn read_values(path: &Path) -> AnsResult<HashMap<String, String>> {
+    let file = try!(File::open(path));
+    let parser = EventReader::new(BufReader::new(file));
+
+    let mut values = HashMap::new();
+    let mut stack: Vec<String> = vec![];
+
+    for event in parser {
+        match event {
+            Ok(XmlEvent::StartElement { name, .. }) => stack.push(name.local_name),
+            Ok(XmlEvent::EndElement { .. }) => {
+                stack.pop();
+            }
+            Ok(XmlEvent::Characters(text)) => {
+                if stack.len() > 1 {
+                    values.insert(stack[1..].join("/"), text.trim().to_string());
+                }
+            }
+            Err(e) => return Err(AnsError::Config(format!("Could not parse {:?}: {}", path, e))),
+            _ => {}
+        }
+    }
+    Ok(values)
+}
+
+// Every augmentation is configured by a <probability> and a <min>/<max> range of its parameter,
+// augmentations missing in the config file are not used
+pub fn read_augmentations(path: &Path) -> AnsResult<Vec<Augmentation>> {
+    let values = try!(read_values(path));
+    let mut augmentations = vec![];
+
+    if let Some(p) = try!(probability(&values, "gaussian_noise")) {
+        let sigma = try!(range_within(&values, "gaussian_noise/sigma", 0.0, f32::INFINITY));
+        augmentations.push(Augmentation::new(Photometric::GaussianNoise { sigma: sigma }, p));
+    }
+    if let Some(p) = try!(probability(&values, "salt_and_pepper")) {
+        let amount = try!(range_within(&values, "salt_and_pepper/amount", 0.0, 1.0));
+        augmentations.push(Augmentation::new(Photometric::SaltAndPepper { amount: amount }, p));
+    }
+    if let Some(p) = try!(probability(&values, "gaussian_blur")) {
+        let sigma = try!(range_within(&values, "gaussian_blur/sigma", 0.0, f32::INFINITY));
+        augmentations.push(Augmentation::new(Photometric::GaussianBlur { sigma: sigma }, p));
+    }
+    if let Some(p) = try!(probability(&values, "motion_blur")) {
+        let length = try!(range(&values, "motion_blur/length"));
+        augmentations.push(Augmentation::new(Photometric::MotionBlur { length: length }, p));
+    }
+    if let Some(p) = try!(probability(&values, "jpeg")) {
+        let quality: (u8, u8) = try!(range(&values, "jpeg/quality"));
+        if quality.0 < 1 || quality.1 > 100 {
+            return Err(AnsError::Config(format!("JPEG quality range {:?} is not within 1 to 100",
+                                                quality)));
+        }
+        augmentations.push(Augmentation::new(Photometric::Jpeg { quality: quality }, p));
+    }
+    Ok(augmentations)
+}
+
+// <mask> is either "keep" or "erase", see CutoutMask
+pub fn read_cutout(path: &Path) -> AnsResult<Option<Cutout>> {
+    let values = try!(read_values(path));
+
+    if let Some(p) = try!(probability(&values, "cutout")) {
+        let mask = match values.get("augment/cutout/mask").map(|m| m.to_lowercase()) {
+            Some(ref m) if m == "erase" => CutoutMask::Erase,
+            Some(ref m) if m == "keep" => CutoutMask::Keep,
+            None => CutoutMask::Keep,
+            Some(m) => {
+                return Err(AnsError::Config(format!("Unknown cutout mask policy {:?}", m)))
+            }
+        };
+        Ok(Some(Cutout::new(try!(range(&values, "cutout/count")),
+                            try!(range_within(&values, "cutout/size", 0.0, 1.0)),
+                            p,
+                            mask)))
+    } else {
+        Ok(None)
+    }
+}
+
+fn probability(values: &HashMap<String, String>, augmentation: &str) -> AnsResult<Option<f32>> {
+    let key = format!("augment/{}/probability", augmentation);
+    let p: f32 = match values.get(&key) {
+        Some(p) => try!(parse(p, &key)),
+        None => return Ok(None),
+    };
+    if !(p >= 0.0 && p <= 1.0) {
+        return Err(AnsError::Config(format!("{} of {} is not within 0 to 1", p, key)));
+    }
+    Ok(Some(p))
+}
+
+fn range<T>(values: &HashMap<String, String>, key: &str) -> AnsResult<(T, T)>
+    where T: FromStr + PartialOrd + Debug
+{
+    let get = |bound: &str| {
+        let key = format!("augment/{}/{}", key, bound);
+        match values.get(&key) {
+            Some(v) => parse(v, &key),
+            None => Err(AnsError::Config(format!("Missing value {}", key))),
+        }
+    };
+    let range = (try!(get("min")), try!(get("max")));
+    if range.0 > range.1 {
+        return Err(AnsError::Config(format!("Range {:?} of {} has its min above its max",
+                                            range,
+                                            key)));
+    }
+    Ok(range)
+}
+
+// Rejects NaN bounds as well
+fn range_within(values: &HashMap<String, String>,
+                key: &str,
+                low: f32,
+                high: f32)
+                -> AnsResult<(f32, f32)> {
+    let range: (f32, f32) = try!(range(values, key));
+    if !(range.0 >= low && range.1 <= high) {
+        return Err(AnsError::Config(format!("Range {:?} of {} is not within {} to {}",
+                                            range,
+                                            key,
+                                            low,
+                                            high)));
+    }
+    Ok(range)
+}
+
+fn parse<T: FromStr>(value: &str, key: &str) -> AnsResult<T> {
+    value.parse::<T>()
+        .map_err(|_| AnsError::Config(format!("Could not parse value {:?} of {}", value, key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use ans::augment::{CutoutMask, Photometric};
+    use super::{read_augmentations, read_cutout};
+
+    fn config(name: &str, augment: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("ans_config_{}.xml", name));
+        let mut file = File::create(&path).unwrap();
+        write!(file, "<config><augment>{}</augment></config>", augment).unwrap();
+        path
+    }
+
+    fn entry(name: &str, probability: &str, parameter: &str, min: &str, max: &str) -> String {
+        format!("<{0}><probability>{1}</probability><{2}><min>{3}</min><max>{4}</max></{2}></{0}>",
+                name,
+                probability,
+                parameter,
+                min,
+                max)
+    }
+
+    #[test]
+    fn reads_augmentations() {
+        let augment = entry("gaussian_noise", "0.3", "sigma", "2.0", "8.0") +
+                      &entry("motion_blur", "0.1", "length", "3", "9") +
+                      &entry("jpeg", "1", "quality", "30", "90");
+        let augmentations = read_augmentations(&config("valid", &augment)).unwrap();
+        assert_eq!(augmentations.len(), 3);
+        match augmentations[0].kind {
+            Photometric::GaussianNoise { sigma } => assert_eq!(sigma, (2.0, 8.0)),
+            ref kind => panic!("{:?}", kind),
+        }
+        assert_eq!(augmentations[0].probability, 0.3);
+        assert!(read_cutout(&config("valid", &augment)).unwrap().is_none());
+    }
+
+    #[test]
+    fn reads_cutout() {
+        let augment = entry("cutout", "0.2", "count", "1", "3")
+            .replace("</cutout>", "<size><min>0.1</min><max>0.3</max></size><mask>erase</mask>\
+                                   </cutout>");
+        let cutout = read_cutout(&config("cutout", &augment)).unwrap().unwrap();
+        assert_eq!((cutout.count, cutout.size, cutout.probability), ((1, 3), (0.1, 0.3), 0.2));
+        match cutout.mask {
+            CutoutMask::Erase => {}
+            mask => panic!("{:?}", mask),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let invalid = [entry("gaussian_noise", "0.3", "sigma", "-1.0", "8.0"),
+                       entry("gaussian_noise", "0.3", "sigma", "NaN", "8.0"),
+                       entry("gaussian_blur", "0.3", "sigma", "-0.5", "-0.1"),
+                       entry("motion_blur", "0.3", "length", "-3", "9"),
+                       entry("motion_blur", "0.3", "length", "9", "3"),
+                       entry("salt_and_pepper", "0.3", "amount", "0.1", "1.5"),
+                       entry("jpeg", "1.5", "quality", "30", "90"),
+                       entry("jpeg", "-0.1", "quality", "30", "90"),
+                       entry("jpeg", "0.5", "quality", "0", "90")];
+        for (i, augment) in invalid.iter().enumerate() {
+            let result = read_augmentations(&config(&format!("invalid{}", i), augment));
+            assert!(result.is_err(), "{}", augment);
+        }
+
+        let cutout = |probability: &str, min: &str, max: &str| {
+            entry("cutout", probability, "count", "1", "3")
+                .replace("</cutout>",
+                         &format!("<size><min>{}</min><max>{}</max></size></cutout>", min, max))
+        };
+        for (i, augment) in [cutout("2", "0.1", "0.3"), cutout("0.5", "0.3", "0.1"),
+                             cutout("0.5", "0.1", "1.3")]
+            .iter()
+            .enumerate() {
+            let result = read_cutout(&config(&format!("cutout{}", i), augment));
+            assert!(result.is_err(), "{}", augment);
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use image::{DynamicImage, FilterType, GenericImage, ImageBuffer, Rgb, RgbImage};
+use rand::{Rng, SeedableRng, StdRng};
+
+use ans::split_image::SplitImage;
+use error::AnsResult;
+
+const MAX_THUMB: u32 = 128;
+const GAP: u32 = 4;
+const STRIPE: u32 = 8;
+
+// Keeps a uniform sample of up to per_label tiles for every label (reservoir sampling)
+pub struct ContactSheet {
+    per_label: u32,
+    seen: BTreeMap<&'static str, u32>,
+    tiles: BTreeMap<&'static str, ([u8; 3], Vec<RgbImage>)>,
+    // Separate from the augmentation rng, so the sample doesn't change the planned tiles
+    rng: StdRng,
+}
+
+impl ContactSheet {
+    pub fn new(per_label: u32) -> ContactSheet {
+        ContactSheet {
+            per_label: per_label,
+            seen: BTreeMap::new(),
+            tiles: BTreeMap::new(),
+            rng: StdRng::from_seed(&[4, 2]),
+        }
+    }
+
+    pub fn offer(&mut self, split: &SplitImage) {
+        let (label, real) = match (&split.label, split.get_real()) {
+            (&Some(ref label), &Some(ref real)) => (label, real),
+            _ => return,
+        };
+        if self.per_label == 0 {
+            return;
+        }
+
+        let seen = self.seen.entry(label.name()).or_insert(0);
+        let tiles = &mut self.tiles.entry(label.name()).or_insert((label.color(), vec![])).1;
+        if (tiles.len() as u32) < self.per_label {
+            tiles.push(ContactSheet::thumbnail(real));
+        } else {
+            let j = self.rng.gen_range(0, *seen + 1);
+            if j < self.per_label {
+                tiles[j as usize] = ContactSheet::thumbnail(real);
+            }
+        }
+        *seen += 1;
+    }
+
+    fn thumbnail(real: &DynamicImage) -> RgbImage {
+        let (w, h) = real.dimensions();
+        let factor = (MAX_THUMB as f32 / w.max(h) as f32).min(1.0);
+        let w = ((w as f32 * factor).round() as u32).max(1);
+        let h = ((h as f32 * factor).round() as u32).max(1);
+        real.resize_exact(w, h, FilterType::Triangle).to_rgb()
+    }
+
+    // One row per label, starting with a stripe in the label color
+    pub fn render(&self) -> RgbImage {
+        let thumbs = self.tiles.values().flat_map(|t| t.1.iter());
+        let (thumb_w, thumb_h) =
+            thumbs.fold((1, 1), |(w, h), t| (w.max(t.width()), h.max(t.height())));
+
+        let rows = self.tiles.len().max(1) as u32;
+        let width = STRIPE + GAP + self.per_label.max(1) * (thumb_w + GAP) + GAP;
+        let height = rows * (thumb_h + GAP) + GAP;
+        let mut sheet = ImageBuffer::from_pixel(width, height, Rgb { data: [40, 40, 40] });
+
+        for (row, &(color, ref tiles)) in self.tiles.values().enumerate() {
+            let y = GAP + row as u32 * (thumb_h + GAP);
+            for dy in 0..thumb_h {
+                for dx in 0..STRIPE {
+                    sheet.put_pixel(GAP + dx, y + dy, Rgb { data: color });
+                }
+            }
+            for (col, tile) in tiles.iter().enumerate() {
+                let x = STRIPE + 2 * GAP + col as u32 * (thumb_w + GAP);
+                for (dx, dy, pixel) in tile.enumerate_pixels() {
+                    sheet.put_pixel(x + dx, y + dy, *pixel);
+                }
+            }
+        }
+        sheet
+    }
+
+    pub fn save(&self, path: &Path) -> AnsResult<()> {
+        try!(self.render().save(path));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Rgb};
+
+    use ans::label::Label;
+    use ans::split_image::SplitImage;
+    use super::{ContactSheet, GAP, STRIPE};
+
+    // A tile filled with the gray value
+    fn tile(value: u8, label: Label, size: u32) -> SplitImage {
+        let real = ImageBuffer::from_pixel(size, size, Rgb { data: [value, value, value] });
+        SplitImage::new(&String::from("source"),
+                        DynamicImage::ImageRgb8(real),
+                        DynamicImage::new_luma8(size, size),
+                        label,
+                        (size, size),
+                        0,
+                        0,
+                        0)
+    }
+
+    #[test]
+    fn samples_tiles_per_label() {
+        let mut sheet = ContactSheet::new(3);
+        for i in 0..20 {
+            sheet.offer(&tile(i, Label::Sick, 16));
+        }
+        sheet.offer(&tile(100, Label::Healthy, 16));
+        let mut unlabelled = tile(200, Label::Healthy, 16);
+        unlabelled.label = None;
+        sheet.offer(&unlabelled);
+
+        let image = sheet.render();
+        assert_eq!(image.dimensions(),
+                   (STRIPE + GAP + 3 * (16 + GAP) + GAP, 2 * (16 + GAP) + GAP));
+        // Rows are sorted by label name, each starts with a stripe in the label color
+        assert_eq!(image.get_pixel(GAP, GAP).data, Label::Healthy.color());
+        assert_eq!(image.get_pixel(GAP, 2 * GAP + 16).data, Label::Sick.color());
+
+        let x = |col: u32| STRIPE + 2 * GAP + col * (16 + GAP);
+        assert_eq!(image.get_pixel(x(0), GAP).data, [100, 100, 100]);
+        assert_eq!(image.get_pixel(x(1), GAP).data, [40, 40, 40]);
+        let mut sick = (0..3)
+            .map(|col| image.get_pixel(x(col), 2 * GAP + 16).data[0])
+            .collect::<Vec<_>>();
+        sick.sort();
+        sick.dedup();
+        assert_eq!(sick.len(), 3);
+        assert!(sick.iter().all(|&v| v < 20));
+        // The sample isn't just the first tiles offered
+        assert!(sick != vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn shrinks_large_tiles() {
+        let mut sheet = ContactSheet::new(1);
+        sheet.offer(&tile(50, Label::Fuzzy, 512));
+        assert_eq!(sheet.render().height(), 128 + 2 * GAP);
+    }
+}
@@ -0,0 +1,135 @@
+use image::{DynamicImage, GenericImage};
+
+use ans::color_values::ColorValues;
+
+// Summed-area table of the pixels matching a color, counts inside any rectangle come out in O(1)
+pub struct IntegralImage {
+    width: u32,
+    height: u32,
+    // (width + 1) * (height + 1) entries, the first row and column are 0
+    sums: Vec<u32>,
+}
+
+impl IntegralImage {
+    pub fn new<F: Fn(u32, u32) -> bool>(width: u32, height: u32, matches: F) -> IntegralImage {
+        let stride = (width + 1) as usize;
+        let mut sums = vec![0u32; stride * (height + 1) as usize];
+        for y in 0..height {
+            let mut row = 0;
+            for x in 0..width {
+                if matches(x, y) {
+                    row += 1;
+                }
+                let i = (y + 1) as usize * stride + (x + 1) as usize;
+                sums[i] = sums[i - stride] + row;
+            }
+        }
+        IntegralImage {
+            width: width,
+            height: height,
+            sums: sums,
+        }
+    }
+
+    // None if the channels of image and color don't fit together, like AugmentSplit::get_color
+    pub fn from_color(image: &DynamicImage, color: &ColorValues) -> Option<IntegralImage> {
+        let (width, height) = image.dimensions();
+        match (image, color.channels()) {
+            (&DynamicImage::ImageLuma8(ref image), Some(1)) => {
+                Some(IntegralImage::new(width,
+                                        height,
+                                        |x, y| color.matches(&image.get_pixel(x, y).data)))
+            }
+            (&DynamicImage::ImageLumaA8(ref image), Some(1)) => {
+                Some(IntegralImage::new(width,
+                                        height,
+                                        |x, y| color.matches(&image.get_pixel(x, y).data[..1])))
+            }
+            (&DynamicImage::ImageRgb8(ref image), Some(3)) => {
+                Some(IntegralImage::new(width,
+                                        height,
+                                        |x, y| color.matches(&image.get_pixel(x, y).data)))
+            }
+            (&DynamicImage::ImageRgba8(ref image), Some(3)) => {
+                Some(IntegralImage::new(width,
+                                        height,
+                                        |x, y| color.matches(&image.get_pixel(x, y).data[..3])))
+            }
+            _ => None,
+        }
+    }
+
+    // Matching pixels inside the rectangle, the part outside of the image is left out
+    pub fn count(&self, x: u32, y: u32, width: u32, height: u32) -> u32 {
+        let x0 = x.min(self.width) as usize;
+        let y0 = y.min(self.height) as usize;
+        let x1 = (x + width).min(self.width) as usize;
+        let y1 = (y + height).min(self.height) as usize;
+        let stride = (self.width + 1) as usize;
+
+        self.sums[y1 * stride + x1] + self.sums[y0 * stride + x0] -
+        self.sums[y0 * stride + x1] - self.sums[y1 * stride + x0]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Luma};
+
+    use ans::color_values::ColorValues;
+    use super::IntegralImage;
+
+    fn matches(x: u32, y: u32) -> bool {
+        (x * 7 + y * 13 + x * y) % 5 < 2
+    }
+
+    fn brute_force(x: u32, y: u32, width: u32, height: u32, (w, h): (u32, u32)) -> u32 {
+        let mut count = 0;
+        for j in y..(y + height).min(h) {
+            for i in x..(x + width).min(w) {
+                if matches(i, j) {
+                    count += 1;
+                }
+            }
+        }
+        count
+    }
+
+    #[test]
+    fn counts_every_rectangle() {
+        let (w, h) = (9, 7);
+        let table = IntegralImage::new(w, h, matches);
+        for y in 0..h + 2 {
+            for x in 0..w + 2 {
+                for height in 0..h + 3 {
+                    for width in 0..w + 3 {
+                        assert_eq!(table.count(x, y, width, height),
+                                   brute_force(x, y, width, height, (w, h)),
+                                   "rectangle {} {} {} {}",
+                                   x,
+                                   y,
+                                   width,
+                                   height);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn empty_image() {
+        let table = IntegralImage::new(0, 0, matches);
+        assert_eq!(table.count(0, 0, 4, 4), 0);
+    }
+
+    #[test]
+    fn from_color_matches_channels() {
+        let image = DynamicImage::ImageLuma8(ImageBuffer::from_fn(6, 5, |x, y| {
+            Luma { data: [if matches(x, y) { 250 } else { 10 }] }
+        }));
+        let white = ColorValues::white_luma().with_delta(10);
+        let table = IntegralImage::from_color(&image, &white).unwrap();
+        assert_eq!(table.count(0, 0, 6, 5), brute_force(0, 0, 6, 5, (6, 5)));
+        assert!(IntegralImage::from_color(&image, &ColorValues::white_rgb()).is_none());
+    }
+}
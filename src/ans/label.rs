@@ -9,11 +9,38 @@ pub enum Label {
 }
 
 impl Label {
-    pub fn determine_label(label_image: &DynamicImage, color: color_values::ColorValues) -> Label {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Label::Sick => "Sick",
+            Label::Fuzzy => "Fuzzy",
+            Label::Healthy => "Healthy",
+        }
+    }
+
+    // Class index in exported datasets
+    pub fn index(&self) -> u32 {
+        match *self {
+            Label::Healthy => 0,
+            Label::Sick => 1,
+            Label::Fuzzy => 2,
+        }
+    }
+
+    // Color used for the label in previews and contact sheets
+    pub fn color(&self) -> [u8; 3] {
+        match *self {
+            Label::Sick => [220, 40, 40],
+            Label::Fuzzy => [230, 190, 40],
+            Label::Healthy => [40, 180, 70],
+        }
+    }
+
+    // Sick if the most frequent color of label_image matches color
+    pub fn determine_label(label_image: &DynamicImage, color: &color_values::ColorValues) -> Label {
         // let set_percentage = 0.2;
         let major_color = augment_split::AugmentSplit::majority_color(&label_image);
         if let Some(mj) = major_color {
-            if mj.0 == color {
+            if color.matches(&mj.0.channel_values()) {
                 Label::Sick
             } else {
                 Label::Healthy
@@ -0,0 +1,512 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions, DirBuilder};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf, Component};
+
+use ans::shards::ShardPosition;
+use error::{AnsError, AnsResult};
+
+pub const MANIFEST_CSV: &str = "manifest.csv";
+pub const MANIFEST_JSONL: &str = "manifest.jsonl";
+
+const CSV_HEADER: &str = "source,pass,x_offset,y_offset,width,height,pad_right,\
+                                  pad_bottom,scale,context,rotation,augmentations,label,\
+                                  mask_ratio,background_ratio,partition,real_path,mask_path,\
+                                  source_width,source_height";
+const CSV_FIELDS: usize = 20;
+// Manifests written before the source size was recorded
+const CSV_FIELDS_WITHOUT_SIZE: usize = 18;
+
+// One row for every tile written to disk, the file paths are relative to the manifest directory
+#[derive(Clone, Debug)]
+pub struct ManifestEntry {
+    pub source: String,
+    // Either "split" or "oversample"
+    pub pass: String,
+    pub x_offset: u32,
+    pub y_offset: u32,
+    pub width: u32,
+    pub height: u32,
+    // Padded pixels at the border of the unrotated tile, they aren't part of the mask ratios
+    pub pad_right: u32,
+    pub pad_bottom: u32,
+    pub scale: f32,
+    pub context: bool,
+    // Rotation in degrees
+    pub rotation: u32,
+    pub augmentations: Vec<String>,
+    pub label: Option<String>,
+    pub mask_ratio: Option<f32>,
+    pub background_ratio: Option<f32>,
+    pub partition: Option<String>,
+    pub real_path: Option<String>,
+    pub mask_path: Option<String>,
+    // Width and height of the source image, the tiles don't cover strips at the border dropped by
+    // the BorderPolicy
+    pub source_size: Option<(u32, u32)>,
+}
+
+impl ManifestEntry {
+    pub fn to_csv(&self) -> String {
+        let fields = vec![csv_field(&self.source),
+                          csv_field(&self.pass),
+                          self.x_offset.to_string(),
+                          self.y_offset.to_string(),
+                          self.width.to_string(),
+                          self.height.to_string(),
+                          self.pad_right.to_string(),
+                          self.pad_bottom.to_string(),
+                          self.scale.to_string(),
+                          self.context.to_string(),
+                          self.rotation.to_string(),
+                          csv_field(&self.augmentations.join("+")),
+                          csv_field(self.label.as_ref().map_or("", |l| &l[..])),
+                          self.mask_ratio.map_or(String::new(), |r| r.to_string()),
+                          self.background_ratio.map_or(String::new(), |r| r.to_string()),
+                          csv_field(self.partition.as_ref().map_or("", |p| &p[..])),
+                          csv_field(self.real_path.as_ref().map_or("", |p| &p[..])),
+                          csv_field(self.mask_path.as_ref().map_or("", |p| &p[..])),
+                          self.source_size.map_or(String::new(), |s| s.0.to_string()),
+                          self.source_size.map_or(String::new(), |s| s.1.to_string())];
+        fields.join(",")
+    }
+
+    pub fn to_json(&self) -> String {
+        let augmentations = self.augmentations
+            .iter()
+            .map(|a| json_string(a))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        format!("{{\"source\":{},\"pass\":{},\"x_offset\":{},\"y_offset\":{},\"width\":{},\
+                 \"height\":{},\"pad_right\":{},\"pad_bottom\":{},\"scale\":{},\
+                 \"context\":{},\"rotation\":{},\
+                 \"augmentations\":[{}],\"label\":{},\"mask_ratio\":{},\
+                 \"background_ratio\":{},\"partition\":{},\"real_path\":{},\"mask_path\":{},\
+                 \"source_width\":{},\"source_height\":{}}}",
+                json_string(&self.source),
+                json_string(&self.pass),
+                self.x_offset,
+                self.y_offset,
+                self.width,
+                self.height,
+                self.pad_right,
+                self.pad_bottom,
+                json_number(self.scale),
+                self.context,
+                self.rotation,
+                augmentations,
+                json_option(&self.label),
+                self.mask_ratio.map_or(String::from("null"), json_number),
+                self.background_ratio.map_or(String::from("null"), json_number),
+                json_option(&self.partition),
+                json_option(&self.real_path),
+                json_option(&self.mask_path),
+                self.source_size.map_or(String::from("null"), |s| s.0.to_string()),
+                self.source_size.map_or(String::from("null"), |s| s.1.to_string()))
+    }
+}
+
+pub struct Manifest {
+    dir: PathBuf,
+    pass: String,
+    entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn new(dir: PathBuf, pass: &str) -> Manifest {
+        Manifest {
+            dir: dir,
+            pass: String::from(pass),
+            entries: vec![],
+        }
+    }
+
+    pub fn get_pass(&self) -> &str {
+        &self.pass
+    }
+
+    pub fn get_dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn get_entries(&self) -> &Vec<ManifestEntry> {
+        &self.entries
+    }
+
+    pub fn push(&mut self, entry: ManifestEntry) {
+        self.entries.push(entry);
+    }
+
+    // Path of file relative to the manifest directory
+    pub fn relative(&self, file: &Path) -> String {
+        relative_path(&self.dir, file)
+    }
+
+    // Writes the entries of a source image and adds it to the finished sources of the pass, also
+    // if it didn't yield any tile. position is where the shards stand after the source.
+    pub fn finish_source(&mut self,
+                         source: &str,
+                         position: Option<ShardPosition>)
+                         -> AnsResult<()> {
+        try!(self.write());
+        let path = self.dir.join(sources_file(&self.pass));
+        let new_file = !path.exists();
+        let mut file = try!(OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path));
+        let mut lines = String::new();
+        if new_file {
+            lines.push_str("source,shard,records,bytes\n");
+        }
+        lines.push_str(&csv_field(source));
+        match position {
+            Some(p) => lines.push_str(&format!(",{},{},{}\n", p.shard, p.records, p.bytes)),
+            None => lines.push_str(",,,\n"),
+        }
+        try!(file.write_all(lines.as_bytes()));
+        Ok(())
+    }
+
+    // Appends all collected entries to the CSV and JSON Lines manifest and clears them
+    pub fn write(&mut self) -> AnsResult<()> {
+        try!(DirBuilder::new().recursive(true).create(&self.dir));
+
+        let csv_path = self.dir.join(MANIFEST_CSV);
+        let new_csv = !csv_path.exists();
+        let mut csv = try!(OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(csv_path));
+        let mut jsonl = try!(OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(self.dir.join(MANIFEST_JSONL)));
+
+        let mut csv_lines = String::new();
+        if new_csv {
+            csv_lines.push_str(CSV_HEADER);
+            csv_lines.push('\n');
+        }
+        let mut json_lines = String::new();
+        for entry in self.entries.drain(..) {
+            csv_lines.push_str(&entry.to_csv());
+            csv_lines.push('\n');
+            json_lines.push_str(&entry.to_json());
+            json_lines.push('\n');
+        }
+
+        try!(csv.write_all(csv_lines.as_bytes()));
+        try!(jsonl.write_all(json_lines.as_bytes()));
+        Ok(())
+    }
+}
+
+// Source images finished in pass, sources_<pass>.csv in dir
+pub fn sources_file(pass: &str) -> String {
+    format!("sources_{}.csv", pass)
+}
+
+// Finished source images of pass, None if the pass hasn't been run in dir before
+pub fn read_sources(dir: &Path, pass: &str) -> AnsResult<Option<HashSet<String>>> {
+    let path = dir.join(sources_file(pass));
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut content = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut content));
+    Ok(Some(content.lines()
+        .skip(1)
+        .filter(|l| !l.is_empty())
+        .filter_map(|l| split_csv_line(l).into_iter().next())
+        .collect()))
+}
+
+// Shard position after the last finished source image of pass, None if it wasn't recorded
+pub fn read_shard_position(dir: &Path, pass: &str) -> AnsResult<Option<ShardPosition>> {
+    let path = dir.join(sources_file(pass));
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut content = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut content));
+    let last = match content.lines().skip(1).filter(|l| !l.is_empty()).last() {
+        Some(last) => split_csv_line(last),
+        None => return Ok(None),
+    };
+    if last.len() < 4 || last[1].is_empty() {
+        return Ok(None);
+    }
+    match (last[1].parse(), last[2].parse(), last[3].parse()) {
+        (Ok(shard), Ok(records), Ok(bytes)) => {
+            Ok(Some(ShardPosition {
+                shard: shard,
+                records: records,
+                bytes: bytes,
+            }))
+        }
+        _ => {
+            Err(AnsError::Manifest(format!("Invalid shard position in {}: {:?}",
+                                           sources_file(pass),
+                                           last)))
+        }
+    }
+}
+
+// Rewrites both manifest files in dir with the entries keep returns true for
+pub fn retain<F>(dir: &Path, keep: F) -> AnsResult<()>
+    where F: Fn(&ManifestEntry) -> bool
+{
+    let csv_path = dir.join(MANIFEST_CSV);
+    if !csv_path.exists() {
+        return Ok(());
+    }
+    let entries = try!(read_csv(&csv_path));
+    if entries.iter().all(|entry| keep(entry)) {
+        return Ok(());
+    }
+    let mut csv_lines = String::from(CSV_HEADER);
+    csv_lines.push('\n');
+    let mut json_lines = String::new();
+    for entry in entries.iter().filter(|entry| keep(entry)) {
+        csv_lines.push_str(&entry.to_csv());
+        csv_lines.push('\n');
+        json_lines.push_str(&entry.to_json());
+        json_lines.push('\n');
+    }
+    try!(try!(File::create(csv_path)).write_all(csv_lines.as_bytes()));
+    try!(try!(File::create(dir.join(MANIFEST_JSONL))).write_all(json_lines.as_bytes()));
+    Ok(())
+}
+
+pub fn read_csv(path: &Path) -> AnsResult<Vec<ManifestEntry>> {
+    let mut content = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut content));
+
+    let mut entries = vec![];
+    for line in content.lines().skip(1).filter(|l| !l.is_empty()) {
+        let f = split_csv_line(line);
+        if f.len() != CSV_FIELDS && f.len() != CSV_FIELDS_WITHOUT_SIZE {
+            return Err(AnsError::Manifest(format!("Malformed line {:?}", line)));
+        }
+        let opt = |s: &String| if s.is_empty() { None } else { Some(s.clone()) };
+        let malformed = |field: &str| {
+            AnsError::Manifest(format!("Malformed {} in line {:?}", field, line))
+        };
+
+        entries.push(ManifestEntry {
+            source: f[0].clone(),
+            pass: f[1].clone(),
+            x_offset: try!(f[2].parse().map_err(|_| malformed("x_offset"))),
+            y_offset: try!(f[3].parse().map_err(|_| malformed("y_offset"))),
+            width: try!(f[4].parse().map_err(|_| malformed("width"))),
+            height: try!(f[5].parse().map_err(|_| malformed("height"))),
+            pad_right: try!(f[6].parse().map_err(|_| malformed("pad_right"))),
+            pad_bottom: try!(f[7].parse().map_err(|_| malformed("pad_bottom"))),
+            scale: try!(f[8].parse().map_err(|_| malformed("scale"))),
+            context: f[9] == "true",
+            rotation: try!(f[10].parse().map_err(|_| malformed("rotation"))),
+            augmentations: if f[11].is_empty() {
+                vec![]
+            } else {
+                f[11].split('+').map(String::from).collect()
+            },
+            label: opt(&f[12]),
+            mask_ratio: f[13].parse().ok(),
+            background_ratio: f[14].parse().ok(),
+            partition: opt(&f[15]),
+            real_path: opt(&f[16]),
+            mask_path: opt(&f[17]),
+            source_size: match (f.get(18).and_then(|w| w.parse().ok()),
+                                f.get(19).and_then(|h| h.parse().ok())) {
+                (Some(width), Some(height)) => Some((width, height)),
+                _ => None,
+            },
+        });
+    }
+    Ok(entries)
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+pub fn json_number(n: f32) -> String {
+    if n.is_finite() {
+        n.to_string()
+    } else {
+        String::from("null")
+    }
+}
+
+fn json_option(s: &Option<String>) -> String {
+    s.as_ref().map_or(String::from("null"), |s| json_string(s))
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains(',') || s.contains('"') || s.contains('\n') {
+        format!("\"{}\"", s.replace("\"", "\"\""))
+    } else {
+        String::from(s)
+    }
+}
+
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(::std::mem::replace(&mut field, String::new())),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+fn relative_path(from: &Path, to: &Path) -> String {
+    let from = from.components().filter(|c| *c != Component::CurDir).collect::<Vec<_>>();
+    let to = to.components().filter(|c| *c != Component::CurDir).collect::<Vec<_>>();
+    let common = from.iter().zip(to.iter()).take_while(|&(a, b)| a == b).count();
+
+    let mut path = PathBuf::new();
+    for _ in common..from.len() {
+        path.push("..");
+    }
+    for c in &to[common..] {
+        path.push(c.as_os_str());
+    }
+    path.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::path::{Path, PathBuf};
+
+    use super::{read_csv, relative_path, split_csv_line, Manifest, ManifestEntry, MANIFEST_CSV,
+                MANIFEST_JSONL};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("ans_manifest_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn read(path: &Path) -> String {
+        let mut content = String::new();
+        File::open(path).unwrap().read_to_string(&mut content).unwrap();
+        content
+    }
+
+    fn entry(source: &str, x: u32) -> ManifestEntry {
+        ManifestEntry {
+            source: String::from(source),
+            pass: String::from("split"),
+            x_offset: x,
+            y_offset: 20,
+            width: 64,
+            height: 32,
+            pad_right: 4,
+            pad_bottom: 0,
+            scale: 0.5,
+            context: false,
+            rotation: 90,
+            augmentations: vec![String::from("blur1.0"), String::from("cutout2")],
+            label: Some(String::from("Sick")),
+            mask_ratio: Some(0.25),
+            background_ratio: None,
+            partition: Some(String::from("train")),
+            real_path: Some(format!("Sick/{}_{}.png", source, x)),
+            mask_path: None,
+            source_size: Some((1000, 800)),
+        }
+    }
+
+    #[test]
+    fn writes_csv_and_json_lines() {
+        let dir = temp_dir("write");
+        let mut manifest = Manifest::new(dir.clone(), "split");
+        manifest.push(entry("a, \"quoted\"", 0));
+        manifest.push(entry("b", 64));
+        manifest.write().unwrap();
+        assert!(manifest.get_entries().is_empty());
+        // A second write appends without repeating the header
+        manifest.push(entry("c", 128));
+        manifest.write().unwrap();
+
+        let entries = read_csv(&dir.join(MANIFEST_CSV)).unwrap();
+        assert_eq!(entries.iter().map(|e| &e.source[..]).collect::<Vec<_>>(),
+                   vec!["a, \"quoted\"", "b", "c"]);
+        assert_eq!(entries.iter().map(|e| e.to_csv()).collect::<Vec<_>>(),
+                   vec![entry("a, \"quoted\"", 0).to_csv(),
+                        entry("b", 64).to_csv(),
+                        entry("c", 128).to_csv()]);
+        assert_eq!(entries[1].augmentations, vec!["blur1.0", "cutout2"]);
+        assert_eq!(entries[1].source_size, Some((1000, 800)));
+        assert_eq!(entries[1].background_ratio, None);
+
+        let jsonl = read(&dir.join(MANIFEST_JSONL));
+        let lines = jsonl.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1],
+                   "{\"source\":\"b\",\"pass\":\"split\",\"x_offset\":64,\"y_offset\":20,\
+                    \"width\":64,\"height\":32,\"pad_right\":4,\"pad_bottom\":0,\"scale\":0.5,\
+                    \"context\":false,\"rotation\":90,\"augmentations\":[\"blur1.0\",\
+                    \"cutout2\"],\"label\":\"Sick\",\"mask_ratio\":0.25,\
+                    \"background_ratio\":null,\"partition\":\"train\",\
+                    \"real_path\":\"Sick/b_64.png\",\"mask_path\":null,\"source_width\":1000,\
+                    \"source_height\":800}");
+        assert!(lines[0].starts_with("{\"source\":\"a, \\\"quoted\\\"\","));
+    }
+
+    #[test]
+    fn reads_manifests_without_source_size() {
+        let dir = temp_dir("old");
+        fs::create_dir_all(&dir).unwrap();
+        let line = entry("a", 0).to_csv();
+        let old = line.rsplitn(3, ',').last().unwrap();
+        write!(File::create(dir.join(MANIFEST_CSV)).unwrap(), "header\n{}\n", old).unwrap();
+        let entries = read_csv(&dir.join(MANIFEST_CSV)).unwrap();
+        assert_eq!(entries[0].source_size, None);
+        assert_eq!(entries[0].real_path, Some(String::from("Sick/a_0.png")));
+    }
+
+    #[test]
+    fn splits_quoted_fields() {
+        assert_eq!(split_csv_line("a,\"b,c\",\"d\"\"e\",,f"),
+                   vec!["a", "b,c", "d\"e", "", "f"]);
+    }
+
+    #[test]
+    fn relative_paths() {
+        assert_eq!(relative_path(Path::new("./out"), Path::new("out/Sick/a.png")),
+                   "Sick/a.png");
+        assert_eq!(relative_path(Path::new("out/real"), Path::new("out/mask/a.png")),
+                   "../mask/a.png");
+    }
+}
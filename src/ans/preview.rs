@@ -0,0 +1,163 @@
+use std::path::Path;
+
+use image::{DynamicImage, FilterType, GenericImage, Rgb, RgbImage};
+
+use ans::color_values::ColorValues;
+use ans::label::Label;
+use error::AnsResult;
+
+const BACKGROUND: [u8; 3] = [90, 90, 90];
+const REJECTED: [u8; 3] = [60, 110, 230];
+const MASK: [u8; 3] = [255, 0, 255];
+
+// What became of a tile considered by split or oversample
+pub enum Outcome {
+    Label(Label),
+    Background,
+    Rejected,
+}
+
+impl Outcome {
+    fn color(&self) -> [u8; 3] {
+        match *self {
+            Outcome::Label(ref label) => label.color(),
+            Outcome::Background => BACKGROUND,
+            Outcome::Rejected => REJECTED,
+        }
+    }
+}
+
+// Downscaled copy of a source image with the mask blended on top, tiles are given in native
+// source coordinates
+pub struct Preview {
+    factor: f32,
+    image: RgbImage,
+}
+
+impl Preview {
+    pub fn new(real: &DynamicImage,
+               mask: &DynamicImage,
+               cv: &ColorValues,
+               max_size: u32)
+               -> Preview {
+        let (w, h) = real.dimensions();
+        let factor = (max_size as f32 / w.max(h) as f32).min(1.0);
+        let (pw, ph) = (((w as f32 * factor).round() as u32).max(1),
+                        ((h as f32 * factor).round() as u32).max(1));
+
+        let mut image = real.resize_exact(pw, ph, FilterType::Triangle).to_rgb();
+        let mask = mask.resize_exact(pw, ph, FilterType::Nearest);
+        let matches = if cv.channels() == Some(1) {
+            let mask = mask.to_luma();
+            mask.pixels().map(|p| cv.matches(&p.data)).collect::<Vec<_>>()
+        } else {
+            let mask = mask.to_rgb();
+            mask.pixels().map(|p| cv.matches(&p.data)).collect::<Vec<_>>()
+        };
+
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            if matches[(y * pw + x) as usize] {
+                for k in 0..3 {
+                    pixel.data[k] = ((pixel.data[k] as u32 * 3 + MASK[k] as u32 * 2) / 5) as u8;
+                }
+            }
+        }
+
+        Preview {
+            factor: factor,
+            image: image,
+        }
+    }
+
+    // Outline of a tile considered by split
+    pub fn tile(&mut self, x: u32, y: u32, w: u32, h: u32, outcome: &Outcome) {
+        let (x0, y0) = self.scaled(x, y);
+        let (x1, y1) = self.scaled(x + w, y + h);
+        let color = outcome.color();
+        for px in x0..x1.max(x0 + 1) {
+            self.put(px, y0, color);
+            self.put(px, y1.max(1) - 1, color);
+        }
+        for py in y0..y1.max(y0 + 1) {
+            self.put(x0, py, color);
+            self.put(x1.max(1) - 1, py, color);
+        }
+    }
+
+    // Cross at the centre of a crop sampled by oversample
+    pub fn marker(&mut self, x: u32, y: u32, w: u32, h: u32, outcome: &Outcome) {
+        let (cx, cy) = self.scaled(x + w / 2, y + h / 2);
+        let color = outcome.color();
+        for d in 0..7 {
+            self.put((cx + d).saturating_sub(3), cy, color);
+            self.put(cx, (cy + d).saturating_sub(3), color);
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> AnsResult<()> {
+        try!(self.image.save(path));
+        Ok(())
+    }
+
+    fn scaled(&self, x: u32, y: u32) -> (u32, u32) {
+        ((x as f32 * self.factor) as u32, (y as f32 * self.factor) as u32)
+    }
+
+    fn put(&mut self, x: u32, y: u32, color: [u8; 3]) {
+        if x < self.image.width() && y < self.image.height() {
+            self.image.put_pixel(x, y, Rgb { data: color });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Luma, Rgb};
+
+    use ans::color_values::ColorValues;
+    use ans::label::Label;
+    use super::{Outcome, Preview, BACKGROUND, REJECTED};
+
+    // 200 x 100 gray source, the left half of the mask is white
+    fn preview() -> Preview {
+        let real = ImageBuffer::from_pixel(200, 100, Rgb { data: [100u8, 100, 100] });
+        let mask = ImageBuffer::from_fn(200, 100, |x, _| {
+            Luma { data: [if x < 100 { 255u8 } else { 0 }] }
+        });
+        Preview::new(&DynamicImage::ImageRgb8(real),
+                     &DynamicImage::ImageLuma8(mask),
+                     &ColorValues::white_luma(),
+                     50)
+    }
+
+    #[test]
+    fn blends_the_mask_into_a_downscaled_copy() {
+        let preview = preview();
+        assert_eq!(preview.image.dimensions(), (50, 25));
+        assert_eq!(preview.image.get_pixel(10, 10).data, [162, 60, 162]);
+        assert_eq!(preview.image.get_pixel(40, 10).data, [100, 100, 100]);
+    }
+
+    #[test]
+    fn draws_tiles_and_markers_by_outcome() {
+        let mut preview = preview();
+        preview.tile(40, 20, 40, 40, &Outcome::Label(Label::Sick));
+        preview.tile(120, 0, 80, 100, &Outcome::Background);
+        preview.marker(0, 40, 40, 40, &Outcome::Rejected);
+
+        let pixel = |x, y| preview.image.get_pixel(x, y).data;
+        // Outline from (10, 5) to (19, 14) in preview coordinates
+        assert_eq!(pixel(10, 5), Label::Sick.color());
+        assert_eq!(pixel(19, 14), Label::Sick.color());
+        assert_eq!(pixel(15, 5), Label::Sick.color());
+        assert_eq!(pixel(15, 10), [162, 60, 162]);
+        // Clipped at the border of the preview
+        assert_eq!(pixel(49, 24), BACKGROUND);
+        assert_eq!(pixel(30, 24), BACKGROUND);
+        // Cross at the centre of the crop
+        assert_eq!(pixel(5, 15), REJECTED);
+        assert_eq!(pixel(2, 15), REJECTED);
+        assert_eq!(pixel(5, 18), REJECTED);
+        assert_eq!(pixel(4, 14), [162, 60, 162]);
+    }
+}
@@ -0,0 +1,442 @@
+use image::*;
+
+use img_reader::raster::Raster;
+
+// How pixels outside of the image are filled when a tile reaches over the border
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Padding {
+    // Every channel set to the value
+    Constant(u8),
+    // Mirrored at the border pixel, which isn't repeated
+    Reflect,
+    // The border pixel repeated
+    Replicate,
+}
+
+// Mask images are resampled with this filter, every other filter would blend label values
+pub enum Interpolation {
+    Filter(FilterType),
+    NearestNeighbor,
+}
+
+pub fn resample(image: &DynamicImage, scale: f32, interpolation: Interpolation) -> DynamicImage {
+    let (width, height) = image.dimensions();
+    let width = ((width as f32 * scale).round() as u32).max(1);
+    let height = ((height as f32 * scale).round() as u32).max(1);
+    resize(image, width, height, interpolation)
+}
+
+pub fn resize(image: &DynamicImage,
+              width: u32,
+              height: u32,
+              interpolation: Interpolation)
+              -> DynamicImage {
+    if image.dimensions() == (width, height) {
+        return image.clone();
+    }
+    match interpolation {
+        Interpolation::Filter(filter) => image.resize_exact(width, height, filter),
+        Interpolation::NearestNeighbor => {
+            match *image {
+                DynamicImage::ImageLuma8(ref img) => {
+                    DynamicImage::ImageLuma8(nearest(img, width, height))
+                }
+                DynamicImage::ImageLumaA8(ref img) => {
+                    DynamicImage::ImageLumaA8(nearest(img, width, height))
+                }
+                DynamicImage::ImageRgb8(ref img) => {
+                    DynamicImage::ImageRgb8(nearest(img, width, height))
+                }
+                DynamicImage::ImageRgba8(ref img) => {
+                    DynamicImage::ImageRgba8(nearest(img, width, height))
+                }
+            }
+        }
+    }
+}
+
+// Cuts out the given region, pixels outside of the image are left black
+pub fn crop_padded(image: &DynamicImage, x: i64, y: i64, width: u32, height: u32) -> DynamicImage {
+    match *image {
+        DynamicImage::ImageLuma8(ref img) => {
+            DynamicImage::ImageLuma8(crop_buffer(img, x, y, width, height))
+        }
+        DynamicImage::ImageLumaA8(ref img) => {
+            DynamicImage::ImageLumaA8(crop_buffer(img, x, y, width, height))
+        }
+        DynamicImage::ImageRgb8(ref img) => {
+            DynamicImage::ImageRgb8(crop_buffer(img, x, y, width, height))
+        }
+        DynamicImage::ImageRgba8(ref img) => {
+            DynamicImage::ImageRgba8(crop_buffer(img, x, y, width, height))
+        }
+    }
+}
+
+// Cuts out the given region, pixels right of or below the image are filled according to padding
+pub fn crop_border(image: &DynamicImage,
+                   x: u32,
+                   y: u32,
+                   width: u32,
+                   height: u32,
+                   padding: Padding)
+                   -> DynamicImage {
+    match *image {
+        DynamicImage::ImageLuma8(ref img) => {
+            DynamicImage::ImageLuma8(border_buffer(img, x, y, width, height, padding))
+        }
+        DynamicImage::ImageLumaA8(ref img) => {
+            DynamicImage::ImageLumaA8(border_buffer(img, x, y, width, height, padding))
+        }
+        DynamicImage::ImageRgb8(ref img) => {
+            DynamicImage::ImageRgb8(border_buffer(img, x, y, width, height, padding))
+        }
+        DynamicImage::ImageRgba8(ref img) => {
+            DynamicImage::ImageRgba8(border_buffer(img, x, y, width, height, padding))
+        }
+    }
+}
+
+// Cuts out a region of size / scale around centre and resizes it back to size, so a scale of 0.5
+// covers twice the width and height of the original tile
+pub fn concentric_crop(image: &DynamicImage,
+                       centre: (u32, u32),
+                       size: (u32, u32),
+                       scale: f32,
+                       interpolation: Interpolation)
+                       -> DynamicImage {
+    let region = (((size.0 as f32 / scale).round() as u32).max(1),
+                  ((size.1 as f32 / scale).round() as u32).max(1));
+    let x = centre.0 as i64 - (region.0 / 2) as i64;
+    let y = centre.1 as i64 - (region.1 / 2) as i64;
+
+    let crop = crop_padded(image, x, y, region.0, region.1);
+    resize(&crop, size.0, size.1, interpolation)
+}
+
+// Deep images are real images, so they are always resized with a triangle filter like
+// Interpolation::Filter(FilterType::Triangle)
+pub fn resample_raster(raster: &Raster, scale: f32) -> Raster {
+    let (width, height) = raster.dimensions();
+    let width = ((width as f32 * scale).round() as u32).max(1);
+    let height = ((height as f32 * scale).round() as u32).max(1);
+    resize_raster(raster, width, height)
+}
+
+pub fn resize_raster(raster: &Raster, width: u32, height: u32) -> Raster {
+    if raster.dimensions() == (width, height) {
+        return raster.clone();
+    }
+    let (src_width, src_height) = raster.dimensions();
+    let channels = raster.channels();
+    let columns = triangle_weights(src_width, width);
+    let rows = triangle_weights(src_height, height);
+
+    let mut horizontal = vec![0f32; width as usize * src_height as usize * channels];
+    for y in 0..src_height {
+        for (x, &(start, ref weights)) in columns.iter().enumerate() {
+            let out = (y as usize * width as usize + x) * channels;
+            for (k, weight) in weights.iter().enumerate() {
+                let pixel = raster.pixel((start + k) as u32, y);
+                for c in 0..channels {
+                    horizontal[out + c] += weight * pixel[c] as f32;
+                }
+            }
+        }
+    }
+    let max = raster.max_value() as f32;
+    let mut resized = Raster::new(width, height, channels, raster.bits());
+    for (y, &(start, ref weights)) in rows.iter().enumerate() {
+        for x in 0..width as usize {
+            let pixel = resized.pixel_mut(x as u32, y as u32);
+            for c in 0..channels {
+                let value = weights.iter()
+                    .enumerate()
+                    .map(|(k, weight)| {
+                        weight * horizontal[((start + k) * width as usize + x) * channels + c]
+                    })
+                    .fold(0.0, |sum, v| sum + v);
+                pixel[c] = value.round().max(0.0).min(max) as u16;
+            }
+        }
+    }
+    resized
+}
+
+// Same as crop_padded for deep images
+pub fn crop_padded_raster(raster: &Raster, x: i64, y: i64, width: u32, height: u32) -> Raster {
+    let (src_width, src_height) = raster.dimensions();
+    let mut crop = Raster::new(width, height, raster.channels(), raster.bits());
+    for j in 0..height {
+        let src_y = y + j as i64;
+        if src_y < 0 || src_y >= src_height as i64 {
+            continue;
+        }
+        for i in 0..width {
+            let src_x = x + i as i64;
+            if src_x < 0 || src_x >= src_width as i64 {
+                continue;
+            }
+            crop.pixel_mut(i, j).copy_from_slice(raster.pixel(src_x as u32, src_y as u32));
+        }
+    }
+    crop
+}
+
+// Same as crop_border for deep images, a constant padding is scaled from 8 bit to the bit depth
+pub fn crop_border_raster(raster: &Raster,
+                          x: u32,
+                          y: u32,
+                          width: u32,
+                          height: u32,
+                          padding: Padding)
+                          -> Raster {
+    let (src_width, src_height) = raster.dimensions();
+    let constant = match padding {
+        Padding::Constant(value) => (value as u32 * raster.max_value() as u32 / 255) as u16,
+        _ => 0,
+    };
+    let mut crop = Raster::new(width, height, raster.channels(), raster.bits());
+    for j in 0..height {
+        for i in 0..width {
+            match (border_index(x + i, src_width, padding),
+                   border_index(y + j, src_height, padding)) {
+                (Some(src_x), Some(src_y)) => {
+                    crop.pixel_mut(i, j).copy_from_slice(raster.pixel(src_x, src_y))
+                }
+                _ => {
+                    for v in crop.pixel_mut(i, j) {
+                        *v = constant;
+                    }
+                }
+            }
+        }
+    }
+    crop
+}
+
+// Same as concentric_crop for deep images
+pub fn concentric_crop_raster(raster: &Raster,
+                              centre: (u32, u32),
+                              size: (u32, u32),
+                              scale: f32)
+                              -> Raster {
+    let region = (((size.0 as f32 / scale).round() as u32).max(1),
+                  ((size.1 as f32 / scale).round() as u32).max(1));
+    let x = centre.0 as i64 - (region.0 / 2) as i64;
+    let y = centre.1 as i64 - (region.1 / 2) as i64;
+
+    let crop = crop_padded_raster(raster, x, y, region.0, region.1);
+    resize_raster(&crop, size.0, size.1)
+}
+
+// First source sample and normalized weights of every output sample along an axis, the support
+// of the triangle grows with the reduction so every source sample contributes
+fn triangle_weights(src_len: u32, len: u32) -> Vec<(usize, Vec<f32>)> {
+    let ratio = src_len as f32 / len as f32;
+    let support = ratio.max(1.0);
+    (0..len)
+        .map(|i| {
+            let centre = (i as f32 + 0.5) * ratio;
+            let start = (centre - support).floor().max(0.0) as usize;
+            let end = ((centre + support).ceil() as usize).min(src_len as usize);
+            let mut weights = (start..end)
+                .map(|s| (1.0 - ((s as f32 + 0.5 - centre) / support).abs()).max(0.0))
+                .collect::<Vec<_>>();
+            let sum = weights.iter().fold(0.0, |sum, w| sum + w);
+            if sum > 0.0 {
+                for weight in &mut weights {
+                    *weight /= sum;
+                }
+            }
+            (start, weights)
+        })
+        .collect()
+}
+
+fn nearest<P: Pixel + 'static>(image: &ImageBuffer<P, Vec<P::Subpixel>>,
+                               width: u32,
+                               height: u32)
+                               -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let (src_width, src_height) = image.dimensions();
+    let mut buffer = ImageBuffer::new(width, height);
+
+    for y in 0..height {
+        let src_y = ((y as f32 + 0.5) * src_height as f32 / height as f32) as u32;
+        for x in 0..width {
+            let src_x = ((x as f32 + 0.5) * src_width as f32 / width as f32) as u32;
+            buffer.put_pixel(x,
+                             y,
+                             *image.get_pixel(src_x.min(src_width - 1), src_y.min(src_height - 1)));
+        }
+    }
+    buffer
+}
+
+fn crop_buffer<P: Pixel + 'static>(image: &ImageBuffer<P, Vec<P::Subpixel>>,
+                                   x: i64,
+                                   y: i64,
+                                   width: u32,
+                                   height: u32)
+                                   -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let (src_width, src_height) = image.dimensions();
+    let mut buffer = ImageBuffer::new(width, height);
+
+    for j in 0..height {
+        let src_y = y + j as i64;
+        if src_y < 0 || src_y >= src_height as i64 {
+            continue;
+        }
+        for i in 0..width {
+            let src_x = x + i as i64;
+            if src_x < 0 || src_x >= src_width as i64 {
+                continue;
+            }
+            buffer.put_pixel(i, j, *image.get_pixel(src_x as u32, src_y as u32));
+        }
+    }
+    buffer
+}
+
+fn border_buffer<P: Pixel<Subpixel = u8> + 'static>(image: &ImageBuffer<P, Vec<u8>>,
+                                                   x: u32,
+                                                   y: u32,
+                                                   width: u32,
+                                                   height: u32,
+                                                   padding: Padding)
+                                                   -> ImageBuffer<P, Vec<u8>> {
+    let (src_width, src_height) = image.dimensions();
+    let mut constant = *image.get_pixel(0, 0);
+    if let Padding::Constant(value) = padding {
+        constant.apply(|_| value);
+    }
+
+    ImageBuffer::from_fn(width, height, |i, j| {
+        match (border_index(x + i, src_width, padding), border_index(y + j, src_height, padding)) {
+            (Some(src_x), Some(src_y)) => *image.get_pixel(src_x, src_y),
+            _ => constant,
+        }
+    })
+}
+
+fn border_index(index: u32, len: u32, padding: Padding) -> Option<u32> {
+    if index < len {
+        return Some(index);
+    }
+    match padding {
+        Padding::Constant(_) => None,
+        Padding::Replicate => Some(len - 1),
+        Padding::Reflect => {
+            if len == 1 {
+                return Some(0);
+            }
+            let period = 2 * (len - 1);
+            let index = index % period;
+            Some(if index < len { index } else { period - index })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::*;
+
+    use img_reader::raster::Raster;
+    use super::{border_index, concentric_crop, crop_border, crop_padded, resample, resize_raster,
+                Interpolation, Padding};
+
+    // Every pixel holds its x coordinate
+    fn columns(width: u32, height: u32) -> DynamicImage {
+        let image = ImageBuffer::from_fn(width, height, |x, _| Luma { data: [x as u8] });
+        DynamicImage::ImageLuma8(image)
+    }
+
+    #[test]
+    fn rounds_scaled_dimensions() {
+        let image = columns(10, 7);
+        let nearest = || Interpolation::NearestNeighbor;
+        assert_eq!(resample(&image, 0.5, nearest()).dimensions(), (5, 4));
+        assert_eq!(resample(&image, 2.0, nearest()).dimensions(), (20, 14));
+        assert_eq!(resample(&image, 0.01, nearest()).dimensions(), (1, 1));
+    }
+
+    #[test]
+    fn nearest_neighbour_keeps_label_values() {
+        let mask = DynamicImage::ImageLuma8(ImageBuffer::from_fn(33, 21, |x, y| {
+            Luma { data: [[0, 128, 255][((x / 3 + y / 2) % 3) as usize]] }
+        }));
+        for &scale in &[0.5, 0.3, 1.7] {
+            let resampled = resample(&mask, scale, Interpolation::NearestNeighbor).to_luma();
+            assert!(resampled.pixels().all(|p| [0, 128, 255].contains(&p.data[0])));
+        }
+    }
+
+    #[test]
+    fn concentric_crop_covers_scaled_region() {
+        let image = columns(40, 40);
+        let crop = |scale: f32| {
+            concentric_crop(&image, (20, 20), (10, 10), scale, Interpolation::NearestNeighbor)
+        };
+        let native = crop(1.0);
+        assert_eq!(native.to_luma().get_pixel(0, 3).data[0], 15);
+        assert_eq!(native.to_luma().get_pixel(9, 3).data[0], 24);
+
+        // Twice the width around the same centre, every other column is kept
+        let context = crop(0.5);
+        let row = (0..10).map(|x| context.to_luma().get_pixel(x, 5).data[0]).collect::<Vec<_>>();
+        assert_eq!(row, (0..10).map(|x| 11 + 2 * x).collect::<Vec<u8>>());
+
+        // A zoom into the tile covers half of it
+        let zoomed = crop(2.0);
+        assert_eq!(zoomed.to_luma().get_pixel(0, 0).data[0], 18);
+        assert_eq!(zoomed.to_luma().get_pixel(9, 0).data[0], 22);
+    }
+
+    #[test]
+    fn pads_crops_with_black() {
+        let image = ImageBuffer::from_pixel(5, 5, Rgb { data: [9u8, 8, 7] });
+        let image = DynamicImage::ImageRgb8(image);
+        let crop = crop_padded(&image, -2, 3, 4, 4).to_rgb();
+        assert_eq!(crop.get_pixel(1, 0).data, [0, 0, 0]);
+        assert_eq!(crop.get_pixel(2, 0).data, [9, 8, 7]);
+        assert_eq!(crop.get_pixel(2, 2).data, [0, 0, 0]);
+    }
+
+    #[test]
+    fn resizes_rasters_within_their_values() {
+        let constant = Raster::from_raw(7, 5, 2, 16, vec![40000; 70]).unwrap();
+        let resized = resize_raster(&constant, 3, 9);
+        assert_eq!(resized.dimensions(), (3, 9));
+        assert!(resized.as_raw().iter().all(|&v| v == 40000));
+
+        let ramp = (0..64).map(|i| i * 1000).collect::<Vec<u16>>();
+        let resized = resize_raster(&Raster::from_raw(64, 1, 1, 16, ramp).unwrap(), 16, 1);
+        let values = resized.as_raw();
+        assert!(values.windows(2).all(|w| w[0] < w[1]), "{:?}", values);
+        assert!((values[8] as i32 - 34500).abs() <= 1000, "{:?}", values);
+    }
+
+    #[test]
+    fn fills_the_border() {
+        let index = |i, padding| border_index(i, 4, padding);
+        let filled = |padding| (2..10).map(|i| index(i, padding)).collect::<Vec<_>>();
+        assert_eq!(filled(Padding::Constant(7)),
+                   vec![Some(2), Some(3), None, None, None, None, None, None]);
+        assert_eq!(filled(Padding::Replicate),
+                   vec![Some(2), Some(3), Some(3), Some(3), Some(3), Some(3), Some(3), Some(3)]);
+        assert_eq!(filled(Padding::Reflect),
+                   vec![Some(2), Some(3), Some(2), Some(1), Some(0), Some(1), Some(2), Some(3)]);
+        assert_eq!(border_index(5, 1, Padding::Reflect), Some(0));
+
+        let image = columns(4, 2);
+        let row = |padding| {
+            let crop = crop_border(&image, 2, 1, 4, 2, padding).to_luma();
+            (0..4).map(|x| crop.get_pixel(x, 0).data[0]).collect::<Vec<_>>()
+        };
+        assert_eq!(row(Padding::Constant(7)), vec![2, 3, 7, 7]);
+        assert_eq!(row(Padding::Replicate), vec![2, 3, 3, 3]);
+        assert_eq!(row(Padding::Reflect), vec![2, 3, 2, 1]);
+        let crop = crop_border(&image, 2, 1, 4, 2, Padding::Reflect).to_luma();
+        assert_eq!(crop.get_pixel(0, 1).data[0], 2);
+    }
+}
@@ -1,5 +1,8 @@
+use std::path::PathBuf;
 use image::*;
 
+use ans::resample::Padding;
+
 pub struct ReturnType {
     layout: ImgLayout,
     format: ImgFormat,
@@ -24,4 +27,81 @@ pub enum ImgFormat {
         batch_size: usize,
     },
     Img(ImageFormat),
+    // tf.train.Example records with PNG encoded tiles, shard_size records per file
+    TfRecord {
+        shard_size: usize,
+    },
+    // Tar shards with a PNG tile, PNG mask and JSON metadata per sample, read by WebDataset
+    WebDataset(ShardSize),
+}
+
+// When a new tar shard is started
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ShardSize {
+    Samples(usize),
+    Bytes(u64),
+}
+
+// Directory structure below output_real and output_mask
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OutputLayout {
+    // Every tile directly in the output directory
+    Flat,
+    // One subdirectory per label as expected by ImageFolder style loaders, below a directory per
+    // partition if partitions are set
+    ImageFolder,
+}
+
+// What to do with tiles and manifest of an earlier run in the output directories
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputPolicy {
+    // Refuse to run if the output directories already contain files
+    Fail,
+    // Remove the old output directories before writing
+    Overwrite,
+    // Skip every source image finished in an earlier run of the current pass, whether it yielded
+    // tiles or not. Manifest entries of an interrupted source image are dropped and it is cut
+    // again, TFRecord and tar shards are cut back to the last finished source image.
+    Resume,
+}
+
+// What to do with tiles reaching over the right or bottom border of an image
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BorderPolicy {
+    // Leave out the strips not covered by a whole tile
+    Drop,
+    // Align the last tile with the border, overlapping its neighbour
+    Shift,
+    // Keep tiles reaching over the border and fill the missing pixels, masks are always padded
+    // with 0
+    Pad(Padding),
+}
+
+// Whether split and oversample write tiles or only plan them
+#[derive(Clone, PartialEq)]
+pub enum RunMode {
+    Write,
+    // Runs tiling and labelling, but only writes manifest and statistics into the directory
+    DryRun(PathBuf),
+    // Like DryRun, additionally renders up to n sample tiles per label into a contact sheet
+    ContactSheet(PathBuf, u32),
+}
+
+// Which tiles are cut from a source image
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Pass {
+    // A regular grid over every scale
+    Split,
+    // Tiles at randomly sampled label pixels, the sample size is the factor times the number of
+    // label pixels
+    Oversample(f32),
+}
+
+impl Pass {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Pass::Split => "split",
+            Pass::Oversample(_) => "oversample",
+        }
+    }
 }
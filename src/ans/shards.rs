@@ -0,0 +1,83 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use error::{AnsError, AnsResult};
+
+// Where a shard writer stood after a finished source image, recorded in the list of finished
+// sources so a resumed run can cut off the tiles of an interrupted image
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ShardPosition {
+    pub shard: u32,
+    // Records and bytes in the shard
+    pub records: usize,
+    pub bytes: u64,
+}
+
+impl ShardPosition {
+    pub fn start() -> ShardPosition {
+        ShardPosition {
+            shard: 0,
+            records: 0,
+            bytes: 0,
+        }
+    }
+}
+
+pub fn shard_path(dir: &Path, pass: &str, shard: u32, extension: &str) -> PathBuf {
+    dir.join(format!("{}-{:05}.{}", pass, shard, extension))
+}
+
+// Numbers of the <pass>-<shard>.<extension> files in dir
+pub fn shard_numbers(dir: &Path, pass: &str, extension: &str) -> Vec<u32> {
+    let prefix = format!("{}-", pass);
+    let suffix = format!(".{}", extension);
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries.filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    if name.starts_with(&prefix) && name.ends_with(&suffix) &&
+                       name.len() >= prefix.len() + suffix.len() {
+                        name[prefix.len()..name.len() - suffix.len()].parse::<u32>().ok()
+                    } else {
+                        None
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_else(|_| vec![])
+}
+
+// Removes the shards after position and cuts the shard of position back to its bytes. Returns
+// that shard opened at its end, None if it holds no records yet.
+pub fn reopen(dir: &Path,
+              pass: &str,
+              extension: &str,
+              position: ShardPosition)
+              -> AnsResult<Option<File>> {
+    for shard in shard_numbers(dir, pass, extension) {
+        if shard > position.shard || (shard == position.shard && position.records == 0) {
+            try!(fs::remove_file(shard_path(dir, pass, shard, extension)));
+        }
+    }
+    if position.records == 0 {
+        return Ok(None);
+    }
+
+    let path = shard_path(dir, pass, position.shard, extension);
+    let mut file = match OpenOptions::new().write(true).open(&path) {
+        Ok(file) => file,
+        Err(_) => {
+            return Err(AnsError::Manifest(format!("Shard {:?} of the finished source images is \
+                                                   missing",
+                                                  path)))
+        }
+    };
+    if try!(file.metadata()).len() < position.bytes {
+        return Err(AnsError::Manifest(format!("Shard {:?} is shorter than recorded", path)));
+    }
+    try!(file.set_len(position.bytes));
+    try!(file.seek(SeekFrom::End(0)));
+    Ok(Some(file))
+}
@@ -1,5 +1,6 @@
 use image::DynamicImage;
 use ans::label::Label;
+use img_reader::raster::Raster;
 use std::mem;
 use rand::*;
 
@@ -7,12 +8,27 @@ use rand::*;
 pub struct SplitImage {
     source: String,
     pub real: Option<DynamicImage>,
+    // All samples of tiles of 16 bit or multichannel sources, real is their 8 bit view then
+    pub deep: Option<Raster>,
     pub mask: Option<DynamicImage>,
     pub label: Option<Label>,
     dimension: (u32, u32),
     pub rotation: u8,
     x_offset: u32,
     y_offset: u32,
+    // Resampling factor of the source image, offsets always refer to the unscaled source
+    scale: f32,
+    // Context tiles share the centre of the native tile they were cut around
+    context: bool,
+    // Augmentations applied to this tile, in the order they were applied
+    augmentations: Vec<String>,
+    // Fraction of mask pixels matching the label color and of background pixels
+    mask_ratio: Option<f32>,
+    background_ratio: Option<f32>,
+    // Padded pixels at the right and bottom border of the unrotated tile
+    padding: (u32, u32),
+    // Width and height of the source image, of the cut pyramid level for slides
+    source_size: (u32, u32),
 }
 
 impl SplitImage {
@@ -28,12 +44,20 @@ impl SplitImage {
         SplitImage {
             source: src.clone(),
             real: Some(real),
+            deep: None,
             mask: Some(mask),
             label: Some(label),
             dimension: dim,
             rotation: rot,
             x_offset: x,
             y_offset: y,
+            scale: 1.0,
+            context: false,
+            augmentations: vec![],
+            mask_ratio: None,
+            background_ratio: None,
+            padding: (0, 0),
+            source_size: (0, 0),
         }
     }
 
@@ -41,12 +65,20 @@ impl SplitImage {
         SplitImage {
             source: src.clone(),
             real: None,
+            deep: None,
             mask: None,
             label: None,
             dimension: (x_dim, y_dim),
             rotation: rot,
             x_offset: x,
             y_offset: y,
+            scale: 1.0,
+            context: false,
+            augmentations: vec![],
+            mask_ratio: None,
+            background_ratio: None,
+            padding: (0, 0),
+            source_size: (0, 0),
         }
     }
 
@@ -77,6 +109,62 @@ impl SplitImage {
         self.y_offset
     }
 
+    pub fn get_scale(&self) -> f32 {
+        self.scale
+    }
+
+    pub fn set_scale(&mut self, scale: f32) {
+        self.scale = scale;
+    }
+
+    pub fn is_context(&self) -> bool {
+        self.context
+    }
+
+    pub fn set_context(&mut self, context: bool) {
+        self.context = context;
+    }
+
+    pub fn get_augmentations(&self) -> &Vec<String> {
+        &self.augmentations
+    }
+
+    pub fn add_augmentation(&mut self, augmentation: String) {
+        self.augmentations.push(augmentation);
+    }
+
+    pub fn get_mask_ratio(&self) -> Option<f32> {
+        self.mask_ratio
+    }
+
+    pub fn set_mask_ratio(&mut self, ratio: Option<f32>) {
+        self.mask_ratio = ratio;
+    }
+
+    pub fn get_background_ratio(&self) -> Option<f32> {
+        self.background_ratio
+    }
+
+    pub fn set_background_ratio(&mut self, ratio: Option<f32>) {
+        self.background_ratio = ratio;
+    }
+
+    pub fn get_padding(&self) -> (u32, u32) {
+        self.padding
+    }
+
+    pub fn set_padding(&mut self, padding: (u32, u32)) {
+        self.padding = padding;
+    }
+
+    pub fn get_source_size(&self) -> (u32, u32) {
+        self.source_size
+    }
+
+    pub fn set_source_size(&mut self, size: (u32, u32)) {
+        self.source_size = size;
+    }
+
     pub fn get_real(&self) -> &Option<DynamicImage> {
         &self.real
     }
@@ -126,6 +214,13 @@ impl SplitImage {
                     self.set_real(real_);
                     self.set_mask(mask_);
                 }
+                self.deep = self.deep.take().map(|deep| {
+                    match self.rotation {
+                        1 => deep.rotate90(),
+                        2 => deep.rotate180(),
+                        _ => deep.rotate270(),
+                    }
+                });
                 Some(self)
             } else {
                 None
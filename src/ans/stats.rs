@@ -0,0 +1,309 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{File, DirBuilder};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use ans::manifest::json_string;
+use ans::split_image::SplitImage;
+use img_reader::json::Json;
+use error::{AnsError, AnsResult};
+
+#[derive(Clone, Debug, Default)]
+pub struct ImageStats {
+    // Tiles cut from the source image, for every scale
+    pub considered: u32,
+    // Tiles above the background threshold
+    pub background: u32,
+    // Tiles for which the FindLabel returned None
+    pub rejected: u32,
+    // Tiles emitted per label, including context, zoomed and augmented copies
+    pub written: BTreeMap<String, u32>,
+}
+
+impl ImageStats {
+    pub fn add(&mut self, other: &ImageStats) {
+        self.considered += other.considered;
+        self.background += other.background;
+        self.rejected += other.rejected;
+        for (label, cnt) in &other.written {
+            *self.written.entry(label.clone()).or_insert(0) += *cnt;
+        }
+    }
+
+    pub fn total_written(&self) -> u32 {
+        self.written.values().sum()
+    }
+
+    fn from_json(json: &Json) -> Option<ImageStats> {
+        let count = |key: &str| json.get(key).and_then(|v| v.as_f64()).map(|v| v as u32);
+        let mut stats = ImageStats::default();
+        match (count("considered"), count("background"), count("rejected")) {
+            (Some(considered), Some(background), Some(rejected)) => {
+                stats.considered = considered;
+                stats.background = background;
+                stats.rejected = rejected;
+            }
+            _ => return None,
+        }
+        if let Some(&Json::Object(ref labels)) = json.get("written") {
+            for &(ref label, ref cnt) in labels {
+                match cnt.as_f64() {
+                    Some(cnt) => stats.written.insert(label.clone(), cnt as u32),
+                    None => return None,
+                };
+            }
+        }
+        Some(stats)
+    }
+
+    fn to_json(&self) -> String {
+        let written = self.written
+            .iter()
+            .map(|(label, cnt)| format!("{}:{}", json_string(label), cnt))
+            .collect::<Vec<_>>()
+            .join(",");
+        format!("{{\"considered\":{},\"background\":{},\"rejected\":{},\"written\":{{{}}}}}",
+                self.considered,
+                self.background,
+                self.rejected,
+                written)
+    }
+}
+
+// Statistics of a single split or oversample pass, only covers the source images processed in
+// this run unless the statistics of an earlier run are added with extend
+pub struct Stats {
+    pass: String,
+    images: BTreeMap<String, ImageStats>,
+}
+
+impl Stats {
+    pub fn new(pass: &str) -> Stats {
+        Stats {
+            pass: String::from(pass),
+            images: BTreeMap::new(),
+        }
+    }
+
+    pub fn get_pass(&self) -> &str {
+        &self.pass
+    }
+
+    pub fn get_images(&self) -> &BTreeMap<String, ImageStats> {
+        &self.images
+    }
+
+    pub fn image(&mut self, name: &str) -> &mut ImageStats {
+        self.images.entry(String::from(name)).or_insert_with(ImageStats::default)
+    }
+
+    // Counts a tile handed to the TileVisitor under its label
+    pub fn add_written(&mut self, tile: &SplitImage) {
+        let label = tile.label.as_ref().map_or("None", |l| l.name());
+        *self.image(tile.get_name()).written.entry(String::from(label)).or_insert(0) += 1;
+    }
+
+    // Adds the statistics of the source images of other
+    pub fn extend(&mut self, other: &Stats) {
+        for (name, image) in &other.images {
+            self.image(name).add(image);
+        }
+    }
+
+    // Drops the statistics of the source images keep returns false for
+    pub fn retain<F>(&mut self, keep: F)
+        where F: Fn(&str) -> bool
+    {
+        let names = self.images.keys().filter(|name| !keep(name)).cloned().collect::<Vec<_>>();
+        for name in names {
+            self.images.remove(&name);
+        }
+    }
+
+    pub fn total(&self) -> ImageStats {
+        let mut total = ImageStats::default();
+        for image in self.images.values() {
+            total.add(image);
+        }
+        total
+    }
+
+    fn labels(&self) -> Vec<String> {
+        let mut labels = self.total().written.keys().cloned().collect::<Vec<_>>();
+        for label in ["Healthy", "Fuzzy", "Sick"].iter() {
+            if !labels.iter().any(|l| l == label) {
+                labels.push(String::from(*label));
+            }
+        }
+        labels.sort();
+        labels
+    }
+
+    pub fn to_json(&self) -> String {
+        let images = self.images
+            .iter()
+            .map(|(name, image)| format!("{}:{}", json_string(name), image.to_json()))
+            .collect::<Vec<_>>()
+            .join(",");
+        format!("{{\"pass\":{},\"images\":{{{}}},\"total\":{}}}",
+                json_string(&self.pass),
+                images,
+                self.total().to_json())
+    }
+
+    // Reads stats_<pass>.json from dir, None if there is none
+    pub fn read(dir: &Path, pass: &str) -> AnsResult<Option<Stats>> {
+        let path = dir.join(Stats::file(pass));
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut content = String::new();
+        try!(try!(File::open(&path)).read_to_string(&mut content));
+        let malformed = |msg: &str| AnsError::Manifest(format!("Malformed {:?}: {}", path, msg));
+
+        let json = try!(Json::parse(&content).map_err(|e| malformed(&e)));
+        let mut stats = Stats::new(pass);
+        if let Some(&Json::Object(ref images)) = json.get("images") {
+            for &(ref name, ref image) in images {
+                let image = try!(ImageStats::from_json(image)
+                    .ok_or_else(|| malformed(&format!("statistics of {}", name))));
+                stats.images.insert(name.clone(), image);
+            }
+        }
+        Ok(Some(stats))
+    }
+
+    fn file(pass: &str) -> String {
+        format!("stats_{}.json", pass)
+    }
+
+    // Writes stats_<pass>.json into dir and returns its path
+    pub fn write(&self, dir: &Path) -> AnsResult<PathBuf> {
+        try!(DirBuilder::new().recursive(true).create(dir));
+        let path = dir.join(Stats::file(&self.pass));
+        let mut file = try!(File::create(&path));
+        try!(file.write_all(self.to_json().as_bytes()));
+        try!(file.write_all(b"\n"));
+        Ok(path)
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let labels = self.labels();
+        let width = self.images.keys().map(|n| n.len()).max().unwrap_or(0).max(self.pass.len());
+
+        try!(write!(f,
+                    "{:<w$} {:>10} {:>10} {:>10}",
+                    self.pass,
+                    "considered",
+                    "background",
+                    "rejected",
+                    w = width));
+        for label in &labels {
+            try!(write!(f, " {:>10}", label));
+        }
+        try!(writeln!(f, ""));
+
+        let total = self.total();
+        let rows = self.images
+            .iter()
+            .map(|(name, image)| (&name[..], image))
+            .chain(Some(("total", &total)));
+        for (name, image) in rows {
+            try!(write!(f,
+                        "{:<w$} {:>10} {:>10} {:>10}",
+                        name,
+                        image.considered,
+                        image.background,
+                        image.rejected,
+                        w = width));
+            for label in &labels {
+                try!(write!(f, " {:>10}", image.written.get(label).cloned().unwrap_or(0)));
+            }
+            try!(writeln!(f, ""));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use super::{ImageStats, Stats};
+
+    fn stats() -> Stats {
+        let mut stats = Stats::new("split");
+        {
+            let a = stats.image("a.png");
+            a.considered = 10;
+            a.background = 3;
+            a.rejected = 2;
+            a.written.insert(String::from("Sick"), 4);
+            a.written.insert(String::from("Healthy"), 1);
+        }
+        stats.image("b \"2\".png").considered = 5;
+        stats.image("b \"2\".png").written.insert(String::from("Healthy"), 5);
+        stats
+    }
+
+    fn counts(image: &ImageStats) -> (u32, u32, u32, Vec<(String, u32)>) {
+        (image.considered,
+         image.background,
+         image.rejected,
+         image.written.iter().map(|(l, c)| (l.clone(), *c)).collect())
+    }
+
+    #[test]
+    fn totals_every_image() {
+        let total = stats().total();
+        assert_eq!(counts(&total),
+                   (15, 3, 2, vec![(String::from("Healthy"), 6), (String::from("Sick"), 4)]));
+        assert_eq!(total.total_written(), 10);
+    }
+
+    #[test]
+    fn prints_a_table() {
+        let table = format!("{}", stats());
+        let lines = table.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0].split_whitespace().collect::<Vec<_>>(),
+                   vec!["split", "considered", "background", "rejected", "Fuzzy", "Healthy",
+                        "Sick"]);
+        assert_eq!(lines[3].split_whitespace().collect::<Vec<_>>(),
+                   vec!["total", "15", "3", "2", "0", "6", "4"]);
+        assert!(lines.iter().all(|l| l.len() == lines[0].len()));
+    }
+
+    #[test]
+    fn writes_and_reads_json() {
+        let dir = env::temp_dir().join("ans_stats_json");
+        let _ = fs::remove_dir_all(&dir);
+        assert!(Stats::read(&dir, "split").unwrap().is_none());
+
+        let stats = stats();
+        let path = stats.write(&dir).unwrap();
+        assert_eq!(path, dir.join("stats_split.json"));
+        let read = Stats::read(&dir, "split").unwrap().unwrap();
+        assert_eq!(read.to_json(), stats.to_json());
+        assert_eq!(counts(&read.get_images()["b \"2\".png"]),
+                   (5, 0, 0, vec![(String::from("Healthy"), 5)]));
+    }
+
+    #[test]
+    fn extends_the_statistics_of_an_earlier_run() {
+        let mut earlier = stats();
+        earlier.retain(|name| name == "a.png");
+        assert_eq!(earlier.get_images().keys().collect::<Vec<_>>(), vec!["a.png"]);
+
+        let mut all = Stats::new("split");
+        all.extend(&earlier);
+        all.extend(&stats());
+        assert_eq!(counts(&all.get_images()["a.png"]),
+                   (20, 6, 4, vec![(String::from("Healthy"), 2), (String::from("Sick"), 8)]));
+        assert_eq!(all.total().considered, 25);
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::BTreeMap;
+use std::fs::DirBuilder;
+use std::path::{Path, PathBuf};
+
+use image::{self, FilterType, GenericImage, ImageBuffer, Luma};
+
+use ans::manifest::{self, ManifestEntry};
+use error::{AnsError, AnsResult};
+
+// How overlapping tile predictions are combined
+#[derive(Clone, Copy)]
+pub enum Blend {
+    Average,
+    Max,
+    // Weighted by a Gaussian window centred on every tile, sigma relative to the tile size
+    Gaussian(f32),
+}
+
+// Reassembles per-tile predictions into full images, the inverse of AugmentSplit::split
+pub struct Stitcher {
+    blend: Blend,
+    // Only tiles cut at this scale are used, their predictions are resized to native size
+    scale: f32,
+}
+
+impl Stitcher {
+    pub fn new(blend: Blend) -> Stitcher {
+        Stitcher {
+            blend: blend,
+            scale: 1.0,
+        }
+    }
+
+    pub fn set_scale(mut self, scale: f32) -> Stitcher {
+        self.scale = scale;
+        self
+    }
+
+    // Reads the tiles of the split pass from the manifest and looks up a prediction with the file
+    // name of every real tile in predictions. Writes one image per source into output and returns
+    // their paths. The stitched images have the size of their source image, for manifests without
+    // the source size the extent of the tiles.
+    pub fn stitch(&self,
+                  manifest_path: &Path,
+                  predictions: &Path,
+                  output: &Path)
+                  -> AnsResult<Vec<PathBuf>> {
+        let mut sources: BTreeMap<String, Vec<ManifestEntry>> = BTreeMap::new();
+        for entry in try!(manifest::read_csv(manifest_path)) {
+            if entry.pass == "split" && !entry.context && entry.rotation == 0 &&
+               entry.augmentations.is_empty() && entry.scale == self.scale {
+                sources.entry(entry.source.clone()).or_insert_with(Vec::new).push(entry);
+            }
+        }
+
+        try!(DirBuilder::new().recursive(true).create(output));
+        let mut written = vec![];
+        for (source, entries) in sources {
+            let image = try!(self.stitch_source(&entries, predictions));
+            let stem = Path::new(&source)
+                .file_stem()
+                .map_or(source.clone(), |s| s.to_string_lossy().into_owned());
+            let path = output.join(format!("{}.png", stem));
+            try!(image.save(&path));
+            written.push(path);
+        }
+        Ok(written)
+    }
+
+    fn stitch_source(&self,
+                     entries: &[ManifestEntry],
+                     predictions: &Path)
+                     -> AnsResult<ImageBuffer<Luma<u8>, Vec<u8>>> {
+        let native = |v: u32| (v as f32 / self.scale).round() as u32;
+        // Padding at the border is left out, so it doesn't extend the image
+        let valid = |e: &ManifestEntry| {
+            (native(e.width - e.pad_right), native(e.height - e.pad_bottom))
+        };
+        let (width, height) = match entries.iter().filter_map(|e| e.source_size).next() {
+            Some(size) => size,
+            None => {
+                (entries.iter().map(|e| e.x_offset + valid(e).0).max().unwrap_or(0),
+                 entries.iter().map(|e| e.y_offset + valid(e).1).max().unwrap_or(0))
+            }
+        };
+
+        let mut values = vec![0.0f32; (width * height) as usize];
+        let mut weights = vec![0.0f32; (width * height) as usize];
+
+        for entry in entries {
+            let file_name = match entry.real_path.as_ref().and_then(|p| Path::new(p).file_name()) {
+                Some(file_name) => file_name.to_os_string(),
+                None => {
+                    return Err(AnsError::Manifest(format!("No real tile for {} at {}, {}",
+                                                          entry.source,
+                                                          entry.x_offset,
+                                                          entry.y_offset)))
+                }
+            };
+            let path = predictions.join(file_name);
+            let prediction = match image::open(&path) {
+                Ok(prediction) => prediction,
+                Err(e) => return Err(AnsError::ImageRead(path, e)),
+            };
+            let (w, h) = (native(entry.width), native(entry.height));
+            let prediction = if prediction.dimensions() != (w, h) {
+                prediction.resize_exact(w, h, FilterType::Triangle).to_luma()
+            } else {
+                prediction.to_luma()
+            };
+
+            let (valid_w, valid_h) = valid(entry);
+            let pixels = prediction.enumerate_pixels().filter(|p| {
+                p.0 < valid_w && p.1 < valid_h && entry.x_offset + p.0 < width &&
+                entry.y_offset + p.1 < height
+            });
+            for (x, y, pixel) in pixels {
+                let i = ((entry.y_offset + y) * width + entry.x_offset + x) as usize;
+                let value = pixel.data[0] as f32;
+                match self.blend {
+                    Blend::Average => {
+                        values[i] += value;
+                        weights[i] += 1.0;
+                    }
+                    Blend::Max => {
+                        values[i] = values[i].max(value);
+                        weights[i] = 1.0;
+                    }
+                    Blend::Gaussian(sigma) => {
+                        let weight = gaussian_weight(x, y, w, h, sigma);
+                        values[i] += value * weight;
+                        weights[i] += weight;
+                    }
+                }
+            }
+        }
+
+        Ok(ImageBuffer::from_fn(width, height, |x, y| {
+            let i = (y * width + x) as usize;
+            let value = if weights[i] > 0.0 {
+                values[i] / weights[i]
+            } else {
+                0.0
+            };
+            Luma { data: [value.round().max(0.0).min(255.0) as u8] }
+        }))
+    }
+}
+
+fn gaussian_weight(x: u32, y: u32, w: u32, h: u32, sigma: f32) -> f32 {
+    let dx = (x as f32 + 0.5 - w as f32 / 2.0) / (sigma * w as f32).max(1e-3);
+    let dy = (y as f32 + 0.5 - h as f32 / 2.0) / (sigma * h as f32).max(1e-3);
+    // Never fully zero, so pixels covered only by tile borders still get a value
+    (-(dx * dx + dy * dy) / 2.0).exp().max(1e-6)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    use image::{DynamicImage, GrayImage, ImageBuffer, Luma};
+
+    use ans::manifest::ManifestEntry;
+    use super::{Blend, Stitcher};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("ans_stitch_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // 24 bit BMP, the PNG decoder of image fails the debug assertions of the standard library
+    fn save_bmp(image: &GrayImage, path: &Path) {
+        let (width, height) = image.dimensions();
+        let row = (width * 3 + 3) / 4 * 4;
+        let mut bmp = vec![];
+        bmp.extend_from_slice(b"BM");
+        for v in &[54 + row * height, 0, 54, 40, width, height] {
+            bmp.extend_from_slice(&[*v as u8, (*v >> 8) as u8, (*v >> 16) as u8, (*v >> 24) as u8]);
+        }
+        bmp.extend_from_slice(&[1, 0, 24, 0]);
+        bmp.extend_from_slice(&[0; 24]);
+        for y in (0..height).rev() {
+            for x in 0..width {
+                let v = image.get_pixel(x, y).data[0];
+                bmp.extend_from_slice(&[v, v, v]);
+            }
+            bmp.extend(vec![0; (row - width * 3) as usize]);
+        }
+        File::create(path).unwrap().write_all(&bmp).unwrap();
+    }
+
+    fn entry(x: u32, y: u32, size: u32, padding: (u32, u32)) -> ManifestEntry {
+        ManifestEntry {
+            source: String::from("source.png"),
+            pass: String::from("split"),
+            x_offset: x,
+            y_offset: y,
+            width: size,
+            height: size,
+            pad_right: padding.0,
+            pad_bottom: padding.1,
+            scale: 1.0,
+            context: false,
+            rotation: 0,
+            augmentations: vec![],
+            label: Some(String::from("Sick")),
+            mask_ratio: None,
+            background_ratio: None,
+            partition: None,
+            real_path: Some(format!("Sick/{}_{}.bmp", x, y)),
+            mask_path: None,
+            source_size: Some((40, 30)),
+        }
+    }
+
+    // Cuts image into 16 pixel tiles every 12 pixels like split, tiles at the border are padded
+    fn cut(image: &GrayImage, predictions: &Path) -> Vec<ManifestEntry> {
+        let mut entries = vec![];
+        for y in (0..30u32).filter(|y| y % 12 == 0) {
+            for x in (0..40u32).filter(|x| x % 12 == 0) {
+                let padding = ((x + 16).saturating_sub(40), (y + 16).saturating_sub(30));
+                let tile = ImageBuffer::from_fn(16, 16, |i, j| {
+                    if x + i < 40 && y + j < 30 {
+                        *image.get_pixel(x + i, y + j)
+                    } else {
+                        Luma { data: [0] }
+                    }
+                });
+                save_bmp(&tile, &predictions.join(format!("{}_{}.bmp", x, y)));
+                entries.push(entry(x, y, 16, padding));
+            }
+        }
+        entries
+    }
+
+    fn source() -> GrayImage {
+        ImageBuffer::from_fn(40, 30, |x, y| Luma { data: [(x * 5 + y * 3) as u8] })
+    }
+
+    #[test]
+    fn reassembles_what_split_cut() {
+        let dir = temp_dir("reassemble");
+        let source = source();
+        let entries = cut(&source, &dir);
+        // The gray values read back from the BMP files
+        let expected = DynamicImage::ImageRgb8(DynamicImage::ImageLuma8(source).to_rgb()).to_luma();
+        for blend in &[Blend::Average, Blend::Max, Blend::Gaussian(0.25)] {
+            let stitched = Stitcher::new(*blend).stitch_source(&entries, &dir).unwrap();
+            assert_eq!(stitched.dimensions(), (40, 30));
+            assert_eq!(stitched.into_raw(), expected.clone().into_raw());
+        }
+    }
+
+    #[test]
+    fn blends_overlapping_predictions() {
+        let dir = temp_dir("blend");
+        save_bmp(&ImageBuffer::from_pixel(16, 16, Luma { data: [100] }), &dir.join("0_0.bmp"));
+        save_bmp(&ImageBuffer::from_pixel(16, 16, Luma { data: [200] }), &dir.join("12_0.bmp"));
+        let mut entries = vec![entry(0, 0, 16, (0, 0)), entry(12, 0, 16, (0, 0))];
+        for entry in &mut entries {
+            entry.source_size = None;
+        }
+
+        let stitch = |blend| Stitcher::new(blend).stitch_source(&entries, &dir).unwrap();
+        let average = stitch(Blend::Average);
+        assert_eq!(average.dimensions(), (28, 16));
+        let row = |image: &GrayImage| {
+            [0, 13, 27].iter().map(|&x| image.get_pixel(x, 8).data[0]).collect::<Vec<_>>()
+        };
+        assert_eq!(row(&average), vec![100, 150, 200]);
+        assert_eq!(row(&stitch(Blend::Max)), vec![100, 200, 200]);
+        // Across the overlap the weight moves from the first to the second tile
+        let gaussian = stitch(Blend::Gaussian(0.25));
+        let overlap = (12..16).map(|x| gaussian.get_pixel(x, 8).data[0]).collect::<Vec<_>>();
+        assert!(overlap[0] > 100 && overlap[1] < 150 && overlap[2] > 150 && overlap[3] < 200);
+        assert!(overlap.windows(2).all(|w| w[0] < w[1]));
+    }
+}
@@ -0,0 +1,330 @@
+use std::fs::{DirBuilder, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use ans::shards::{self, ShardPosition};
+use error::AnsResult;
+
+// tf.train.Example, encoded by hand so no TensorFlow or protobuf install is needed
+pub struct Example {
+    // Encoded entries of the map<string, Feature> of the Features message
+    features: Vec<u8>,
+}
+
+impl Example {
+    pub fn new() -> Example {
+        Example { features: vec![] }
+    }
+
+    pub fn bytes(&mut self, key: &str, value: &[u8]) {
+        // BytesList
+        let mut list = vec![];
+        field(&mut list, 1, value);
+        self.feature(key, 1, &list);
+    }
+
+    pub fn int64(&mut self, key: &str, value: i64) {
+        // Int64List, packed
+        let mut values = vec![];
+        varint(&mut values, value as u64);
+        let mut list = vec![];
+        field(&mut list, 1, &values);
+        self.feature(key, 3, &list);
+    }
+
+    pub fn float(&mut self, key: &str, value: f32) {
+        // FloatList, packed
+        let bits = value.to_bits();
+        let values = [bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8];
+        let mut list = vec![];
+        field(&mut list, 1, &values);
+        self.feature(key, 2, &list);
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut example = vec![];
+        field(&mut example, 1, &self.features);
+        example
+    }
+
+    // kind is the field number of the list inside the oneof of the Feature message
+    fn feature(&mut self, key: &str, kind: u32, list: &[u8]) {
+        let mut feature = vec![];
+        field(&mut feature, kind, list);
+        let mut entry = vec![];
+        field(&mut entry, 1, key.as_bytes());
+        field(&mut entry, 2, &feature);
+        field(&mut self.features, 1, &entry);
+    }
+}
+
+// Length-delimited protobuf field
+fn field(buffer: &mut Vec<u8>, number: u32, value: &[u8]) {
+    varint(buffer, (number << 3 | 2) as u64);
+    varint(buffer, value.len() as u64);
+    buffer.extend_from_slice(value);
+}
+
+fn varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+// Appends records to <pass>-<shard>.tfrecord files in dir, a new shard is started every
+// shard_size records
+pub struct TfRecordWriter {
+    dir: PathBuf,
+    pass: String,
+    shard_size: usize,
+    shard: u32,
+    // Records and bytes in the current shard
+    records: usize,
+    bytes: u64,
+    file: Option<BufWriter<File>>,
+    // Without writing only the shard a record would end up in is returned, for dry runs
+    write: bool,
+    crc_table: [u32; 256],
+}
+
+impl TfRecordWriter {
+    // Shards of earlier runs are kept, numbering continues after the last one
+    pub fn new(dir: PathBuf, pass: &str, shard_size: usize, write: bool) -> TfRecordWriter {
+        let shard = shards::shard_numbers(&dir, pass, "tfrecord")
+            .into_iter()
+            .map(|shard| shard + 1)
+            .max()
+            .unwrap_or(0);
+
+        TfRecordWriter {
+            dir: dir,
+            pass: String::from(pass),
+            shard_size: shard_size,
+            shard: shard,
+            records: 0,
+            bytes: 0,
+            file: None,
+            write: write,
+            crc_table: crc32c_table(),
+        }
+    }
+
+    // Continues writing at position, records written after it are dropped
+    pub fn resume(&mut self, position: ShardPosition) -> AnsResult<()> {
+        self.file = None;
+        if self.write {
+            self.file = try!(shards::reopen(&self.dir, &self.pass, "tfrecord", position))
+                .map(BufWriter::new);
+        }
+        self.shard = position.shard;
+        self.records = position.records;
+        self.bytes = position.bytes;
+        Ok(())
+    }
+
+    // Position after the records written so far
+    pub fn position(&self) -> ShardPosition {
+        ShardPosition {
+            shard: self.shard,
+            records: self.records,
+            bytes: self.bytes,
+        }
+    }
+
+    fn shard_path(&self) -> PathBuf {
+        shards::shard_path(&self.dir, &self.pass, self.shard, "tfrecord")
+    }
+
+    // Returns the path of the shard the record was written to
+    pub fn write(&mut self, record: &[u8]) -> AnsResult<PathBuf> {
+        if self.records == self.shard_size {
+            try!(self.flush());
+            self.file = None;
+            self.shard += 1;
+            self.records = 0;
+            self.bytes = 0;
+        }
+        let path = self.shard_path();
+        self.records += 1;
+        self.bytes += 16 + record.len() as u64;
+        if !self.write {
+            return Ok(path);
+        }
+
+        if self.file.is_none() {
+            try!(DirBuilder::new().recursive(true).create(&self.dir));
+            self.file = Some(BufWriter::new(try!(File::create(&path))));
+        }
+        let length = le_bytes(record.len() as u64, 8);
+        let length_crc = le_bytes(self.masked_crc(&length) as u64, 4);
+        let data_crc = le_bytes(self.masked_crc(record) as u64, 4);
+        if let Some(ref mut file) = self.file {
+            try!(file.write_all(&length));
+            try!(file.write_all(&length_crc));
+            try!(file.write_all(record));
+            try!(file.write_all(&data_crc));
+        }
+        Ok(path)
+    }
+
+    pub fn flush(&mut self) -> AnsResult<()> {
+        if let Some(ref mut file) = self.file {
+            try!(file.flush());
+        }
+        Ok(())
+    }
+
+    fn crc32c(&self, data: &[u8]) -> u32 {
+        !data.iter().fold(!0u32, |crc, &b| {
+            self.crc_table[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8)
+        })
+    }
+
+    // CRC32C of the TFRecord format, rotated and offset
+    fn masked_crc(&self, data: &[u8]) -> u32 {
+        let crc = self.crc32c(data);
+        ((crc >> 15) | (crc << 17)).wrapping_add(0xa282ead8)
+    }
+}
+
+fn le_bytes(value: u64, len: usize) -> Vec<u8> {
+    (0..len).map(|i| (value >> (8 * i)) as u8).collect()
+}
+
+// Castagnoli polynomial, reflected
+fn crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        let mut crc = i as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f63b78
+            } else {
+                crc >> 1
+            };
+        }
+        *entry = crc;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::path::PathBuf;
+
+    use super::{Example, TfRecordWriter};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("ans_tfrecord_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn read(path: &PathBuf) -> Vec<u8> {
+        let mut content = vec![];
+        File::open(path).unwrap().read_to_end(&mut content).unwrap();
+        content
+    }
+
+    // Example { features { feature { key: "a" value { int64_list { value: 1 } } } } }
+    const EXAMPLE: [u8; 14] = [0x0a, 0x0c, 0x0a, 0x0a, 0x0a, 0x01, 0x61, 0x12, 0x05, 0x1a, 0x03,
+                               0x0a, 0x01, 0x01];
+
+    #[test]
+    fn crc32c_check_value() {
+        let writer = TfRecordWriter::new(temp_dir("crc"), "split", 1, false);
+        assert_eq!(writer.crc32c(b"123456789"), 0xe3069283);
+        assert_eq!(writer.masked_crc(b""), 0xa282ead8);
+        assert_eq!(writer.masked_crc(b"123456789"), 0xc78ab0e5);
+    }
+
+    #[test]
+    fn encodes_example() {
+        let mut example = Example::new();
+        example.int64("a", 1);
+        assert_eq!(example.encode(), EXAMPLE.to_vec());
+
+        let mut example = Example::new();
+        example.bytes("b", b"xy");
+        example.float("c", 1.0);
+        assert_eq!(example.encode(),
+                   vec![0x0a, 0x1c, 0x0a, 0x0b, 0x0a, 0x01, 0x62, 0x12, 0x06, 0x0a, 0x04, 0x0a,
+                        0x02, 0x78, 0x79, 0x0a, 0x0d, 0x0a, 0x01, 0x63, 0x12, 0x08, 0x12, 0x06,
+                        0x0a, 0x04, 0x00, 0x00, 0x80, 0x3f]);
+    }
+
+    #[test]
+    fn frames_record() {
+        let dir = temp_dir("frame");
+        let mut writer = TfRecordWriter::new(dir.clone(), "split", 10, true);
+        let path = writer.write(&EXAMPLE).unwrap();
+        writer.flush().unwrap();
+
+        let mut expected = vec![0x0e, 0, 0, 0, 0, 0, 0, 0, 0xc5, 0xe5, 0x69, 0x3f];
+        expected.extend_from_slice(&EXAMPLE);
+        expected.extend_from_slice(&[0x39, 0xe8, 0x78, 0x50]);
+        assert_eq!(read(&path), expected);
+        assert_eq!(writer.position().bytes, expected.len() as u64);
+    }
+
+    #[test]
+    fn starts_new_shards() {
+        let dir = temp_dir("shards");
+        let mut writer = TfRecordWriter::new(dir.clone(), "split", 2, true);
+        let paths = (0..5).map(|_| writer.write(&EXAMPLE).unwrap()).collect::<Vec<_>>();
+        writer.flush().unwrap();
+        assert_eq!(paths[1], dir.join("split-00000.tfrecord"));
+        assert_eq!(paths[2], dir.join("split-00001.tfrecord"));
+        assert_eq!(paths[4], dir.join("split-00002.tfrecord"));
+        assert_eq!(read(&paths[0]).len(), 2 * 30);
+
+        // Numbering continues after the shards of earlier runs
+        let mut writer = TfRecordWriter::new(dir.clone(), "split", 2, true);
+        assert_eq!(writer.write(&EXAMPLE).unwrap(), dir.join("split-00003.tfrecord"));
+    }
+
+    #[test]
+    fn resumes_interrupted_run() {
+        let records = (0..7u8).map(|i| vec![i; i as usize + 1]).collect::<Vec<_>>();
+
+        let full_dir = temp_dir("full");
+        let mut writer = TfRecordWriter::new(full_dir.clone(), "split", 3, true);
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        writer.flush().unwrap();
+
+        // Interrupted after record 5, only the first 4 records belong to finished sources
+        let dir = temp_dir("interrupted");
+        let mut writer = TfRecordWriter::new(dir.clone(), "split", 3, true);
+        for record in &records[..4] {
+            writer.write(record).unwrap();
+        }
+        writer.flush().unwrap();
+        let position = writer.position();
+        for record in &records[4..6] {
+            writer.write(record).unwrap();
+        }
+        writer.write(&[9; 9]).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        let mut writer = TfRecordWriter::new(dir.clone(), "split", 3, true);
+        writer.resume(position).unwrap();
+        for record in &records[4..] {
+            writer.write(record).unwrap();
+        }
+        writer.flush().unwrap();
+
+        for shard in 0..3 {
+            let name = format!("split-{:05}.tfrecord", shard);
+            assert_eq!(read(&dir.join(&name)), read(&full_dir.join(&name)));
+        }
+        assert!(!dir.join("split-00003.tfrecord").exists());
+    }
+}
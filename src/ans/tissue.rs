@@ -0,0 +1,194 @@
+use image::{DynamicImage, GrayImage, ImageBuffer, Luma};
+
+// Channel the Otsu threshold is computed on
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TissueChannel {
+    // Stained tissue is saturated, white, grey and black backgrounds aren't. Pixels darker than
+    // MIN_VALUE count as unsaturated, noise makes their saturation meaningless.
+    Saturation,
+    // Tissue darker than the threshold, only suited for bright backgrounds
+    Grayscale,
+}
+
+// Slides are searched for tissue on a pyramid level with at most this many times less resolution
+// than the level which is cut
+pub const SLIDE_DOWNSAMPLE: u32 = 16;
+
+// Brightness of the brightest channel below which a pixel has no saturation, a tenth of 255
+pub const MIN_VALUE: f32 = 25.5;
+
+#[derive(Clone, Debug)]
+pub struct TissueDetection {
+    pub channel: TissueChannel,
+    // Tiles with a smaller fraction of tissue pixels are discarded as background
+    pub min_fraction: f32,
+    // Radius of the square structuring element of the opening and closing, 0 disables them
+    pub radius: u32,
+}
+
+impl TissueDetection {
+    pub fn new(channel: TissueChannel, min_fraction: f32, radius: u32) -> TissueDetection {
+        TissueDetection {
+            channel: channel,
+            min_fraction: min_fraction,
+            radius: radius,
+        }
+    }
+
+    // Tissue pixels are 255, background pixels 0
+    pub fn detect(&self, real: &DynamicImage) -> GrayImage {
+        let rgb = real.to_rgb();
+        let channel: GrayImage = match self.channel {
+            TissueChannel::Saturation => {
+                ImageBuffer::from_fn(rgb.width(), rgb.height(), |x, y| {
+                    let p = rgb.get_pixel(x, y).data;
+                    let max = p[0].max(p[1]).max(p[2]) as f32;
+                    let min = p[0].min(p[1]).min(p[2]) as f32;
+                    let saturation = if max >= MIN_VALUE { (max - min) / max } else { 0.0 };
+                    Luma { data: [(saturation * 255.0).round() as u8] }
+                })
+            }
+            // Inverted, so tissue is above the threshold for both channels
+            TissueChannel::Grayscale => {
+                let mut gray = real.to_luma();
+                for pixel in gray.pixels_mut() {
+                    pixel.data[0] = 255 - pixel.data[0];
+                }
+                gray
+            }
+        };
+
+        let mut histogram = [0u32; 256];
+        for pixel in channel.pixels() {
+            histogram[pixel.data[0] as usize] += 1;
+        }
+        let threshold = otsu(&histogram);
+
+        let mut mask = ImageBuffer::from_fn(channel.width(), channel.height(), |x, y| {
+            if channel.get_pixel(x, y).data[0] > threshold {
+                Luma { data: [255u8] }
+            } else {
+                Luma { data: [0u8] }
+            }
+        });
+        if self.radius > 0 {
+            // Opening removes speckles of noise, closing fills small holes in the tissue
+            mask = dilate(&erode(&mask, self.radius), self.radius);
+            mask = erode(&dilate(&mask, self.radius), self.radius);
+        }
+        mask
+    }
+}
+
+// Threshold maximising the between-class variance of the histogram
+pub fn otsu(histogram: &[u32; 256]) -> u8 {
+    let total = histogram.iter().map(|&c| c as f64).sum::<f64>();
+    let sum = histogram.iter().enumerate().map(|(i, &c)| i as f64 * c as f64).sum::<f64>();
+
+    let mut weight_bg = 0.0;
+    let mut sum_bg = 0.0;
+    let mut best = (0.0, 0u8);
+    for (i, &count) in histogram.iter().enumerate() {
+        weight_bg += count as f64;
+        if weight_bg == 0.0 {
+            continue;
+        }
+        let weight_fg = total - weight_bg;
+        if weight_fg == 0.0 {
+            break;
+        }
+        sum_bg += i as f64 * count as f64;
+        let mean_bg = sum_bg / weight_bg;
+        let mean_fg = (sum - sum_bg) / weight_fg;
+        let variance = weight_bg * weight_fg * (mean_bg - mean_fg) * (mean_bg - mean_fg);
+        if variance > best.0 {
+            best = (variance, i as u8);
+        }
+    }
+    best.1
+}
+
+fn erode(mask: &GrayImage, radius: u32) -> GrayImage {
+    filter(mask, radius, |a, b| a.min(b))
+}
+
+fn dilate(mask: &GrayImage, radius: u32) -> GrayImage {
+    filter(mask, radius, |a, b| a.max(b))
+}
+
+// Square min or max filter, applied separately along both axes
+fn filter<F: Fn(u8, u8) -> u8>(mask: &GrayImage, radius: u32, f: F) -> GrayImage {
+    let (width, height) = mask.dimensions();
+    let horizontal = ImageBuffer::from_fn(width, height, |x, y| {
+        let from = x.saturating_sub(radius);
+        let to = (x + radius).min(width - 1);
+        let value = (from..to + 1).fold(mask.get_pixel(x, y).data[0],
+                                       |v, i| f(v, mask.get_pixel(i, y).data[0]));
+        Luma { data: [value] }
+    });
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let from = y.saturating_sub(radius);
+        let to = (y + radius).min(height - 1);
+        let value = (from..to + 1).fold(horizontal.get_pixel(x, y).data[0],
+                                       |v, j| f(v, horizontal.get_pixel(x, j).data[0]));
+        Luma { data: [value] }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GrayImage, ImageBuffer, Rgb};
+
+    use super::{otsu, TissueChannel, TissueDetection};
+
+    // Noisy light gray background, a pink disc of tissue around (20, 20) with radius 12 and a
+    // single saturated speckle at (35, 5)
+    fn scan() -> DynamicImage {
+        DynamicImage::ImageRgb8(ImageBuffer::from_fn(40, 40, |x, y| {
+            let noise = ((x * 7 + y * 11) % 9) as u8;
+            let (dx, dy) = (x as i32 - 20, y as i32 - 20);
+            if dx * dx + dy * dy < 144 || (x, y) == (35, 5) {
+                Rgb { data: [190 + noise, 90 + noise, 160 + noise] }
+            } else {
+                Rgb { data: [225 + noise, 222 + noise, 228 + noise] }
+            }
+        }))
+    }
+
+    fn tissue(mask: &GrayImage) -> Vec<(u32, u32)> {
+        mask.enumerate_pixels().filter(|p| p.2.data[0] == 255).map(|p| (p.0, p.1)).collect()
+    }
+
+    #[test]
+    fn splits_bimodal_histograms() {
+        let mut histogram = [0u32; 256];
+        histogram[40] = 100;
+        histogram[45] = 50;
+        histogram[200] = 80;
+        let threshold = otsu(&histogram);
+        assert!(threshold >= 45 && threshold < 200);
+        assert_eq!(otsu(&[0; 256]), 0);
+    }
+
+    #[test]
+    fn detects_saturated_tissue() {
+        let raw = TissueDetection::new(TissueChannel::Saturation, 0.5, 0).detect(&scan());
+        assert!(tissue(&raw).contains(&(35, 5)));
+
+        let mask = TissueDetection::new(TissueChannel::Saturation, 0.5, 1).detect(&scan());
+        assert_eq!(mask.dimensions(), (40, 40));
+        // The opening removes the speckle, the disc stays
+        assert_eq!(mask.get_pixel(35, 5).data[0], 0);
+        assert_eq!(mask.get_pixel(20, 20).data[0], 255);
+        assert_eq!(mask.get_pixel(2, 2).data[0], 0);
+        let area = tissue(&mask).len();
+        assert!(area > 400 && area < 460, "tissue area {}", area);
+    }
+
+    #[test]
+    fn detects_dark_tissue_on_grayscale() {
+        let mask = TissueDetection::new(TissueChannel::Grayscale, 0.5, 0).detect(&scan());
+        assert_eq!(mask.get_pixel(20, 20).data[0], 255);
+        assert_eq!(mask.get_pixel(2, 30).data[0], 0);
+    }
+}
@@ -0,0 +1,28 @@
+use std::collections::VecDeque;
+
+use ans::split_image::SplitImage;
+use error::AnsResult;
+
+// Receives the tiles of a pass in the order they are cut, writing them to disk is just one
+// visitor. Context, zoomed, augmented and rotated copies are visited like any other tile.
+pub trait TileVisitor {
+    fn visit(&mut self, tile: &SplitImage) -> AnsResult<()>;
+
+    // Called after the last tile of a source image
+    fn finish_image(&mut self, _source: &str) -> AnsResult<()> {
+        Ok(())
+    }
+
+    // Called after the last source image of a pass
+    fn finish(&mut self) -> AnsResult<()> {
+        Ok(())
+    }
+}
+
+// Collects the tiles in memory, AugmentSplit::tiles buffers the tiles of one image this way
+impl TileVisitor for VecDeque<SplitImage> {
+    fn visit(&mut self, tile: &SplitImage) -> AnsResult<()> {
+        self.push_back(tile.clone());
+        Ok(())
+    }
+}
@@ -0,0 +1,394 @@
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ans::manifest::json_string;
+use ans::return_type::ShardSize;
+use ans::shards::{self, ShardPosition};
+use error::{AnsError, AnsResult};
+use img_reader::json::Json;
+
+const BLOCK: u64 = 512;
+
+// Appends samples to <pass>-<shard>.tar files in dir, in the layout read by WebDataset: all
+// members of a sample share a key and follow each other. Every closed shard is listed in
+// shards_<pass>.jsonl.
+pub struct TarShardWriter {
+    dir: PathBuf,
+    pass: String,
+    shard_size: ShardSize,
+    shard: u32,
+    // Samples and bytes in the current shard
+    samples: usize,
+    bytes: u64,
+    file: Option<BufWriter<File>>,
+    // Without writing only the shard a sample would end up in is returned, for dry runs
+    write: bool,
+    mtime: u64,
+}
+
+impl TarShardWriter {
+    // Shards of earlier runs are kept, numbering continues after the last one
+    pub fn new(dir: PathBuf, pass: &str, shard_size: ShardSize, write: bool) -> TarShardWriter {
+        let shard = shards::shard_numbers(&dir, pass, "tar")
+            .into_iter()
+            .map(|shard| shard + 1)
+            .max()
+            .unwrap_or(0);
+        let mtime = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        TarShardWriter {
+            dir: dir,
+            pass: String::from(pass),
+            shard_size: shard_size,
+            shard: shard,
+            samples: 0,
+            bytes: 0,
+            file: None,
+            write: write,
+            mtime: mtime,
+        }
+    }
+
+    // Continues writing at position, samples written after it are dropped from the shards and
+    // shards from the index
+    pub fn resume(&mut self, position: ShardPosition) -> AnsResult<()> {
+        self.file = None;
+        if self.write {
+            try!(self.drop_from_index(position.shard));
+            self.file = try!(shards::reopen(&self.dir, &self.pass, "tar", position))
+                .map(BufWriter::new);
+        }
+        self.shard = position.shard;
+        self.samples = position.records;
+        self.bytes = position.bytes;
+        Ok(())
+    }
+
+    // Position after the samples written so far, the end of the archive isn't part of it
+    pub fn position(&self) -> ShardPosition {
+        ShardPosition {
+            shard: self.shard,
+            records: self.samples,
+            bytes: self.bytes,
+        }
+    }
+
+    fn shard_name(&self) -> String {
+        format!("{}-{:05}.tar", self.pass, self.shard)
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.dir.join(format!("shards_{}.jsonl", self.pass))
+    }
+
+    // Removes the lines of shard and the shards after it from the index
+    fn drop_from_index(&self, shard: u32) -> AnsResult<()> {
+        let path = self.index_path();
+        if !path.exists() {
+            return Ok(());
+        }
+        let mut content = String::new();
+        try!(try!(File::open(&path)).read_to_string(&mut content));
+        let prefix = format!("{}-", self.pass);
+        let mut lines = String::new();
+        for line in content.lines().filter(|l| !l.is_empty()) {
+            let number = Json::parse(line)
+                .ok()
+                .and_then(|json| json.get("shard").and_then(|s| s.as_str()).map(String::from))
+                .and_then(|name| {
+                    if name.starts_with(&prefix) && name.ends_with(".tar") {
+                        name[prefix.len()..name.len() - ".tar".len()].parse::<u32>().ok()
+                    } else {
+                        None
+                    }
+                });
+            match number {
+                Some(number) if number < shard => {
+                    lines.push_str(line);
+                    lines.push('\n');
+                }
+                Some(_) => {}
+                None => {
+                    return Err(AnsError::Manifest(format!("Invalid line in {:?}: {}", path, line)))
+                }
+            }
+        }
+        try!(try!(File::create(&path)).write_all(lines.as_bytes()));
+        Ok(())
+    }
+
+    // members are file names and contents, returns the path of the shard the sample was written
+    // to. A sample is never split across shards, so a shard may exceed a byte limit if a single
+    // sample does.
+    pub fn write(&mut self, members: &[(String, Vec<u8>)]) -> AnsResult<PathBuf> {
+        let size = members.iter()
+            .map(|&(_, ref data)| BLOCK + padded(data.len() as u64))
+            .sum::<u64>();
+        let full = match self.shard_size {
+            ShardSize::Samples(samples) => self.samples >= samples,
+            ShardSize::Bytes(bytes) => self.samples > 0 && self.bytes + size > bytes,
+        };
+        if full {
+            try!(self.close());
+            self.shard += 1;
+        }
+        let path = self.dir.join(self.shard_name());
+        self.samples += 1;
+        self.bytes += size;
+        if !self.write {
+            return Ok(path);
+        }
+
+        if self.file.is_none() {
+            try!(DirBuilder::new().recursive(true).create(&self.dir));
+            self.file = Some(BufWriter::new(try!(File::create(&path))));
+        }
+        for &(ref name, ref data) in members {
+            let header = try!(self.header(name, data.len() as u64));
+            if let Some(ref mut file) = self.file {
+                try!(file.write_all(&header));
+                try!(file.write_all(data));
+                try!(file.write_all(&vec![0; (padded(data.len() as u64) - data.len() as u64) as
+                                             usize]));
+            }
+        }
+        Ok(path)
+    }
+
+    // Ends the archive after the samples written so far, the end marker is overwritten by the
+    // next sample. The shards stay readable if a run is interrupted.
+    pub fn flush(&mut self) -> AnsResult<()> {
+        if let Some(ref mut file) = self.file {
+            try!(file.write_all(&[0; 2 * BLOCK as usize]));
+            try!(file.flush());
+            try!(file.seek(SeekFrom::Current(-2 * BLOCK as i64)));
+        }
+        Ok(())
+    }
+
+    // Closes the current shard and adds it to the index
+    pub fn close(&mut self) -> AnsResult<()> {
+        if self.samples > 0 && self.write {
+            try!(self.flush());
+            self.file = None;
+            let line = format!("{{\"shard\":{},\"samples\":{},\"bytes\":{}}}\n",
+                               json_string(&self.shard_name()),
+                               self.samples,
+                               self.bytes + 2 * BLOCK);
+            let mut index = try!(OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(self.index_path()));
+            try!(index.write_all(line.as_bytes()));
+        }
+        self.samples = 0;
+        self.bytes = 0;
+        Ok(())
+    }
+
+    // ustar header of a regular file
+    fn header(&self, name: &str, size: u64) -> AnsResult<Vec<u8>> {
+        if name.len() > 100 {
+            return Err(AnsError::Unsupported(format!("tar member name longer than 100 bytes: \
+                                                      {}",
+                                                     name)));
+        }
+        let mut header = vec![0u8; BLOCK as usize];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        try!(octal(&mut header[100..108], 0o644));
+        try!(octal(&mut header[108..116], 0));
+        try!(octal(&mut header[116..124], 0));
+        try!(octal(&mut header[124..136], size));
+        try!(octal(&mut header[136..148], self.mtime));
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+
+        // Computed with the checksum field filled with spaces
+        for b in &mut header[148..156] {
+            *b = b' ';
+        }
+        let checksum = header.iter().map(|&b| b as u64).sum::<u64>();
+        try!(octal(&mut header[148..155], checksum));
+        Ok(header)
+    }
+}
+
+fn padded(len: u64) -> u64 {
+    (len + BLOCK - 1) / BLOCK * BLOCK
+}
+
+// Zero padded octal number terminated by a NUL byte, filling the field
+fn octal(field: &mut [u8], value: u64) -> AnsResult<()> {
+    let width = field.len() - 1;
+    let digits = format!("{:01$o}", value, width);
+    if digits.len() > width {
+        return Err(AnsError::Unsupported(format!("{} doesn't fit into a tar header field of {} \
+                                                  octal digits",
+                                                 value,
+                                                 width)));
+    }
+    field[..width].copy_from_slice(digits.as_bytes());
+    field[width] = 0;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::path::PathBuf;
+
+    use ans::return_type::ShardSize;
+    use super::{octal, TarShardWriter, BLOCK};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("ans_webdataset_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn read(path: &PathBuf) -> Vec<u8> {
+        let mut content = vec![];
+        File::open(path).unwrap().read_to_end(&mut content).unwrap();
+        content
+    }
+
+    fn sample(key: u32, len: usize) -> Vec<(String, Vec<u8>)> {
+        vec![(format!("{:04}.png", key), vec![key as u8 + 1; len]),
+             (format!("{:04}.cls", key), format!("{}", key % 3).into_bytes())]
+    }
+
+    fn parse_octal(field: &[u8]) -> u64 {
+        let digits = field.iter().take_while(|&&b| b != 0).map(|&b| b as char).collect::<String>();
+        u64::from_str_radix(&digits, 8).unwrap()
+    }
+
+    #[test]
+    fn header_checksum() {
+        let writer = TarShardWriter::new(temp_dir("header"), "split", ShardSize::Samples(1), false);
+        let header = writer.header("0001.png", 1000).unwrap();
+        assert_eq!(header.len(), BLOCK as usize);
+        assert_eq!(&header[..9], b"0001.png\0");
+        assert_eq!(parse_octal(&header[124..136]), 1000);
+        assert_eq!(&header[257..265], b"ustar\x0000");
+
+        let checksum = parse_octal(&header[148..155]);
+        let sum = header.iter()
+            .enumerate()
+            .map(|(i, &b)| if i >= 148 && i < 156 { b' ' as u64 } else { b as u64 })
+            .sum::<u64>();
+        assert_eq!(checksum, sum);
+        assert_eq!(&header[154..156], b"\0 ");
+
+        assert!(writer.header(&"x".repeat(101), 0).is_err());
+    }
+
+    #[test]
+    fn octal_field() {
+        let mut field = [b'x'; 8];
+        octal(&mut field, 0o644).unwrap();
+        assert_eq!(&field, b"0000644\0");
+        octal(&mut field, 0o7777777).unwrap();
+        assert_eq!(&field, b"7777777\0");
+        assert!(octal(&mut field, 0o10000000).is_err());
+    }
+
+    #[test]
+    fn pads_members() {
+        let dir = temp_dir("padding");
+        let mut writer = TarShardWriter::new(dir.clone(), "split", ShardSize::Samples(10), true);
+        let path = writer.write(&sample(0, 700)).unwrap();
+        writer.close().unwrap();
+
+        let tar = read(&path);
+        // Two headers, 1024 bytes of data, one padded block of the class and the end marker
+        assert_eq!(tar.len() as u64, 2 * BLOCK + 1024 + BLOCK + 2 * BLOCK);
+        assert!(tar[512..1212].iter().all(|&b| b == 1));
+        assert!(tar[1212..1536].iter().all(|&b| b == 0));
+        assert_eq!(&tar[1536..1544], b"0000.cls");
+        assert_eq!(&tar[2048..2049], b"0");
+        assert!(tar[tar.len() - 2 * BLOCK as usize..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn starts_new_shard_by_samples() {
+        let dir = temp_dir("samples");
+        let mut writer = TarShardWriter::new(dir.clone(), "split", ShardSize::Samples(2), true);
+        let paths = (0..5).map(|key| writer.write(&sample(key, 10)).unwrap()).collect::<Vec<_>>();
+        writer.close().unwrap();
+
+        assert_eq!(paths[1], dir.join("split-00000.tar"));
+        assert_eq!(paths[2], dir.join("split-00001.tar"));
+        assert_eq!(paths[4], dir.join("split-00002.tar"));
+        let mut index = String::new();
+        File::open(dir.join("shards_split.jsonl")).unwrap().read_to_string(&mut index).unwrap();
+        assert_eq!(index.lines().collect::<Vec<_>>(),
+                   vec!["{\"shard\":\"split-00000.tar\",\"samples\":2,\"bytes\":5120}",
+                        "{\"shard\":\"split-00001.tar\",\"samples\":2,\"bytes\":5120}",
+                        "{\"shard\":\"split-00002.tar\",\"samples\":1,\"bytes\":3072}"]);
+    }
+
+    #[test]
+    fn starts_new_shard_by_bytes() {
+        let dir = temp_dir("bytes");
+        // A sample of 10 bytes takes 2048 bytes, one of 600 bytes 2560
+        let mut writer = TarShardWriter::new(dir.clone(), "split", ShardSize::Bytes(4608), true);
+        let shard = |path: PathBuf| path.file_name().unwrap().to_string_lossy().into_owned();
+        assert_eq!(shard(writer.write(&sample(0, 10)).unwrap()), "split-00000.tar");
+        assert_eq!(shard(writer.write(&sample(1, 600)).unwrap()), "split-00000.tar");
+        assert_eq!(shard(writer.write(&sample(2, 10)).unwrap()), "split-00001.tar");
+        // A sample larger than the limit gets a shard of its own
+        assert_eq!(shard(writer.write(&sample(3, 9000)).unwrap()), "split-00002.tar");
+        assert_eq!(shard(writer.write(&sample(4, 10)).unwrap()), "split-00003.tar");
+        writer.close().unwrap();
+        assert_eq!(read(&dir.join("split-00000.tar")).len(), 4608 + 1024);
+    }
+
+    #[test]
+    fn resumes_interrupted_run() {
+        let full_dir = temp_dir("full");
+        let mut writer = TarShardWriter::new(full_dir.clone(),
+                                             "split",
+                                             ShardSize::Samples(3),
+                                             true);
+        for key in 0..7 {
+            writer.write(&sample(key, 100 * key as usize)).unwrap();
+        }
+        writer.close().unwrap();
+
+        // Interrupted while writing the samples of a source after sample 3, which closed a shard
+        let dir = temp_dir("interrupted");
+        let mut writer = TarShardWriter::new(dir.clone(), "split", ShardSize::Samples(3), true);
+        for key in 0..4 {
+            writer.write(&sample(key, 100 * key as usize)).unwrap();
+        }
+        writer.flush().unwrap();
+        let position = writer.position();
+        for key in 4..7 {
+            writer.write(&sample(key, 100 * key as usize)).unwrap();
+        }
+        writer.write(&sample(9, 9)).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        let mut writer = TarShardWriter::new(dir.clone(), "split", ShardSize::Samples(3), true);
+        writer.resume(position).unwrap();
+        for key in 4..7 {
+            writer.write(&sample(key, 100 * key as usize)).unwrap();
+        }
+        writer.close().unwrap();
+
+        let names = ["split-00000.tar", "split-00001.tar", "split-00002.tar", "shards_split.jsonl"];
+        for name in &names {
+            assert_eq!(read(&dir.join(name)), read(&full_dir.join(name)), "{}", name);
+        }
+        assert!(!dir.join("split-00003.tar").exists());
+    }
+}
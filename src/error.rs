@@ -0,0 +1,147 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use image::ImageError;
+
+#[derive(Debug)]
+pub enum AnsError {
+    Io(io::Error),
+    Image(ImageError),
+    // A source, label or reference image could not be decoded
+    ImageRead(PathBuf, ImageError),
+    // A source image without a corresponding label
+    MissingLabel(String),
+    // A required setting of the AugmentSplitBuilder was never set
+    MissingSetting(&'static str),
+    InvalidSetting(String),
+    OutputExists(PathBuf),
+    Config(String),
+    Manifest(String),
+    // A polygon annotation file could not be read
+    Annotation(String),
+    // The stain vectors of an image could not be estimated for stain normalization
+    StainFit(String),
+    Unsupported(String),
+}
+
+pub type AnsResult<T> = Result<T, AnsError>;
+
+impl fmt::Display for AnsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AnsError::Io(ref e) => write!(f, "I/O error: {}", e),
+            AnsError::Image(ref e) => write!(f, "Image error: {}", e),
+            AnsError::ImageRead(ref path, ref e) => {
+                write!(f, "Could not read image {:?}: {}", path, e)
+            }
+            AnsError::MissingLabel(ref name) => {
+                write!(f, "Could not find a corresponding label for image {}", name)
+            }
+            AnsError::MissingSetting(setting) => {
+                write!(f, "Called AugmentSplitBuilder.build() without setting {}", setting)
+            }
+            AnsError::InvalidSetting(ref msg) => write!(f, "Invalid setting: {}", msg),
+            AnsError::OutputExists(ref path) => {
+                write!(f, "Output directory {:?} is not empty", path)
+            }
+            AnsError::Config(ref msg) => write!(f, "Config error: {}", msg),
+            AnsError::Manifest(ref msg) => write!(f, "Manifest error: {}", msg),
+            AnsError::Annotation(ref msg) => write!(f, "Annotation error: {}", msg),
+            AnsError::StainFit(ref msg) => write!(f, "Could not estimate stain vectors: {}", msg),
+            AnsError::Unsupported(ref msg) => write!(f, "Unsupported: {}", msg),
+        }
+    }
+}
+
+impl error::Error for AnsError {
+    fn description(&self) -> &str {
+        match *self {
+            AnsError::Io(_) => "I/O error",
+            AnsError::Image(_) => "image error",
+            AnsError::ImageRead(..) => "could not read image",
+            AnsError::MissingLabel(_) => "missing label",
+            AnsError::MissingSetting(_) => "missing setting",
+            AnsError::InvalidSetting(_) => "invalid setting",
+            AnsError::OutputExists(_) => "output directory is not empty",
+            AnsError::Config(_) => "config error",
+            AnsError::Manifest(_) => "manifest error",
+            AnsError::Annotation(_) => "annotation error",
+            AnsError::StainFit(_) => "could not estimate stain vectors",
+            AnsError::Unsupported(_) => "unsupported",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            AnsError::Io(ref e) => Some(e),
+            AnsError::Image(ref e) => Some(e),
+            AnsError::ImageRead(_, ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for AnsError {
+    fn from(e: io::Error) -> AnsError {
+        AnsError::Io(e)
+    }
+}
+
+impl From<ImageError> for AnsError {
+    fn from(e: ImageError) -> AnsError {
+        AnsError::Image(e)
+    }
+}
+
+// Abort stops at the first bad input, Skip leaves it out and records the error in an ErrorReport
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ErrorPolicy {
+    Abort,
+    Skip,
+}
+
+#[derive(Debug)]
+pub struct ErrorReport {
+    errors: Vec<(String, AnsError)>,
+}
+
+impl ErrorReport {
+    pub fn new() -> ErrorReport {
+        ErrorReport { errors: vec![] }
+    }
+
+    // Returns the error again if the policy doesn't allow skipping it
+    pub fn handle(&mut self, policy: ErrorPolicy, input: &str, error: AnsError) -> AnsResult<()> {
+        match policy {
+            ErrorPolicy::Abort => Err(error),
+            ErrorPolicy::Skip => {
+                self.errors.push((String::from(input), error));
+                Ok(())
+            }
+        }
+    }
+
+    pub fn append(&mut self, other: ErrorReport) {
+        self.errors.extend(other.errors);
+    }
+
+    pub fn get_errors(&self) -> &Vec<(String, AnsError)> {
+        &self.errors
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl fmt::Display for ErrorReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "{} input(s) skipped", self.errors.len()));
+        for &(ref input, ref error) in &self.errors {
+            try!(writeln!(f, "  {}: {}", input, error));
+        }
+        Ok(())
+    }
+}
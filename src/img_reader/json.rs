@@ -0,0 +1,243 @@
+use std::char;
+use std::str::Chars;
+use std::iter::Peekable;
+
+// Minimal JSON document model, enough to read GeoJSON annotations
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    // Members in document order
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser { chars: text.chars().peekable() };
+        let value = try!(parser.value());
+        parser.whitespace();
+        match parser.chars.next() {
+            None => Ok(value),
+            Some(c) => Err(format!("unexpected {:?} after the document", c)),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref members) => members.iter().find(|m| m.0 == key).map(|m| &m.1),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Json::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Json>> {
+        match *self {
+            Json::Array(ref values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn whitespace(&mut self) {
+        while let Some(&c) = self.chars.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(format!("expected {:?}, found {:?}", expected, c)),
+            None => Err(format!("expected {:?}, found the end", expected)),
+        }
+    }
+
+    fn literal(&mut self, literal: &str, value: Json) -> Result<Json, String> {
+        for expected in literal.chars() {
+            try!(self.expect(expected));
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.whitespace();
+        match self.chars.peek().cloned() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => self.string().map(Json::String),
+            Some('t') => self.literal("true", Json::Bool(true)),
+            Some('f') => self.literal("false", Json::Bool(false)),
+            Some('n') => self.literal("null", Json::Null),
+            Some(c) if c == '-' || c.is_digit(10) => self.number(),
+            Some(c) => Err(format!("unexpected {:?}", c)),
+            None => Err(String::from("unexpected end")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        try!(self.expect('{'));
+        let mut members = vec![];
+        self.whitespace();
+        if self.chars.peek() == Some(&'}') {
+            self.chars.next();
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.whitespace();
+            let key = try!(self.string());
+            self.whitespace();
+            try!(self.expect(':'));
+            members.push((key, try!(self.value())));
+            self.whitespace();
+            match self.chars.next() {
+                Some(',') => {}
+                Some('}') => return Ok(Json::Object(members)),
+                c => return Err(format!("expected ',' or '}}' in object, found {:?}", c)),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        try!(self.expect('['));
+        let mut values = vec![];
+        self.whitespace();
+        if self.chars.peek() == Some(&']') {
+            self.chars.next();
+            return Ok(Json::Array(values));
+        }
+        loop {
+            values.push(try!(self.value()));
+            self.whitespace();
+            match self.chars.next() {
+                Some(',') => {}
+                Some(']') => return Ok(Json::Array(values)),
+                c => return Err(format!("expected ',' or ']' in array, found {:?}", c)),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let mut number = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if c.is_digit(10) || c == '-' || c == '+' || c == '.' || c == 'e' || c == 'E' {
+                number.push(c);
+                self.chars.next();
+            } else {
+                break;
+            }
+        }
+        // Out of range numbers parse as infinity, which JSON can't express
+        match number.parse::<f64>() {
+            Ok(value) if value.is_finite() => Ok(Json::Number(value)),
+            _ => Err(format!("invalid number {}", number)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        try!(self.expect('"'));
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => {
+                    match self.chars.next() {
+                        Some('"') => s.push('"'),
+                        Some('\\') => s.push('\\'),
+                        Some('/') => s.push('/'),
+                        Some('b') => s.push('\u{8}'),
+                        Some('f') => s.push('\u{c}'),
+                        Some('n') => s.push('\n'),
+                        Some('r') => s.push('\r'),
+                        Some('t') => s.push('\t'),
+                        Some('u') => {
+                            let unit = try!(self.hex4());
+                            // A surrogate pair encodes a character outside the basic plane
+                            let code = if unit >= 0xd800 && unit < 0xdc00 {
+                                try!(self.expect('\\'));
+                                try!(self.expect('u'));
+                                let low = try!(self.hex4());
+                                let low = low.wrapping_sub(0xdc00) & 0x3ff;
+                                0x10000 + ((unit - 0xd800) << 10) + low
+                            } else {
+                                unit
+                            };
+                            s.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                        }
+                        c => return Err(format!("invalid escape {:?}", c)),
+                    }
+                }
+                Some(c) => s.push(c),
+                None => return Err(String::from("unterminated string")),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let mut value = 0;
+        for _ in 0..4 {
+            match self.chars.next().and_then(|c| c.to_digit(16)) {
+                Some(digit) => value = value * 16 + digit,
+                None => return Err(String::from("invalid \\u escape")),
+            }
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Json;
+
+    #[test]
+    fn parses_document() {
+        let text = " {\"a\": [1, -2.5e1, true, null], \"b\": {\"c\": \"d\"}, \"e\": []} ";
+        let json = Json::parse(text).unwrap();
+        assert_eq!(json.get("a"),
+                   Some(&Json::Array(vec![Json::Number(1.0),
+                                          Json::Number(-25.0),
+                                          Json::Bool(true),
+                                          Json::Null])));
+        assert_eq!(json.get("b").and_then(|b| b.get("c")).and_then(|c| c.as_str()),
+                   Some("d"));
+        assert_eq!(json.get("e").and_then(|e| e.as_array()).map(|e| e.len()), Some(0));
+        assert_eq!(json.get("f"), None);
+    }
+
+    #[test]
+    fn unescapes_strings() {
+        assert_eq!(Json::parse(r#""a\"\\\/\n\té😀""#),
+                   Ok(Json::String(String::from("a\"\\/\n\té\u{1f600}"))));
+    }
+
+    #[test]
+    fn rejects_invalid_documents() {
+        for text in &["", "{", "[1,]", "{\"a\" 1}", "\"open", "1 2", "tru", "1e999", "-1e999",
+                      "[1.2.3]"] {
+            assert!(Json::parse(text).is_err(), "{:?}", text);
+        }
+    }
+}
@@ -1,74 +1,313 @@
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::collections::HashMap;
-use image;
+use image::{self, GenericImage};
+
+use error::{AnsError, AnsResult, ErrorPolicy, ErrorReport};
+
+pub mod stain_norm;
+pub mod json;
+pub mod polygons;
+pub mod tiff;
+pub mod slide;
+pub mod raster;
+
+use self::stain_norm::{StainNormalization, StainNormalizer, StainParams};
+use self::slide::{Slide, SlideLabel};
+use self::tiff::TiledTiff;
+use self::raster::Raster;
 
 #[derive(Clone)]
 pub enum LabelType {
     Img(PathBuf),
     FileName,
     CSV(PathBuf),
+    // GeoJSON, QuPath or ASAP polygon annotations named like the source images, rasterized into
+    // masks when the images are read. Every class is painted with its mask value, without
+    // classes all polygons are painted white.
+    Polygons(PathBuf, Vec<(String, u8)>),
 }
 
 pub struct ImgReader {
     num_of_images: usize,
     pub img_map: HashMap<String, (image::DynamicImage, image::DynamicImage)>,
+    // Samples of the TIFF sources with 16 bits or more than four channels, img_map holds their
+    // 8 bit view. Other formats are decoded to 8 bits, 16 bit PNG files are not supported.
+    pub deep: HashMap<String, Raster>,
+    // Tiled TIFF sources, which are only read region by region
+    pub slides: HashMap<String, Slide>,
+    // Fitted stain parameters of every source image, only filled if a normalization is used
+    pub stain_params: HashMap<String, StainParams>,
+    // Inputs left out because of the ErrorPolicy::Skip
+    pub report: ErrorReport,
 }
 
 impl ImgReader {
-    pub fn new<'a, 'b: 'a>(img_path: PathBuf, label_type: LabelType) -> ImgReader {
-        let training_map = image_map(img_path);
-        let label_map: HashMap<String, image::DynamicImage> = match label_type {
+    pub fn new(img_path: PathBuf,
+               label_type: LabelType,
+               normalization: Option<StainNormalization>,
+               policy: ErrorPolicy)
+               -> AnsResult<ImgReader> {
+        let mut report = ErrorReport::new();
+        let (mut training_map, training_slides, mut deep) =
+            try!(image_map(img_path, policy, &mut report));
+        let mut stain_params = HashMap::new();
+        let normalized = normalization.is_some();
+
+        if let Some(normalization) = normalization {
+            let normalizer = try!(StainNormalizer::new(normalization));
+            let mut failed = vec![];
+            for (name, training_img) in training_map.iter_mut() {
+                if deep.contains_key(name) {
+                    let error = AnsError::Unsupported(String::from("stain normalization of 16 \
+                                                                    bit or multichannel images"));
+                    try!(report.handle(policy, name, error));
+                    failed.push(name.clone());
+                    continue;
+                }
+                let rgb = training_img.to_rgb();
+                match normalizer.fit(&rgb) {
+                    Ok(params) => {
+                        *training_img =
+                            image::DynamicImage::ImageRgb8(normalizer.normalize(&rgb, &params));
+                        stain_params.insert(name.clone(), params);
+                    }
+                    Err(e) => {
+                        try!(report.handle(policy, name, e));
+                        failed.push(name.clone());
+                    }
+                }
+            }
+            for name in failed {
+                training_map.remove(&name);
+                deep.remove(&name);
+            }
+        }
+
+        let (label_map, mut label_slides) = match label_type {
             // TODO Currently this only works for labels in the form of an image, which is my current
             // use case. Support for the other fields in the LabelType will be added later
-            LabelType::Img(p) => image_map(p),
-            _ => unimplemented!(),
+            LabelType::Img(ref p) => {
+                let (mut labels, slides, deep_labels) =
+                    try!(image_map(p.clone(), policy, &mut report));
+                // The 8 bit view of a deep label image would change its label values
+                for name in deep_labels.keys() {
+                    labels.remove(name);
+                    let error = AnsError::Unsupported(String::from("16 bit or multichannel \
+                                                                    label images"));
+                    try!(report.handle(policy, name, error));
+                }
+                (labels, slides)
+            }
+            LabelType::FileName => {
+                return Err(AnsError::Unsupported(String::from("LabelType::FileName")))
+            }
+            LabelType::CSV(_) => return Err(AnsError::Unsupported(String::from("LabelType::CSV"))),
+            LabelType::Polygons(ref dir, ref classes) => {
+                (try!(polygon_map(dir, classes, &training_map, policy, &mut report)),
+                 HashMap::new())
+            }
         };
 
+        let mut slides = HashMap::new();
+        for (name, real) in training_slides {
+            if normalized {
+                let error = AnsError::Unsupported(String::from("stain normalization of tiled \
+                                                                slides"));
+                try!(report.handle(policy, &name, error));
+                continue;
+            }
+            let label = match label_type {
+                LabelType::Polygons(ref dir, ref classes) => {
+                    let path = polygons::annotation_path(dir, &name);
+                    match path.map(|p| polygons::read_polygons(&p)) {
+                        Some(Ok(polygons)) => Some(SlideLabel::Polygons(polygons, classes.clone())),
+                        Some(Err(e)) => {
+                            try!(report.handle(policy, &name, e));
+                            continue;
+                        }
+                        None => None,
+                    }
+                }
+                _ => {
+                    label_slides.remove(&name)
+                        .map(SlideLabel::Tiff)
+                        .or_else(|| label_map.get(&name).map(|l| SlideLabel::Image(l.to_luma())))
+                }
+            };
+            match label {
+                Some(label) => {
+                    slides.insert(name, Slide::new(real, label));
+                }
+                None => {
+                    let error = AnsError::MissingLabel(name.clone());
+                    try!(report.handle(policy, &name, error));
+                }
+            }
+        }
+
         let img_map = {
             let mut img_map = HashMap::new();
             for (name, training_img) in training_map {
                 match label_map.get(&name) {
                     Some(label_img) => {
-                        // println!("Inserting {:?} into image map", name);
                         img_map.insert(name, (training_img, label_img.clone()));
                     }
                     None => {
-                        panic!("Could not find a corresponding Label for Image {}", name);
+                        deep.remove(&name);
+                        let error = AnsError::MissingLabel(name.clone());
+                        try!(report.handle(policy, &name, error));
                     }
                 }
             }
             img_map
         };
 
-        ImgReader {
-            num_of_images: img_map.len(),
+        Ok(ImgReader {
+            num_of_images: img_map.len() + slides.len(),
             img_map: img_map,
-        }
+            deep: deep,
+            slides: slides,
+            stain_params: stain_params,
+            report: report,
+        })
     }
 
     pub fn get_num_of_images(&self) -> usize {
         self.num_of_images
     }
+
+    pub fn get_stain_params(&self, name: &str) -> Option<&StainParams> {
+        self.stain_params.get(name)
+    }
+}
+
+// Missing annotation files are left out here and reported as missing labels
+fn polygon_map(dir: &Path,
+               classes: &[(String, u8)],
+               training_map: &HashMap<String, image::DynamicImage>,
+               policy: ErrorPolicy,
+               report: &mut ErrorReport)
+               -> AnsResult<HashMap<String, image::DynamicImage>> {
+    let mut label_map = HashMap::new();
+    for (name, training_img) in training_map {
+        let path = match polygons::annotation_path(dir, name) {
+            Some(path) => path,
+            None => continue,
+        };
+        match polygons::read_polygons(&path) {
+            Ok(polygons) => {
+                let (width, height) = training_img.dimensions();
+                let mask = polygons::rasterize(&polygons, classes, width, height);
+                label_map.insert(name.clone(), image::DynamicImage::ImageLuma8(mask));
+            }
+            Err(e) => try!(report.handle(policy, name, e)),
+        }
+    }
+    Ok(label_map)
 }
 
-fn image_map<'a>(img_path: PathBuf) -> HashMap<String, image::DynamicImage> {
-    let dir_entries = fs::read_dir(img_path)
-        .expect("The specified path given to fn image_map() doesn't seem to exist");
+// Tiled TIFF files are opened as slides instead of being decoded. TIFF files with 16 bit samples
+// or more than four channels are decoded into a Raster and their 8 bit view.
+fn image_map(img_path: PathBuf,
+             policy: ErrorPolicy,
+             report: &mut ErrorReport)
+             -> AnsResult<(HashMap<String, image::DynamicImage>,
+                           HashMap<String, TiledTiff>,
+                           HashMap<String, Raster>)> {
+    let dir_entries = try!(fs::read_dir(img_path));
     let mut path_map = HashMap::new();
+    let mut slide_map = HashMap::new();
+    let mut deep_map = HashMap::new();
 
     for d in dir_entries {
-        let dir_entry = d.unwrap();
+        let dir_entry = try!(d);
+        let path = dir_entry.path();
 
-        let img_name = dir_entry.file_name().into_string().unwrap();
-        let img_file = image::open(dir_entry.path());
+        let img_name = match dir_entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(name) => {
+                let error = AnsError::Unsupported(format!("non UTF-8 file name {:?}", name));
+                try!(report.handle(policy, &path.to_string_lossy(), error));
+                continue;
+            }
+        };
 
-        if let Ok(image) = img_file {
-            path_map.insert(img_name, image);
-        } else {
-            panic!("Error in fn image_map(); Could not read Image {:?}",
-                   img_name);
+        let extension = path.extension()
+            .map_or(String::new(), |e| e.to_string_lossy().to_lowercase());
+        if extension == "tif" || extension == "tiff" || extension == "svs" {
+            if let Ok(tiff) = TiledTiff::open(&path) {
+                if tiff.is_tiled() {
+                    slide_map.insert(img_name, tiff);
+                    continue;
+                }
+                if tiff.is_deep() {
+                    let (width, height) = tiff.dimensions(0).unwrap_or((0, 0));
+                    match tiff.read_raster(0, 0, 0, width, height) {
+                        Ok(raster) => {
+                            path_map.insert(img_name.clone(), raster.to_image());
+                            deep_map.insert(img_name, raster);
+                        }
+                        Err(e) => try!(report.handle(policy, &img_name, e)),
+                    }
+                    continue;
+                }
+            }
+        }
+
+        match image::open(&path) {
+            Ok(image) => {
+                path_map.insert(img_name, image);
+            }
+            Err(e) => try!(report.handle(policy, &img_name, AnsError::ImageRead(path, e))),
+        }
+    }
+    Ok((path_map, slide_map, deep_map))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use image::{ImageBuffer, Luma};
+    use error::{AnsError, ErrorPolicy};
+    use super::{ImgReader, LabelType};
+
+    #[test]
+    fn skips_bad_inputs() {
+        let dir = env::temp_dir().join("ans_img_reader_skip");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("real")).unwrap();
+        fs::create_dir_all(dir.join("labels")).unwrap();
+        // JPEG, as the PNG encoder of image fails the debug assertions of the standard library
+        let image = ImageBuffer::from_pixel(8, 8, Luma { data: [9u8] });
+        for file in &["real/good.jpg", "real/unlabelled.jpg", "labels/good.jpg"] {
+            image.save(dir.join(file)).unwrap();
+        }
+        File::create(dir.join("real/broken.png")).unwrap().write_all(b"no png").unwrap();
+
+        let read = |policy| {
+            ImgReader::new(dir.join("real"),
+                           LabelType::Img(dir.join("labels")),
+                           None,
+                           policy)
+        };
+        assert!(read(ErrorPolicy::Abort).is_err());
+
+        let reader = read(ErrorPolicy::Skip).unwrap();
+        assert_eq!(reader.img_map.keys().collect::<Vec<_>>(), vec!["good.jpg"]);
+        assert_eq!(reader.get_num_of_images(), 1);
+        let mut errors = reader.report.get_errors().iter().collect::<Vec<_>>();
+        errors.sort_by(|a, b| a.0.cmp(&b.0));
+        match (&errors[0].1, &errors[1].1) {
+            (&AnsError::ImageRead(ref path, _), &AnsError::MissingLabel(ref name)) => {
+                assert_eq!(path, &dir.join("real/broken.png"));
+                assert_eq!(name, "unlabelled.jpg");
+            }
+            other => panic!("unexpected errors {:?}", other),
         }
+        assert_eq!(errors.len(), 2);
     }
-    path_map
 }
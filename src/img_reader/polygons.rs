@@ -0,0 +1,368 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use image::{GrayImage, Luma};
+use xml::reader::{EventReader, XmlEvent};
+
+use error::{AnsError, AnsResult};
+use super::json::Json;
+
+// Annotation file extensions looked up next to the stem of a source image
+const EXTENSIONS: [&str; 3] = ["geojson", "json", "xml"];
+
+// Closed polygon in source image pixel coordinates, the first ring is the outer boundary and the
+// others are holes
+#[derive(Clone, Debug)]
+pub struct Polygon {
+    pub class: Option<String>,
+    pub rings: Vec<Vec<(f64, f64)>>,
+}
+
+// Annotation file of the source image img_name in dir, if there is one
+pub fn annotation_path(dir: &Path, img_name: &str) -> Option<PathBuf> {
+    let stem = match Path::new(img_name).file_stem() {
+        Some(stem) => stem.to_string_lossy().into_owned(),
+        None => return None,
+    };
+    EXTENSIONS.iter()
+        .map(|ext| dir.join(format!("{}.{}", stem, ext)))
+        .find(|path| path.is_file())
+}
+
+// GeoJSON (including QuPath exports) or ASAP XML, depending on the extension
+pub fn read_polygons(path: &Path) -> AnsResult<Vec<Polygon>> {
+    let mut text = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut text));
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("xml") => read_asap(path, &text),
+        _ => read_geojson(path, &text),
+    }
+}
+
+// Paints the polygons of every class in the order of classes, so later classes cover earlier
+// ones. Polygons of classes not listed are left out, without classes every polygon is painted
+// white. A pixel belongs to a polygon if its centre does.
+pub fn rasterize(polygons: &[Polygon],
+                 classes: &[(String, u8)],
+                 width: u32,
+                 height: u32)
+                 -> GrayImage {
+    rasterize_region(polygons, classes, (0, 0, width, height), (1.0, 1.0))
+}
+
+// Mask of the region x, y, width, height of the polygons scaled by scale, for reading slides
+// region by region at a reduced resolution
+pub fn rasterize_region(polygons: &[Polygon],
+                        classes: &[(String, u8)],
+                        (x, y, width, height): (u32, u32, u32, u32),
+                        scale: (f64, f64))
+                        -> GrayImage {
+    let mut mask = GrayImage::new(width, height);
+    let transform = |polygon: &Polygon| {
+        Polygon {
+            class: None,
+            rings: polygon.rings
+                .iter()
+                .map(|ring| {
+                    ring.iter()
+                        .map(|&(px, py)| (px * scale.0 - x as f64, py * scale.1 - y as f64))
+                        .collect()
+                })
+                .collect(),
+        }
+    };
+    if classes.is_empty() {
+        for polygon in polygons {
+            fill(&mut mask, &transform(polygon), 255);
+        }
+    }
+    for &(ref class, value) in classes {
+        for polygon in polygons {
+            let matches = match polygon.class {
+                Some(ref name) => name.eq_ignore_ascii_case(class),
+                None => false,
+            };
+            if matches {
+                fill(&mut mask, &transform(polygon), value);
+            }
+        }
+    }
+    mask
+}
+
+// Even-odd scanline fill, which also leaves the holes out
+fn fill(mask: &mut GrayImage, polygon: &Polygon, value: u8) {
+    let (width, height) = mask.dimensions();
+    let points = polygon.rings.iter().flat_map(|ring| ring.iter());
+    let (min_y, max_y) = points.fold((::std::f64::MAX, ::std::f64::MIN),
+                                     |(min, max), &(_, y)| (min.min(y), max.max(y)));
+    if min_y > max_y || max_y < 0.0 {
+        return;
+    }
+    let first_row = (min_y - 0.5).ceil().max(0.0).min(height as f64) as u32;
+    let end_row = (max_y - 0.5).ceil().max(0.0).min(height as f64) as u32;
+
+    for y in first_row..end_row {
+        let cy = y as f64 + 0.5;
+        let mut crossings = vec![];
+        for ring in &polygon.rings {
+            for (i, &(ax, ay)) in ring.iter().enumerate() {
+                let (bx, by) = ring[(i + 1) % ring.len()];
+                if (ay <= cy) != (by <= cy) {
+                    crossings.push(ax + (cy - ay) * (bx - ax) / (by - ay));
+                }
+            }
+        }
+        crossings.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        for span in crossings.chunks(2).filter(|span| span.len() == 2) {
+            let x0 = (span[0] - 0.5).ceil().max(0.0).min(width as f64) as u32;
+            let x1 = (span[1] - 0.5).ceil().max(0.0).min(width as f64) as u32;
+            for x in x0..x1 {
+                mask.put_pixel(x, y, Luma { data: [value] });
+            }
+        }
+    }
+}
+
+fn invalid(path: &Path, msg: &str) -> AnsError {
+    AnsError::Annotation(format!("{:?}: {}", path, msg))
+}
+
+// A FeatureCollection, a bare list of features as written by older QuPath versions, a single
+// Feature or a geometry. Only Polygon and MultiPolygon geometries are read, the class is the
+// QuPath classification name or the name property.
+fn read_geojson(path: &Path, text: &str) -> AnsResult<Vec<Polygon>> {
+    let json = try!(Json::parse(text).map_err(|e| invalid(path, &e)));
+    let features = match json.get("features").and_then(|f| f.as_array()) {
+        Some(features) => features.iter().collect::<Vec<_>>(),
+        None => {
+            match json.as_array() {
+                Some(features) => features.iter().collect(),
+                None => vec![&json],
+            }
+        }
+    };
+
+    let mut polygons = vec![];
+    for feature in features {
+        let geometry = feature.get("geometry").unwrap_or(feature);
+        let class = feature.get("properties").and_then(|p| {
+            let classification = p.get("classification");
+            classification.and_then(|c| c.get("name"))
+                .or(classification)
+                .or(p.get("name"))
+                .and_then(|name| name.as_str())
+                .map(String::from)
+        });
+        let coordinates = match geometry.get("coordinates") {
+            Some(coordinates) => coordinates,
+            None => continue,
+        };
+        let shapes = match geometry.get("type").and_then(|t| t.as_str()) {
+            Some("Polygon") => vec![coordinates],
+            Some("MultiPolygon") => {
+                try!(coordinates.as_array()
+                        .ok_or_else(|| invalid(path, "MultiPolygon coordinates are not a list")))
+                    .iter()
+                    .collect()
+            }
+            _ => continue,
+        };
+        for shape in shapes {
+            let rings = try!(shape.as_array()
+                .ok_or_else(|| invalid(path, "Polygon coordinates are not a list of rings")));
+            let mut polygon = Polygon {
+                class: class.clone(),
+                rings: vec![],
+            };
+            for ring in rings {
+                polygon.rings.push(try!(geojson_ring(path, ring)));
+            }
+            polygons.push(polygon);
+        }
+    }
+    Ok(polygons)
+}
+
+fn geojson_ring(path: &Path, ring: &Json) -> AnsResult<Vec<(f64, f64)>> {
+    let positions = try!(ring.as_array().ok_or_else(|| invalid(path, "ring is not a list")));
+    let mut points = vec![];
+    for position in positions {
+        let point = position.as_array().and_then(|p| {
+            match (p.get(0).and_then(|x| x.as_f64()), p.get(1).and_then(|y| y.as_f64())) {
+                (Some(x), Some(y)) => Some((x, y)),
+                _ => None,
+            }
+        });
+        points.push(try!(point.ok_or_else(|| invalid(path, "position is not [x, y]"))));
+    }
+    Ok(points)
+}
+
+// <Annotation Type="Polygon" PartOfGroup="..."> elements with <Coordinate Order X Y> children,
+// the group is the class. Dots and point sets have no area and are left out.
+fn read_asap(path: &Path, text: &str) -> AnsResult<Vec<Polygon>> {
+    let parser = EventReader::new(BufReader::new(text.as_bytes()));
+    let mut polygons = vec![];
+    let mut current: Option<(Option<String>, Vec<(u32, f64, f64)>)> = None;
+
+    for event in parser {
+        match event {
+            Ok(XmlEvent::StartElement { name, attributes, .. }) => {
+                let attribute = |key: &str| {
+                    attributes.iter()
+                        .find(|a| a.name.local_name == key)
+                        .map(|a| a.value.clone())
+                };
+                match name.local_name.as_ref() {
+                    "Annotation" => {
+                        let area = match attribute("Type") {
+                            Some(ref t) => t != "Dot" && t != "PointSet",
+                            None => true,
+                        };
+                        current = if area {
+                            Some((attribute("PartOfGroup"), vec![]))
+                        } else {
+                            None
+                        };
+                    }
+                    "Coordinate" => {
+                        if let Some((_, ref mut coordinates)) = current {
+                            let number = |key: &str| {
+                                attribute(key)
+                                    .and_then(|v| v.trim().replace(',', ".").parse::<f64>().ok())
+                                    .and_then(|v| if v.is_finite() { Some(v) } else { None })
+                            };
+                            let order = number("Order").unwrap_or(coordinates.len() as f64);
+                            match (number("X"), number("Y")) {
+                                (Some(x), Some(y)) => coordinates.push((order as u32, x, y)),
+                                _ => {
+                                    return Err(invalid(path, "Coordinate without finite X and Y"))
+                                }
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Ok(XmlEvent::EndElement { name }) => {
+                if name.local_name == "Annotation" {
+                    if let Some((class, mut coordinates)) = current.take() {
+                        coordinates.sort_by_key(|c| c.0);
+                        polygons.push(Polygon {
+                            class: class,
+                            rings: vec![coordinates.iter().map(|&(_, x, y)| (x, y)).collect()],
+                        });
+                    }
+                }
+            }
+            Err(e) => return Err(invalid(path, &e.to_string())),
+            _ => {}
+        }
+    }
+    Ok(polygons)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{rasterize, rasterize_region, read_asap, read_geojson};
+
+    fn count(mask: &::image::GrayImage, value: u8) -> usize {
+        mask.pixels().filter(|p| p.data[0] == value).count()
+    }
+
+    #[test]
+    fn leaves_holes_out() {
+        let text = r#"{"type": "Polygon", "coordinates": [
+                          [[0, 0], [10, 0], [10, 10], [0, 10], [0, 0]],
+                          [[3, 3], [7, 3], [7, 7], [3, 7], [3, 3]]]}"#;
+        let polygons = read_geojson(Path::new("hole.geojson"), text).unwrap();
+        assert_eq!(polygons.len(), 1);
+        assert_eq!(polygons[0].rings.len(), 2);
+
+        let mask = rasterize(&polygons, &[], 12, 12);
+        assert_eq!(count(&mask, 255), 100 - 16);
+        assert_eq!(mask.get_pixel(1, 1).data[0], 255);
+        assert_eq!(mask.get_pixel(5, 5).data[0], 0);
+        assert_eq!(mask.get_pixel(10, 10).data[0], 0);
+    }
+
+    #[test]
+    fn reads_multipolygon_and_qupath_classes() {
+        let text = r#"{"type": "FeatureCollection", "features": [
+            {"type": "Feature",
+             "geometry": {"type": "MultiPolygon", "coordinates": [
+                 [[[0, 0], [2, 0], [2, 2], [0, 2]]],
+                 [[[4, 4], [6, 4], [6, 6], [4, 6]]]]},
+             "properties": {"classification": {"name": "Tumor", "colorRGB": -3670016}}},
+            {"type": "Feature",
+             "geometry": {"type": "Polygon", "coordinates": [[[0, 4], [2, 4], [2, 6], [0, 6]]]},
+             "properties": {"classification": "Stroma"}},
+            {"type": "Feature",
+             "geometry": {"type": "Polygon", "coordinates": [[[4, 0], [6, 0], [6, 2], [4, 2]]]},
+             "properties": {"name": "Necrosis"}},
+            {"type": "Feature",
+             "geometry": {"type": "Point", "coordinates": [1, 1]},
+             "properties": {"name": "Tumor"}}]}"#;
+        let polygons = read_geojson(Path::new("classes.geojson"), text).unwrap();
+        let classes = polygons.iter().map(|p| p.class.clone().unwrap()).collect::<Vec<_>>();
+        assert_eq!(classes, vec!["Tumor", "Tumor", "Stroma", "Necrosis"]);
+
+        let mask = rasterize(&polygons,
+                             &[(String::from("tumor"), 200), (String::from("Stroma"), 100)],
+                             8,
+                             8);
+        assert_eq!(count(&mask, 200), 8);
+        assert_eq!(count(&mask, 100), 4);
+        assert_eq!(mask.get_pixel(5, 1).data[0], 0);
+    }
+
+    #[test]
+    fn reads_asap_in_coordinate_order() {
+        let text = r#"<?xml version="1.0"?>
+            <ASAP_Annotations><Annotations>
+              <Annotation Name="a" Type="Polygon" PartOfGroup="Tumor"><Coordinates>
+                <Coordinate Order="2" X="4,0" Y="4" />
+                <Coordinate Order="0" X="0" Y="0" />
+                <Coordinate Order="3" X="0" Y="4" />
+                <Coordinate Order="1" X="4" Y="0" />
+              </Coordinates></Annotation>
+              <Annotation Name="b" Type="Dot" PartOfGroup="Tumor"><Coordinates>
+                <Coordinate Order="0" X="1" Y="1" />
+              </Coordinates></Annotation>
+            </Annotations></ASAP_Annotations>"#;
+        let polygons = read_asap(Path::new("a.xml"), text).unwrap();
+        assert_eq!(polygons.len(), 1);
+        assert_eq!(polygons[0].class, Some(String::from("Tumor")));
+        assert_eq!(polygons[0].rings[0],
+                   vec![(0.0, 0.0), (4.0, 0.0), (4.0, 4.0), (0.0, 4.0)]);
+        assert_eq!(count(&rasterize(&polygons, &[], 6, 6), 255), 16);
+    }
+
+    #[test]
+    fn scales_region() {
+        let text = r#"{"type": "Polygon", "coordinates": [[[0, 0], [8, 0], [8, 8], [0, 8]]]}"#;
+        let polygons = read_geojson(Path::new("region.geojson"), text).unwrap();
+        let mask = rasterize_region(&polygons, &[], (2, 2, 4, 4), (0.5, 0.5));
+        assert_eq!(count(&mask, 255), 4);
+        assert_eq!(mask.get_pixel(1, 1).data[0], 255);
+        assert_eq!(mask.get_pixel(2, 2).data[0], 0);
+    }
+
+    #[test]
+    fn rejects_non_finite_coordinates() {
+        let text = r#"{"type": "Polygon", "coordinates": [[[0, 0], [1e999, 0], [0, 5]]]}"#;
+        assert!(read_geojson(Path::new("inf.geojson"), text).is_err());
+
+        for x in &["1e999", "NaN", "inf"] {
+            let text = format!("<ASAP_Annotations><Annotations><Annotation Type=\"Polygon\">\
+                                <Coordinates><Coordinate Order=\"0\" X=\"{}\" Y=\"0\" />\
+                                </Coordinates></Annotation></Annotations></ASAP_Annotations>",
+                               x);
+            assert!(read_asap(Path::new("inf.xml"), &text).is_err(), "{}", x);
+        }
+    }
+}
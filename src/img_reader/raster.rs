@@ -0,0 +1,216 @@
+use image::{DynamicImage, ImageBuffer};
+
+// Interleaved samples of an image with any number of channels and 8 or 16 bits per sample, for
+// sources which don't fit into a DynamicImage. 8 bit samples are kept in the lower byte.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Raster {
+    width: u32,
+    height: u32,
+    channels: usize,
+    bits: u8,
+    data: Vec<u16>,
+}
+
+impl Raster {
+    // Black image
+    pub fn new(width: u32, height: u32, channels: usize, bits: u8) -> Raster {
+        Raster {
+            width: width,
+            height: height,
+            channels: channels,
+            bits: bits,
+            data: vec![0; width as usize * height as usize * channels],
+        }
+    }
+
+    pub fn from_raw(width: u32,
+                    height: u32,
+                    channels: usize,
+                    bits: u8,
+                    data: Vec<u16>)
+                    -> Option<Raster> {
+        if data.len() != width as usize * height as usize * channels {
+            return None;
+        }
+        Some(Raster {
+            width: width,
+            height: height,
+            channels: channels,
+            bits: bits,
+            data: data,
+        })
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.bits
+    }
+
+    pub fn max_value(&self) -> u16 {
+        if self.bits >= 16 {
+            u16::max_value()
+        } else {
+            (1 << self.bits) - 1
+        }
+    }
+
+    pub fn as_raw(&self) -> &Vec<u16> {
+        &self.data
+    }
+
+    // Everything a DynamicImage can't hold without losing samples
+    pub fn is_deep(&self) -> bool {
+        self.bits > 8 || self.channels > 4
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> &[u16] {
+        let i = (y as usize * self.width as usize + x as usize) * self.channels;
+        &self.data[i..i + self.channels]
+    }
+
+    pub fn pixel_mut(&mut self, x: u32, y: u32) -> &mut [u16] {
+        let i = (y as usize * self.width as usize + x as usize) * self.channels;
+        &mut self.data[i..i + self.channels]
+    }
+
+    // The same image for 8 bit sources with up to four channels. Deep images are reduced to
+    // their first channel, or the first three from three channels on, scaled by the largest
+    // value of the bit depth so every tile of a source keeps the same brightness.
+    pub fn to_image(&self) -> DynamicImage {
+        let (width, height) = (self.width, self.height);
+        if !self.is_deep() {
+            let samples = self.data.iter().map(|&v| v as u8).collect::<Vec<_>>();
+            let image = match self.channels {
+                1 => ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageLuma8),
+                2 => ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageLumaA8),
+                3 => ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgb8),
+                _ => ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgba8),
+            };
+            if let Some(image) = image {
+                return image;
+            }
+        }
+
+        let shown = if self.channels >= 3 { 3 } else { 1 };
+        let max = (self.max_value() as u32).max(1);
+        let mut samples = Vec::with_capacity(width as usize * height as usize * shown);
+        for pixel in self.data.chunks(self.channels) {
+            for c in 0..shown {
+                let value = (pixel[c] as u32).min(max) * 255 + max / 2;
+                samples.push((value / max) as u8);
+            }
+        }
+        if shown == 3 {
+            DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, samples).unwrap())
+        } else {
+            DynamicImage::ImageLuma8(ImageBuffer::from_raw(width, height, samples).unwrap())
+        }
+    }
+
+    pub fn rotate90(&self) -> Raster {
+        let mut rotated = Raster::new(self.height, self.width, self.channels, self.bits);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                rotated.pixel_mut(self.height - 1 - y, x).copy_from_slice(self.pixel(x, y));
+            }
+        }
+        rotated
+    }
+
+    pub fn rotate180(&self) -> Raster {
+        let mut rotated = Raster::new(self.width, self.height, self.channels, self.bits);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                rotated.pixel_mut(self.width - 1 - x, self.height - 1 - y)
+                    .copy_from_slice(self.pixel(x, y));
+            }
+        }
+        rotated
+    }
+
+    pub fn rotate270(&self) -> Raster {
+        let mut rotated = Raster::new(self.height, self.width, self.channels, self.bits);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                rotated.pixel_mut(y, self.width - 1 - x).copy_from_slice(self.pixel(x, y));
+            }
+        }
+        rotated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GenericImage};
+
+    use super::Raster;
+
+    // 3 x 2 raster whose first channel holds the pixel index
+    fn raster(channels: usize, bits: u8) -> Raster {
+        let data = (0..6u16)
+            .flat_map(|i| (0..channels).map(move |c| if c == 0 { i } else { 1000 * c as u16 }))
+            .collect();
+        Raster::from_raw(3, 2, channels, bits, data).unwrap()
+    }
+
+    fn first_channel(raster: &Raster) -> Vec<u16> {
+        let (width, height) = raster.dimensions();
+        (0..height).flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| raster.pixel(x, y)[0])
+            .collect()
+    }
+
+    #[test]
+    fn checks_the_sample_count() {
+        assert!(Raster::from_raw(3, 2, 2, 8, vec![0; 11]).is_none());
+        assert_eq!(Raster::new(3, 2, 5, 16).as_raw().len(), 30);
+        assert_eq!((Raster::new(1, 1, 1, 12).max_value(), Raster::new(1, 1, 1, 16).max_value()),
+                   (4095, 65535));
+    }
+
+    #[test]
+    fn views_8_bit_samples_as_they_are() {
+        let gray_alpha = Raster::from_raw(2, 1, 2, 8, vec![10, 255, 20, 128]).unwrap();
+        assert!(!gray_alpha.is_deep());
+        match gray_alpha.to_image() {
+            DynamicImage::ImageLumaA8(image) => {
+                assert_eq!(image.into_raw(), vec![10, 255, 20, 128])
+            }
+            _ => panic!("expected a gray and alpha image"),
+        }
+        let rgba = Raster::from_raw(1, 1, 4, 8, vec![1, 2, 3, 4]).unwrap().to_image();
+        assert_eq!(rgba.raw_pixels(), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn scales_deep_samples_to_8_bits() {
+        let deep = Raster::from_raw(3, 1, 1, 16, vec![0, 32768, 65535]).unwrap();
+        assert!(deep.is_deep());
+        assert_eq!(deep.to_image().raw_pixels(), vec![0, 128, 255]);
+
+        // The first three of five channels are shown
+        let channels = Raster::from_raw(1, 1, 5, 12, vec![4095, 0, 2048, 7, 7]).unwrap();
+        let image = channels.to_image();
+        assert_eq!(image.dimensions(), (1, 1));
+        assert_eq!(image.raw_pixels(), vec![255, 0, 128]);
+    }
+
+    #[test]
+    fn rotates_every_channel() {
+        let raster = raster(5, 16);
+        let rotated = raster.rotate90();
+        assert_eq!(rotated.dimensions(), (2, 3));
+        assert_eq!(first_channel(&rotated), vec![3, 0, 4, 1, 5, 2]);
+        assert_eq!(rotated.pixel(0, 0), &[3, 1000, 2000, 3000, 4000]);
+        assert_eq!(first_channel(&raster.rotate180()), vec![5, 4, 3, 2, 1, 0]);
+        assert_eq!(first_channel(&raster.rotate270()), vec![2, 5, 1, 4, 0, 3]);
+        assert_eq!(raster.rotate90().rotate270(), raster);
+    }
+}
@@ -0,0 +1,129 @@
+use image::{self, DynamicImage, GrayImage, Luma};
+
+use error::AnsResult;
+use super::polygons::{self, Polygon};
+use super::raster::Raster;
+use super::tiff::TiledTiff;
+
+// Labels of a slide may have a lower resolution than the slide, they are scaled to the region
+// which is read
+pub enum SlideLabel {
+    Tiff(TiledTiff),
+    Image(GrayImage),
+    // Polygons in full resolution coordinates and the mask value of every class
+    Polygons(Vec<Polygon>, Vec<(String, u8)>),
+}
+
+// A tiled source image too large to be decoded at once, it is cut region by region
+pub struct Slide {
+    pub real: TiledTiff,
+    pub label: SlideLabel,
+}
+
+impl Slide {
+    pub fn new(real: TiledTiff, label: SlideLabel) -> Slide {
+        Slide {
+            real: real,
+            label: label,
+        }
+    }
+
+    pub fn dimensions(&self, level: usize) -> Option<(u32, u32)> {
+        self.real.dimensions(level)
+    }
+
+    // Gray and alpha channels are kept, deep slides are returned as 8 bit view and samples
+    pub fn read_real(&self,
+                     level: usize,
+                     x: u32,
+                     y: u32,
+                     width: u32,
+                     height: u32)
+                     -> AnsResult<(DynamicImage, Option<Raster>)> {
+        let region = try!(self.real.read_raster(level, x, y, width, height));
+        if region.is_deep() {
+            Ok((region.to_image(), Some(region)))
+        } else {
+            Ok((region.to_image(), None))
+        }
+    }
+
+    // Nearest neighbour scaled label of a region at the given level
+    pub fn read_mask(&self,
+                     level: usize,
+                     x: u32,
+                     y: u32,
+                     width: u32,
+                     height: u32)
+                     -> AnsResult<DynamicImage> {
+        let full = self.real.dimensions(0).unwrap_or((1, 1));
+        let (level_width, level_height) = self.real.dimensions(level).unwrap_or(full);
+        let factor = |(label_width, label_height): (u32, u32)| {
+            (label_width as f64 / level_width as f64, label_height as f64 / level_height as f64)
+        };
+        let region = (x, y, width, height);
+        let mask = match self.label {
+            SlideLabel::Polygons(ref polygons, ref classes) => {
+                let (fx, fy) = factor(full);
+                polygons::rasterize_region(polygons, classes, region, (1.0 / fx, 1.0 / fy))
+            }
+            SlideLabel::Image(ref label) => self.scale_mask(label, level, x, y, width, height),
+            SlideLabel::Tiff(ref label) => {
+                // The smallest label level which still has the resolution of the slide level
+                let label_level = (0..label.get_num_of_levels())
+                    .rev()
+                    .find(|&l| label.dimensions(l).map_or(false, |d| d.0 >= level_width))
+                    .unwrap_or(0);
+                let (fx, fy) = factor(label.dimensions(label_level).unwrap_or(full));
+                let lx = (x as f64 * fx).floor() as u32;
+                let ly = (y as f64 * fy).floor() as u32;
+                let lw = ((x + width) as f64 * fx).ceil() as u32 - lx;
+                let lh = ((y + height) as f64 * fy).ceil() as u32 - ly;
+                let read = try!(label.read_region(label_level, lx, ly, lw, lh)).to_luma();
+                scale_region(&read, (lx, ly), (fx, fy), region)
+            }
+        };
+        Ok(DynamicImage::ImageLuma8(mask))
+    }
+
+    // Nearest neighbour scaled region at the given level of a mask which covers the whole slide
+    // at any resolution
+    pub fn scale_mask(&self,
+                      mask: &GrayImage,
+                      level: usize,
+                      x: u32,
+                      y: u32,
+                      width: u32,
+                      height: u32)
+                      -> GrayImage {
+        let full = self.real.dimensions(0).unwrap_or((1, 1));
+        let (level_width, level_height) = self.real.dimensions(level).unwrap_or(full);
+        let (mask_width, mask_height) = mask.dimensions();
+        let factor = (mask_width as f64 / level_width as f64,
+                      mask_height as f64 / level_height as f64);
+        scale_region(mask, (0, 0), factor, (x, y, width, height))
+    }
+}
+
+// The region x, y, width, height of a slide level, taken from a label which is scaled by factor
+// relative to the level and starts at origin. Pixels outside of the label are 0.
+fn scale_region(label: &GrayImage,
+                origin: (u32, u32),
+                factor: (f64, f64),
+                (x, y, width, height): (u32, u32, u32, u32))
+                -> GrayImage {
+    let (label_width, label_height) = label.dimensions();
+    if origin == (0, 0) && factor == (1.0, 1.0) && (x, y) == (0, 0) &&
+       (width, height) == (label_width, label_height) {
+        return label.clone();
+    }
+    image::ImageBuffer::from_fn(width, height, |i, j| {
+        let lx = ((x + i) as f64 + 0.5) * factor.0 - origin.0 as f64;
+        let ly = ((y + j) as f64 + 0.5) * factor.1 - origin.1 as f64;
+        if lx >= 0.0 && ly >= 0.0 && lx < label_width as f64 && ly < label_height as f64 {
+            *label.get_pixel(lx as u32, ly as u32)
+        } else {
+            Luma { data: [0] }
+        }
+    })
+}
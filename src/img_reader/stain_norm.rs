@@ -0,0 +1,453 @@
+use std::path::PathBuf;
+use std::cmp::Ordering;
+use image;
+use image::{RgbImage, ImageBuffer, Rgb};
+
+use error::{AnsError, AnsResult};
+
+// Transmitted light intensity and the optical density cut-off used by Macenko et al.
+const MACENKO_IO: f32 = 240.0;
+const MACENKO_ALPHA: f32 = 1.0;
+const MACENKO_BETA: f32 = 0.15;
+
+#[derive(Clone)]
+pub enum StainNormalization {
+    // Matches mean and standard deviation of every channel in the lαβ color space
+    Reinhard(PathBuf),
+    // Estimates the H&E stain vectors and rescales the stain concentrations
+    Macenko(PathBuf),
+}
+
+#[derive(Clone, Debug)]
+pub enum StainParams {
+    Reinhard {
+        mean: [f32; 3],
+        std: [f32; 3],
+    },
+    Macenko {
+        stain_matrix: [[f32; 3]; 2],
+        max_concentration: [f32; 2],
+    },
+}
+
+pub struct StainNormalizer {
+    method: StainNormalization,
+    target: StainParams,
+}
+
+impl StainNormalizer {
+    pub fn new(method: StainNormalization) -> AnsResult<StainNormalizer> {
+        let reference = {
+            let path = match method {
+                StainNormalization::Reinhard(ref p) |
+                StainNormalization::Macenko(ref p) => p,
+            };
+            match image::open(path) {
+                Ok(image) => image.to_rgb(),
+                Err(e) => return Err(AnsError::ImageRead(path.clone(), e)),
+            }
+        };
+        let target = try!(fit(&method, &reference));
+
+        Ok(StainNormalizer {
+            method: method,
+            target: target,
+        })
+    }
+
+    pub fn get_target(&self) -> &StainParams {
+        &self.target
+    }
+
+    pub fn fit(&self, image: &RgbImage) -> AnsResult<StainParams> {
+        fit(&self.method, image)
+    }
+
+    pub fn normalize(&self, image: &RgbImage, params: &StainParams) -> RgbImage {
+        match (params, &self.target) {
+            (&StainParams::Reinhard { mean: ref src_mean, std: ref src_std },
+             &StainParams::Reinhard { mean: ref dst_mean, std: ref dst_std }) => {
+                normalize_reinhard(image, (src_mean, src_std), (dst_mean, dst_std))
+            }
+            (&StainParams::Macenko { stain_matrix: ref src_he, max_concentration: ref src_max },
+             &StainParams::Macenko { stain_matrix: ref dst_he, max_concentration: ref dst_max }) => {
+                normalize_macenko(image, (src_he, src_max), (dst_he, dst_max))
+            }
+            _ => panic!("Tried to normalize an image with parameters of another method"),
+        }
+    }
+}
+
+fn fit(method: &StainNormalization, image: &RgbImage) -> AnsResult<StainParams> {
+    match *method {
+        StainNormalization::Reinhard(_) => Ok(fit_reinhard(image)),
+        StainNormalization::Macenko(_) => fit_macenko(image),
+    }
+}
+
+fn fit_reinhard(image: &RgbImage) -> StainParams {
+    let pixels = image.pixels().map(|p| rgb_to_lab(p.data)).collect::<Vec<_>>();
+    let n = pixels.len().max(1) as f32;
+
+    let mut mean = [0f32; 3];
+    for p in &pixels {
+        for c in 0..3 {
+            mean[c] += p[c] / n;
+        }
+    }
+    let mut std = [0f32; 3];
+    for p in &pixels {
+        for c in 0..3 {
+            std[c] += (p[c] - mean[c]).powi(2) / n;
+        }
+    }
+    for c in 0..3 {
+        std[c] = std[c].sqrt();
+    }
+
+    StainParams::Reinhard {
+        mean: mean,
+        std: std,
+    }
+}
+
+fn normalize_reinhard(image: &RgbImage,
+                      src: (&[f32; 3], &[f32; 3]),
+                      dst: (&[f32; 3], &[f32; 3]))
+                      -> RgbImage {
+    let (width, height) = image.dimensions();
+    let mut buffer = ImageBuffer::<Rgb<u8>, Vec<u8>>::new(width, height);
+
+    for (x, y, pixel) in image.enumerate_pixels() {
+        let mut lab = rgb_to_lab(pixel.data);
+        for c in 0..3 {
+            let scale = if src.1[c] > 0.0 {
+                dst.1[c] / src.1[c]
+            } else {
+                1.0
+            };
+            lab[c] = (lab[c] - src.0[c]) * scale + dst.0[c];
+        }
+        buffer.put_pixel(x, y, Rgb { data: lab_to_rgb(lab) });
+    }
+    buffer
+}
+
+// Conversion into the lαβ space described by Reinhard et al. (2001)
+fn rgb_to_lab(rgb: [u8; 3]) -> [f32; 3] {
+    let (r, g, b) = (rgb[0] as f32, rgb[1] as f32, rgb[2] as f32);
+    let lms = [0.3811 * r + 0.5783 * g + 0.0402 * b,
+               0.1967 * r + 0.7244 * g + 0.0782 * b,
+               0.0241 * r + 0.1288 * g + 0.8444 * b];
+    let log = [lms[0].max(1.0).log10(), lms[1].max(1.0).log10(), lms[2].max(1.0).log10()];
+
+    [(log[0] + log[1] + log[2]) / 3f32.sqrt(),
+     (log[0] + log[1] - 2.0 * log[2]) / 6f32.sqrt(),
+     (log[0] - log[1]) / 2f32.sqrt()]
+}
+
+fn lab_to_rgb(lab: [f32; 3]) -> [u8; 3] {
+    let (l, a, b) = (lab[0] / 3f32.sqrt(), lab[1] / 6f32.sqrt(), lab[2] / 2f32.sqrt());
+    let lms = [10f32.powf(l + a + b), 10f32.powf(l + a - b), 10f32.powf(l - 2.0 * a)];
+
+    [clamp_u8(4.4679 * lms[0] - 3.5873 * lms[1] + 0.1193 * lms[2]),
+     clamp_u8(-1.2186 * lms[0] + 2.3809 * lms[1] - 0.1624 * lms[2]),
+     clamp_u8(0.0497 * lms[0] - 0.2439 * lms[1] + 1.2045 * lms[2])]
+}
+
+fn fit_macenko(image: &RgbImage) -> AnsResult<StainParams> {
+    let od = image.pixels().map(|p| optical_density(p.data)).collect::<Vec<_>>();
+    // Transparent pixels carry no stain information
+    let tissue = od.iter()
+        .filter(|p| p.iter().all(|&c| c >= MACENKO_BETA))
+        .cloned()
+        .collect::<Vec<_>>();
+    if tissue.len() < 2 {
+        return Err(AnsError::StainFit(String::from("image contains no stained tissue")));
+    }
+
+    // Plane spanned by the two largest eigenvectors of the OD covariance
+    let (_, eigenvectors) = symmetric_eigen(covariance(&tissue));
+    let plane = [positive(eigenvectors[1]), positive(eigenvectors[0])];
+
+    let mut angles = tissue.iter()
+        .map(|p| dot(p, &plane[1]).atan2(dot(p, &plane[0])))
+        .collect::<Vec<_>>();
+    let min_phi = percentile(&mut angles, MACENKO_ALPHA);
+    let max_phi = percentile(&mut angles, 100.0 - MACENKO_ALPHA);
+
+    let v_min = combine(&plane, min_phi.cos(), min_phi.sin());
+    let v_max = combine(&plane, max_phi.cos(), max_phi.sin());
+    // Hematoxylin is expected as the first stain vector
+    let stain_matrix = if v_min[0] > v_max[0] {
+        [v_min, v_max]
+    } else {
+        [v_max, v_min]
+    };
+
+    let concentrations = od.iter().map(|p| concentration(&stain_matrix, p)).collect::<Vec<_>>();
+    let mut h = concentrations.iter().map(|c| c[0]).collect::<Vec<_>>();
+    let mut e = concentrations.iter().map(|c| c[1]).collect::<Vec<_>>();
+
+    Ok(StainParams::Macenko {
+        stain_matrix: stain_matrix,
+        max_concentration: [percentile(&mut h, 99.0), percentile(&mut e, 99.0)],
+    })
+}
+
+fn normalize_macenko(image: &RgbImage,
+                     src: (&[[f32; 3]; 2], &[f32; 2]),
+                     dst: (&[[f32; 3]; 2], &[f32; 2]))
+                     -> RgbImage {
+    let (width, height) = image.dimensions();
+    let mut buffer = ImageBuffer::<Rgb<u8>, Vec<u8>>::new(width, height);
+    let scale = [safe_div(dst.1[0], src.1[0]), safe_div(dst.1[1], src.1[1])];
+
+    for (x, y, pixel) in image.enumerate_pixels() {
+        let c = concentration(src.0, &optical_density(pixel.data));
+        let mut rgb = [0u8; 3];
+        for ch in 0..3 {
+            let od = dst.0[0][ch] * c[0] * scale[0] + dst.0[1][ch] * c[1] * scale[1];
+            rgb[ch] = clamp_u8(MACENKO_IO * (-od).exp());
+        }
+        buffer.put_pixel(x, y, Rgb { data: rgb });
+    }
+    buffer
+}
+
+fn optical_density(rgb: [u8; 3]) -> [f32; 3] {
+    let od = |c: u8| -((c as f32 + 1.0) / MACENKO_IO).ln();
+    [od(rgb[0]), od(rgb[1]), od(rgb[2])]
+}
+
+// Least squares solution of stain_matrix^T * c = od
+fn concentration(stain_matrix: &[[f32; 3]; 2], od: &[f32; 3]) -> [f32; 2] {
+    let (h, e) = (&stain_matrix[0], &stain_matrix[1]);
+    let (hh, he, ee) = (dot(h, h), dot(h, e), dot(e, e));
+    let det = hh * ee - he * he;
+    if det.abs() < 1e-9 {
+        return [0.0, 0.0];
+    }
+    let (ho, eo) = (dot(h, od), dot(e, od));
+    [(ee * ho - he * eo) / det, (hh * eo - he * ho) / det]
+}
+
+fn covariance(data: &[[f32; 3]]) -> [[f32; 3]; 3] {
+    let n = data.len() as f32;
+    let mut mean = [0f32; 3];
+    for p in data {
+        for c in 0..3 {
+            mean[c] += p[c] / n;
+        }
+    }
+    let mut cov = [[0f32; 3]; 3];
+    for p in data {
+        for i in 0..3 {
+            for j in 0..3 {
+                cov[i][j] += (p[i] - mean[i]) * (p[j] - mean[j]) / (n - 1.0);
+            }
+        }
+    }
+    cov
+}
+
+// Jacobi eigenvalue algorithm, eigenvectors are returned sorted by descending eigenvalue
+fn symmetric_eigen(mut a: [[f32; 3]; 3]) -> ([f32; 3], [[f32; 3]; 3]) {
+    let mut v = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+    for _ in 0..50 {
+        let off = a[0][1].abs() + a[0][2].abs() + a[1][2].abs();
+        if off < 1e-10 {
+            break;
+        }
+        for &(p, q) in &[(0, 1), (0, 2), (1, 2)] {
+            if a[p][q].abs() < 1e-12 {
+                continue;
+            }
+            let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+            let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+            let c = 1.0 / (t * t + 1.0).sqrt();
+            let s = t * c;
+
+            for k in 0..3 {
+                let (akp, akq) = (a[k][p], a[k][q]);
+                a[k][p] = c * akp - s * akq;
+                a[k][q] = s * akp + c * akq;
+            }
+            for k in 0..3 {
+                let (apk, aqk) = (a[p][k], a[q][k]);
+                a[p][k] = c * apk - s * aqk;
+                a[q][k] = s * apk + c * aqk;
+            }
+            for k in 0..3 {
+                let (vkp, vkq) = (v[k][p], v[k][q]);
+                v[k][p] = c * vkp - s * vkq;
+                v[k][q] = s * vkp + c * vkq;
+            }
+        }
+    }
+
+    let mut order = [0usize, 1, 2];
+    order.sort_by(|&i, &j| a[j][j].partial_cmp(&a[i][i]).unwrap_or(Ordering::Equal));
+
+    let mut values = [0f32; 3];
+    let mut vectors = [[0f32; 3]; 3];
+    for (n, &i) in order.iter().enumerate() {
+        values[n] = a[i][i];
+        vectors[n] = [v[0][i], v[1][i], v[2][i]];
+    }
+    (values, vectors)
+}
+
+fn percentile(data: &mut Vec<f32>, p: f32) -> f32 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    data.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    let index = ((p / 100.0) * (data.len() - 1) as f32).round() as usize;
+    data[index.min(data.len() - 1)]
+}
+
+fn positive(v: [f32; 3]) -> [f32; 3] {
+    if v[0] + v[1] + v[2] < 0.0 {
+        [-v[0], -v[1], -v[2]]
+    } else {
+        v
+    }
+}
+
+fn combine(plane: &[[f32; 3]; 2], a: f32, b: f32) -> [f32; 3] {
+    let v = [plane[0][0] * a + plane[1][0] * b,
+             plane[0][1] * a + plane[1][1] * b,
+             plane[0][2] * a + plane[1][2] * b];
+    let norm = dot(&v, &v).sqrt();
+    [v[0] / norm, v[1] / norm, v[2] / norm]
+}
+
+fn dot(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn safe_div(a: f32, b: f32) -> f32 {
+    if b.abs() > 1e-9 { a / b } else { 1.0 }
+}
+
+fn clamp_u8(v: f32) -> u8 {
+    v.max(0.0).min(255.0).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgb, RgbImage};
+
+    use error::AnsError;
+    use super::{dot, fit_macenko, fit_reinhard, lab_to_rgb, normalize_macenko,
+                normalize_reinhard, rgb_to_lab, symmetric_eigen, StainParams, MACENKO_IO};
+
+    fn unit(v: [f32; 3]) -> [f32; 3] {
+        let norm = dot(&v, &v).sqrt();
+        [v[0] / norm, v[1] / norm, v[2] / norm]
+    }
+
+    // Every pixel mixes the two stains with its own concentrations
+    fn stained(he: &[[f32; 3]; 2], scale: f32) -> RgbImage {
+        ImageBuffer::from_fn(40, 40, |x, y| {
+            let c = [(0.3 + x as f32 * 0.03) * scale, (0.3 + y as f32 * 0.03) * scale];
+            let mut rgb = [0u8; 3];
+            for ch in 0..3 {
+                let od = he[0][ch] * c[0] + he[1][ch] * c[1];
+                rgb[ch] = (MACENKO_IO * (-od).exp() - 1.0).round() as u8;
+            }
+            Rgb { data: rgb }
+        })
+    }
+
+    fn max_difference(a: &RgbImage, b: &RgbImage) -> u8 {
+        a.pixels()
+            .zip(b.pixels())
+            .flat_map(|(p, q)| (0..3).map(move |c| (p.data[c] as i32 - q.data[c] as i32).abs()))
+            .max()
+            .unwrap_or(0) as u8
+    }
+
+    #[test]
+    fn lab_round_trip() {
+        for &rgb in &[[200u8, 120, 180], [30, 40, 50], [255, 255, 255], [90, 200, 10]] {
+            let back = lab_to_rgb(rgb_to_lab(rgb));
+            for c in 0..3 {
+                assert!((back[c] as i32 - rgb[c] as i32).abs() <= 1, "{:?} {:?}", rgb, back);
+            }
+        }
+    }
+
+    #[test]
+    fn eigenvectors_by_descending_value() {
+        let matrix = [[2.0, 1.0, 0.0], [1.0, 2.0, 0.0], [0.0, 0.0, 5.0]];
+        let (values, vectors) = symmetric_eigen(matrix);
+        let expected = [5.0, 3.0, 1.0];
+        for i in 0..3 {
+            assert!((values[i] - expected[i]).abs() < 1e-4, "{:?}", values);
+        }
+        assert!(dot(&vectors[0], &[0.0, 0.0, 1.0]).abs() > 0.9999);
+        let diagonal = unit([1.0, 1.0, 0.0]);
+        assert!(dot(&vectors[1], &diagonal).abs() > 0.9999);
+    }
+
+    #[test]
+    fn reinhard_matches_target_statistics() {
+        let source = ImageBuffer::from_fn(30, 30, |x, y| {
+            Rgb { data: [(100 + x * 3) as u8, (60 + y * 2) as u8, (150 + (x + y) % 40) as u8] }
+        });
+        let target = ImageBuffer::from_fn(30, 30, |x, y| {
+            Rgb { data: [(180 + y) as u8, (90 + x * 4) as u8, (120 + x) as u8] }
+        });
+        let statistics = |image: &RgbImage| match fit_reinhard(image) {
+            StainParams::Reinhard { mean, std } => (mean, std),
+            _ => unreachable!(),
+        };
+        let (src, dst) = (statistics(&source), statistics(&target));
+        let normalized = normalize_reinhard(&source, (&src.0, &src.1), (&dst.0, &dst.1));
+        let (mean, std) = statistics(&normalized);
+        for c in 0..3 {
+            assert!((mean[c] - dst.0[c]).abs() < 0.01, "{:?} {:?}", mean, dst.0);
+            assert!((std[c] - dst.1[c]).abs() < 0.01, "{:?} {:?}", std, dst.1);
+        }
+        // Normalizing to the own statistics keeps the image
+        assert!(max_difference(&normalize_reinhard(&source, (&src.0, &src.1), (&src.0, &src.1)),
+                               &source) <= 1);
+    }
+
+    #[test]
+    fn macenko_finds_the_stain_vectors() {
+        let he = [unit([0.65, 0.70, 0.29]), unit([0.07, 0.99, 0.11])];
+        let image = stained(&he, 1.0);
+        let (stain_matrix, max_concentration) = match fit_macenko(&image).unwrap() {
+            StainParams::Macenko { stain_matrix, max_concentration } => {
+                (stain_matrix, max_concentration)
+            }
+            _ => unreachable!(),
+        };
+        assert!(dot(&stain_matrix[0], &he[0]) > 0.99, "{:?}", stain_matrix);
+        assert!(dot(&stain_matrix[1], &he[1]) > 0.99, "{:?}", stain_matrix);
+
+        // Own parameters give the image back, a target with twice the concentrations darkens it
+        let same = normalize_macenko(&image,
+                                     (&stain_matrix, &max_concentration),
+                                     (&stain_matrix, &max_concentration));
+        assert!(max_difference(&same, &image) <= 3);
+        let doubled = [max_concentration[0] * 2.0, max_concentration[1] * 2.0];
+        let darker = normalize_macenko(&image,
+                                       (&stain_matrix, &max_concentration),
+                                       (&stain_matrix, &doubled));
+        assert!(max_difference(&darker, &stained(&he, 2.0)) <= 8);
+    }
+
+    #[test]
+    fn macenko_needs_tissue() {
+        let white = ImageBuffer::from_pixel(10, 10, Rgb { data: [255u8, 255, 255] });
+        match fit_macenko(&white) {
+            Err(AnsError::StainFit(_)) => {}
+            other => panic!("{:?}", other.is_ok()),
+        }
+    }
+}
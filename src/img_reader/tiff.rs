@@ -0,0 +1,751 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use image::{self, DynamicImage, ImageFormat};
+use inflate::InflateStream;
+
+use error::{AnsError, AnsResult};
+use super::raster::Raster;
+
+const NEW_SUBFILE_TYPE: u16 = 254;
+const IMAGE_WIDTH: u16 = 256;
+const IMAGE_LENGTH: u16 = 257;
+const BITS_PER_SAMPLE: u16 = 258;
+const COMPRESSION: u16 = 259;
+const PHOTOMETRIC: u16 = 262;
+const STRIP_OFFSETS: u16 = 273;
+const SAMPLES_PER_PIXEL: u16 = 277;
+const ROWS_PER_STRIP: u16 = 278;
+const STRIP_BYTE_COUNTS: u16 = 279;
+const PLANAR_CONFIGURATION: u16 = 284;
+const PREDICTOR: u16 = 317;
+const TILE_WIDTH: u16 = 322;
+const TILE_LENGTH: u16 = 323;
+const TILE_OFFSETS: u16 = 324;
+const TILE_BYTE_COUNTS: u16 = 325;
+const SUB_IFDS: u16 = 330;
+const EXTRA_SAMPLES: u16 = 338;
+const SAMPLE_FORMAT: u16 = 339;
+const JPEG_TABLES: u16 = 347;
+
+// One resolution of a pyramid. Strips are treated as tiles of the full image width.
+#[derive(Clone, Debug)]
+struct Level {
+    width: u32,
+    height: u32,
+    tile_width: u32,
+    tile_height: u32,
+    offsets: Vec<u64>,
+    byte_counts: Vec<u64>,
+    compression: u64,
+    photometric: u64,
+    samples: u32,
+    bits: u32,
+    big_endian: bool,
+    predictor: u64,
+    jpeg_tables: Option<Vec<u8>>,
+    tiled: bool,
+    reduced: bool,
+}
+
+// Tiled or stripped TIFF and BigTIFF files, read region by region without decoding the whole
+// image. Level 0 is the full resolution, the other levels are the reduced resolution images of
+// a pyramid sorted by decreasing size. Images with 8 or 16 bit unsigned samples and any number of
+// channels, compressed with LZW, Deflate or JPEG or not at all are supported.
+#[derive(Clone, Debug)]
+pub struct TiledTiff {
+    path: PathBuf,
+    levels: Vec<Level>,
+}
+
+impl TiledTiff {
+    pub fn open(path: &Path) -> AnsResult<TiledTiff> {
+        let mut file = BufReader::new(try!(File::open(path)));
+        let mut header = [0u8; 16];
+        try!(file.read_exact(&mut header[..8]));
+        let big_endian = match &header[..2] {
+            b"II" => false,
+            b"MM" => true,
+            _ => return Err(invalid(path, "not a TIFF file")),
+        };
+        let mut reader = IfdReader {
+            file: file,
+            big_endian: big_endian,
+            big_tiff: false,
+        };
+        let first = match reader.u16(&header[2..4]) {
+            42 => reader.u32(&header[4..8]) as u64,
+            43 => {
+                try!(reader.file.read_exact(&mut header[8..16]));
+                reader.big_tiff = true;
+                reader.u64(&header[8..16])
+            }
+            _ => return Err(invalid(path, "not a TIFF file")),
+        };
+
+        let mut levels = vec![];
+        let mut pending = vec![];
+        let mut next = first;
+        // The main chain, sub IFDs are read after it
+        while next != 0 || !pending.is_empty() {
+            let offset = if next != 0 { next } else { pending.pop().unwrap() };
+            let (entries, following) = try!(reader.ifd(offset));
+            next = if next != 0 { following } else { 0 };
+            if let Some(sub_ifds) = entries.iter().find(|e| e.0 == SUB_IFDS) {
+                pending.extend(sub_ifds.1.iter().cloned());
+            }
+            levels.push(try!(level(path, &entries, big_endian)));
+            if levels.len() > 1000 {
+                return Err(invalid(path, "too many images"));
+            }
+        }
+
+        // Thumbnails, labels and macro images of slide scanners are stripped or have another
+        // aspect ratio than the full resolution image
+        let full = match levels.first() {
+            Some(full) => full.clone(),
+            None => return Err(invalid(path, "no image")),
+        };
+        let aspect = full.width as f64 / full.height as f64;
+        let mut reduced = levels.into_iter()
+            .skip(1)
+            .filter(|l| {
+                (l.tiled || l.reduced) && l.width < full.width &&
+                ((l.width as f64 / l.height as f64) / aspect - 1.0).abs() < 0.02
+            })
+            .collect::<Vec<_>>();
+        reduced.sort_by(|a, b| b.width.cmp(&a.width));
+        let mut pyramid = vec![full];
+        for level in reduced {
+            if pyramid.last().map_or(true, |l| l.width != level.width) {
+                pyramid.push(level);
+            }
+        }
+
+        Ok(TiledTiff {
+            path: path.to_path_buf(),
+            levels: pyramid,
+        })
+    }
+
+    // Tiled files and files with a pyramid are read as slides, a plain stripped TIFF can be
+    // decoded as a whole
+    pub fn is_tiled(&self) -> bool {
+        self.levels.len() > 1 || self.levels[0].tiled
+    }
+
+    pub fn get_num_of_levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    pub fn dimensions(&self, level: usize) -> Option<(u32, u32)> {
+        self.levels.get(level).map(|l| (l.width, l.height))
+    }
+
+    // 16 bit samples or more than four channels, which read_region can only return reduced
+    pub fn is_deep(&self) -> bool {
+        self.levels[0].bits > 8 || self.levels[0].samples > 4
+    }
+
+    // Deep images are reduced by Raster::to_image
+    pub fn read_region(&self,
+                       level: usize,
+                       x: u32,
+                       y: u32,
+                       width: u32,
+                       height: u32)
+                       -> AnsResult<DynamicImage> {
+        self.read_raster(level, x, y, width, height).map(|raster| raster.to_image())
+    }
+
+    // Pixels outside of the image are black. Only the tiles overlapping the region are decoded.
+    pub fn read_raster(&self,
+                       level: usize,
+                       x: u32,
+                       y: u32,
+                       width: u32,
+                       height: u32)
+                       -> AnsResult<Raster> {
+        let level = match self.levels.get(level) {
+            Some(level) => level,
+            None => return Err(invalid(&self.path, &format!("no pyramid level {}", level))),
+        };
+        let channels = level.samples as usize;
+        if channels == 0 {
+            return Err(invalid(&self.path, "no samples per pixel"));
+        }
+        let mut region = Raster::new(width, height, channels, level.bits as u8);
+        let mut file = try!(File::open(&self.path));
+
+        let tiles_across = (level.width + level.tile_width - 1) / level.tile_width;
+        let x_end = (x + width).min(level.width);
+        let y_end = (y + height).min(level.height);
+        if x < x_end && y < y_end {
+            for row in y / level.tile_height..(y_end - 1) / level.tile_height + 1 {
+                for column in x / level.tile_width..(x_end - 1) / level.tile_width + 1 {
+                    let index = (row * tiles_across + column) as usize;
+                    let tile = try!(self.decode_tile(&mut file, level, index));
+                    let (tx, ty) = (column * level.tile_width, row * level.tile_height);
+                    let tile_stride = level.tile_width as usize * channels;
+                    let rows = tile.len() / tile_stride;
+
+                    let x0 = x.max(tx);
+                    let x1 = x_end.min(tx + level.tile_width);
+                    let y1 = y_end.min(ty + rows as u32);
+                    for py in y.max(ty)..y1 {
+                        let src = (py - ty) as usize * tile_stride;
+                        for px in x0..x1 {
+                            let s = src + (px - tx) as usize * channels;
+                            region.pixel_mut(px - x, py - y)
+                                .copy_from_slice(&tile[s..s + channels]);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(region)
+    }
+
+    // Decoded samples of a tile, the last strip of a stripped image may have fewer rows
+    fn decode_tile(&self, file: &mut File, level: &Level, index: usize) -> AnsResult<Vec<u16>> {
+        let (offset, count) = match (level.offsets.get(index), level.byte_counts.get(index)) {
+            (Some(&offset), Some(&count)) => (offset, count),
+            _ => return Err(invalid(&self.path, &format!("tile {} is missing", index))),
+        };
+        let row_len = level.tile_width as usize * level.samples as usize;
+        let size = row_len * level.tile_height as usize;
+        if count == 0 {
+            return Ok(vec![0; size]);
+        }
+        let mut data = vec![0u8; count as usize];
+        try!(file.seek(SeekFrom::Start(offset)));
+        try!(file.read_exact(&mut data));
+
+        let bytes = match level.compression {
+            1 => data,
+            5 => try!(lzw(&data).map_err(|e| invalid(&self.path, &e))),
+            8 | 32946 => try!(inflate(&data).map_err(|e| invalid(&self.path, &e))),
+            7 => {
+                let samples = try!(self.decode_jpeg(level, data, size));
+                return Ok(samples.into_iter().map(|v| v as u16).collect());
+            }
+            c => return Err(invalid(&self.path, &format!("compression {}", c))),
+        };
+        let (mut samples, max) = if level.bits == 16 {
+            let samples = bytes.chunks(2)
+                .filter(|b| b.len() == 2)
+                .map(|b| {
+                    if level.big_endian {
+                        (b[0] as u16) << 8 | b[1] as u16
+                    } else {
+                        (b[1] as u16) << 8 | b[0] as u16
+                    }
+                })
+                .collect::<Vec<_>>();
+            (samples, 0xffff)
+        } else {
+            (bytes.into_iter().map(|v| v as u16).collect(), 0xff)
+        };
+        if level.predictor == 2 {
+            for row in samples.chunks_mut(row_len) {
+                for i in level.samples as usize..row.len() {
+                    row[i] = row[i].wrapping_add(row[i - level.samples as usize]) & max;
+                }
+            }
+        }
+        match level.photometric {
+            0 => {
+                for v in &mut samples {
+                    *v = max - *v;
+                }
+            }
+            1 | 2 => {}
+            p => return Err(invalid(&self.path, &format!("photometric interpretation {}", p))),
+        }
+        samples.truncate(size);
+        samples.truncate(samples.len() / row_len * row_len);
+        Ok(samples)
+    }
+
+    // Tiles usually share the quantization and Huffman tables in the JPEGTables tag
+    fn decode_jpeg(&self, level: &Level, data: Vec<u8>, size: usize) -> AnsResult<Vec<u8>> {
+        let mut stream = vec![0xff, 0xd8];
+        // An Adobe marker with transform 0 keeps the decoder from converting RGB from YCbCr
+        if level.photometric == 2 {
+            stream.extend_from_slice(&[0xff, 0xee, 0, 14, b'A', b'd', b'o', b'b', b'e', 0, 100,
+                                       0, 0, 0, 0, 0]);
+        }
+        if let Some(ref tables) = level.jpeg_tables {
+            if tables.len() > 4 {
+                stream.extend_from_slice(&tables[2..tables.len() - 2]);
+            }
+        }
+        stream.extend_from_slice(if data.starts_with(&[0xff, 0xd8]) { &data[2..] } else { &data });
+
+        let mut samples = match image::load_from_memory_with_format(&stream, ImageFormat::JPEG) {
+            Ok(DynamicImage::ImageLuma8(tile)) => tile.into_raw(),
+            Ok(DynamicImage::ImageRgb8(tile)) => tile.into_raw(),
+            Ok(_) => return Err(invalid(&self.path, "JPEG tile is neither gray nor RGB")),
+            Err(e) => return Err(AnsError::ImageRead(self.path.clone(), e)),
+        };
+        samples.resize(size, 0);
+        Ok(samples)
+    }
+}
+
+// Uncompressed little endian TIFF with a single strip, which keeps every sample of a deep tile.
+// Three or four channels are written as RGB, others as gray, any further channels as unspecified
+// extra samples.
+pub fn encode(raster: &Raster) -> Vec<u8> {
+    let (width, height) = raster.dimensions();
+    let channels = raster.channels();
+    let bytes = if raster.bits() > 8 { 2 } else { 1 };
+    let color = if channels == 3 || channels == 4 { 3 } else { 1 };
+    let strip_len = raster.as_raw().len() * bytes;
+
+    // Values longer than four bytes follow the IFD
+    let mut entries: Vec<(u16, u16, Vec<u32>)> = vec![];
+    entries.push((IMAGE_WIDTH, 4, vec![width]));
+    entries.push((IMAGE_LENGTH, 4, vec![height]));
+    entries.push((BITS_PER_SAMPLE, 3, vec![raster.bits() as u32; channels]));
+    entries.push((COMPRESSION, 3, vec![1]));
+    entries.push((PHOTOMETRIC, 3, vec![if color == 3 { 2 } else { 1 }]));
+    entries.push((STRIP_OFFSETS, 4, vec![0]));
+    entries.push((SAMPLES_PER_PIXEL, 3, vec![channels as u32]));
+    entries.push((ROWS_PER_STRIP, 4, vec![height]));
+    entries.push((STRIP_BYTE_COUNTS, 4, vec![strip_len as u32]));
+    entries.push((PLANAR_CONFIGURATION, 3, vec![1]));
+    if channels > color {
+        entries.push((EXTRA_SAMPLES, 3, vec![0; channels - color]));
+    }
+    entries.push((SAMPLE_FORMAT, 3, vec![1; channels]));
+
+    let value_len = |&(_, kind, ref values): &(u16, u16, Vec<u32>)| {
+        values.len() * if kind == 3 { 2 } else { 4 }
+    };
+    let ifd_len = 2 + entries.len() * 12 + 4;
+    let extra_len = entries.iter().map(&value_len).filter(|&l| l > 4).fold(0, |sum, l| sum + l);
+    let strip_offset = 8 + ifd_len + extra_len;
+    for entry in &mut entries {
+        if entry.0 == STRIP_OFFSETS {
+            entry.2 = vec![strip_offset as u32];
+        }
+    }
+
+    let mut out = Vec::with_capacity(strip_offset + strip_len);
+    out.extend_from_slice(b"II");
+    push_u16(&mut out, 42);
+    push_u32(&mut out, 8);
+    push_u16(&mut out, entries.len() as u16);
+    let mut extra = vec![];
+    let mut extra_offset = 8 + ifd_len;
+    for entry in &entries {
+        let mut value = vec![];
+        for &v in &entry.2 {
+            if entry.1 == 3 {
+                push_u16(&mut value, v as u16);
+            } else {
+                push_u32(&mut value, v);
+            }
+        }
+        push_u16(&mut out, entry.0);
+        push_u16(&mut out, entry.1);
+        push_u32(&mut out, entry.2.len() as u32);
+        if value.len() > 4 {
+            push_u32(&mut out, extra_offset as u32);
+            extra_offset += value.len();
+            extra.extend_from_slice(&value);
+        } else {
+            value.resize(4, 0);
+            out.extend_from_slice(&value);
+        }
+    }
+    push_u32(&mut out, 0);
+    out.extend_from_slice(&extra);
+    for &v in raster.as_raw() {
+        if bytes == 2 {
+            push_u16(&mut out, v);
+        } else {
+            out.push(v as u8);
+        }
+    }
+    out
+}
+
+fn push_u16(out: &mut Vec<u8>, v: u16) {
+    out.push(v as u8);
+    out.push((v >> 8) as u8);
+}
+
+fn push_u32(out: &mut Vec<u8>, v: u32) {
+    push_u16(out, v as u16);
+    push_u16(out, (v >> 16) as u16);
+}
+
+fn invalid(path: &Path, msg: &str) -> AnsError {
+    AnsError::Unsupported(format!("TIFF {:?}: {}", path, msg))
+}
+
+struct IfdReader {
+    file: BufReader<File>,
+    big_endian: bool,
+    big_tiff: bool,
+}
+
+impl IfdReader {
+    fn u16(&self, b: &[u8]) -> u16 {
+        if self.big_endian {
+            (b[0] as u16) << 8 | b[1] as u16
+        } else {
+            (b[1] as u16) << 8 | b[0] as u16
+        }
+    }
+
+    fn u32(&self, b: &[u8]) -> u32 {
+        if self.big_endian {
+            (self.u16(&b[..2]) as u32) << 16 | self.u16(&b[2..4]) as u32
+        } else {
+            (self.u16(&b[2..4]) as u32) << 16 | self.u16(&b[..2]) as u32
+        }
+    }
+
+    fn u64(&self, b: &[u8]) -> u64 {
+        if self.big_endian {
+            (self.u32(&b[..4]) as u64) << 32 | self.u32(&b[4..8]) as u64
+        } else {
+            (self.u32(&b[4..8]) as u64) << 32 | self.u32(&b[..4]) as u64
+        }
+    }
+
+    fn read_at(&mut self, offset: u64, len: usize) -> AnsResult<Vec<u8>> {
+        let mut data = vec![0u8; len];
+        try!(self.file.seek(SeekFrom::Start(offset)));
+        try!(self.file.read_exact(&mut data));
+        Ok(data)
+    }
+
+    // Numeric values of every entry and the offset of the next IFD. Values of types that are
+    // neither integers nor bytes are left empty.
+    fn ifd(&mut self, offset: u64) -> AnsResult<(Vec<(u16, Vec<u64>)>, u64)> {
+        let (count_len, entry_len, value_len) = if self.big_tiff { (8, 20, 8) } else { (2, 12, 4) };
+        let count_bytes = try!(self.read_at(offset, count_len));
+        let count = if self.big_tiff {
+            self.u64(&count_bytes)
+        } else {
+            self.u16(&count_bytes) as u64
+        };
+        let table = try!(self.read_at(offset + count_len as u64,
+                                      count as usize * entry_len + value_len));
+
+        let mut entries = vec![];
+        for entry in table.chunks(entry_len).take(count as usize) {
+            let tag = self.u16(&entry[..2]);
+            let size = match self.u16(&entry[2..4]) {
+                1 | 2 | 6 | 7 => 1,
+                3 | 8 => 2,
+                4 | 9 | 13 => 4,
+                16 | 17 | 18 => 8,
+                _ => {
+                    entries.push((tag, vec![]));
+                    continue;
+                }
+            };
+            let n = if self.big_tiff {
+                self.u64(&entry[4..12])
+            } else {
+                self.u32(&entry[4..8]) as u64
+            };
+            let inline = &entry[entry_len - value_len..];
+            let bytes = if n * size <= value_len as u64 {
+                inline[..(n * size) as usize].to_vec()
+            } else {
+                let at = if self.big_tiff { self.u64(inline) } else { self.u32(inline) as u64 };
+                try!(self.read_at(at, (n * size) as usize))
+            };
+            let values = bytes.chunks(size as usize)
+                .map(|b| {
+                    match size {
+                        1 => b[0] as u64,
+                        2 => self.u16(b) as u64,
+                        4 => self.u32(b) as u64,
+                        _ => self.u64(b),
+                    }
+                })
+                .collect();
+            entries.push((tag, values));
+        }
+        let next = &table[count as usize * entry_len..];
+        let next = if self.big_tiff { self.u64(next) } else { self.u32(next) as u64 };
+        Ok((entries, next))
+    }
+}
+
+fn level(path: &Path, entries: &[(u16, Vec<u64>)], big_endian: bool) -> AnsResult<Level> {
+    let value = |tag: u16| entries.iter().find(|e| e.0 == tag).and_then(|e| e.1.first().cloned());
+    let values = |tag: u16| entries.iter().find(|e| e.0 == tag).map(|e| e.1.clone());
+
+    let (width, height) = match (value(IMAGE_WIDTH), value(IMAGE_LENGTH)) {
+        (Some(width), Some(height)) if width > 0 && height > 0 => (width as u32, height as u32),
+        _ => return Err(invalid(path, "image without size")),
+    };
+    let bits = value(BITS_PER_SAMPLE).unwrap_or(1);
+    let same_bits = values(BITS_PER_SAMPLE).map_or(true, |b| b.iter().all(|&v| v == bits));
+    if (bits != 8 && bits != 16) || !same_bits {
+        return Err(invalid(path, "only 8 or 16 bits per sample are supported"));
+    }
+    if values(SAMPLE_FORMAT).map_or(false, |f| f.iter().any(|&v| v != 1)) {
+        return Err(invalid(path, "only unsigned integer samples are supported"));
+    }
+    if value(COMPRESSION) == Some(7) && bits != 8 {
+        return Err(invalid(path, "JPEG compression of more than 8 bits per sample"));
+    }
+    if value(PLANAR_CONFIGURATION).unwrap_or(1) != 1 {
+        return Err(invalid(path, "separate sample planes are not supported"));
+    }
+    let tiled = value(TILE_WIDTH).is_some();
+    let (tile_width, tile_height, offsets, byte_counts) = if tiled {
+        (value(TILE_WIDTH).unwrap() as u32,
+         value(TILE_LENGTH).unwrap_or(0) as u32,
+         values(TILE_OFFSETS),
+         values(TILE_BYTE_COUNTS))
+    } else {
+        (width,
+         value(ROWS_PER_STRIP).unwrap_or(height as u64).min(height as u64) as u32,
+         values(STRIP_OFFSETS),
+         values(STRIP_BYTE_COUNTS))
+    };
+    let (offsets, byte_counts) = match (offsets, byte_counts) {
+        (Some(offsets), Some(byte_counts)) if tile_width > 0 && tile_height > 0 => {
+            (offsets, byte_counts)
+        }
+        _ => return Err(invalid(path, "image without tile or strip offsets")),
+    };
+
+    Ok(Level {
+        width: width,
+        height: height,
+        tile_width: tile_width,
+        tile_height: tile_height,
+        offsets: offsets,
+        byte_counts: byte_counts,
+        compression: value(COMPRESSION).unwrap_or(1),
+        photometric: value(PHOTOMETRIC).unwrap_or(1),
+        samples: value(SAMPLES_PER_PIXEL).unwrap_or(1) as u32,
+        bits: bits as u32,
+        big_endian: big_endian,
+        predictor: value(PREDICTOR).unwrap_or(1),
+        jpeg_tables: values(JPEG_TABLES).map(|t| t.iter().map(|&b| b as u8).collect()),
+        tiled: tiled,
+        reduced: value(NEW_SUBFILE_TYPE).unwrap_or(0) & 1 == 1,
+    })
+}
+
+// TIFF flavour of LZW: codes are written most significant bit first and widen one code early
+fn lzw(data: &[u8]) -> Result<Vec<u8>, String> {
+    const CLEAR: usize = 256;
+    const END: usize = 257;
+    let mut prefix = vec![0usize; 4096];
+    let mut suffix = vec![0u8; 4096];
+    let mut length = vec![1usize; 4096];
+    for code in 0..256 {
+        suffix[code] = code as u8;
+    }
+
+    let mut out = vec![];
+    let mut next = 258;
+    let mut width = 9;
+    let mut previous: Option<usize> = None;
+    let (mut buffer, mut bits) = (0u32, 0);
+    let mut bytes = data.iter();
+    loop {
+        while bits < width {
+            match bytes.next() {
+                Some(&b) => buffer = buffer << 8 | b as u32,
+                None => return Ok(out),
+            }
+            bits += 8;
+        }
+        let code = (buffer >> (bits - width)) as usize & ((1 << width) - 1);
+        bits -= width;
+
+        if code == CLEAR {
+            next = 258;
+            width = 9;
+            previous = None;
+            continue;
+        }
+        if code == END {
+            return Ok(out);
+        }
+        let previous_code = match previous {
+            Some(previous_code) => previous_code,
+            None => {
+                if code >= 256 {
+                    return Err(format!("invalid LZW code {}", code));
+                }
+                out.push(code as u8);
+                previous = Some(code);
+                continue;
+            }
+        };
+        // A code not in the table yet is the previous string followed by its own first byte
+        let known = code < next;
+        if !known && code != next {
+            return Err(format!("invalid LZW code {}", code));
+        }
+        let start = out.len();
+        let string = if known { code } else { previous_code };
+        out.resize(start + length[string], 0);
+        let mut c = string;
+        for i in (start..start + length[string]).rev() {
+            out[i] = suffix[c];
+            c = prefix[c];
+        }
+        let first = out[start];
+        if !known {
+            out.push(first);
+        }
+
+        if next < 4096 {
+            prefix[next] = previous_code;
+            suffix[next] = first;
+            length[next] = length[previous_code] + 1;
+            next += 1;
+        }
+        if next + 1 >= 1 << width && width < 12 {
+            width += 1;
+        }
+        previous = Some(code);
+    }
+}
+
+fn inflate(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut stream = InflateStream::from_zlib();
+    let mut out = vec![];
+    let mut data = data;
+    while !data.is_empty() {
+        let (consumed, chunk) = try!(stream.update(data));
+        out.extend_from_slice(chunk);
+        if consumed == 0 && chunk.is_empty() {
+            break;
+        }
+        data = &data[consumed..];
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+
+    use img_reader::raster::Raster;
+    use super::{encode, lzw, TiledTiff};
+
+    // Reference encoder which widens the codes as libtiff does
+    fn lzw_encode(data: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        let (mut buffer, mut bits) = (0u32, 0);
+        let mut write = |code: usize, width: u32, out: &mut Vec<u8>| {
+            buffer = buffer << width | code as u32;
+            bits += width;
+            while bits >= 8 {
+                out.push((buffer >> (bits - 8)) as u8);
+                bits -= 8;
+            }
+        };
+        let mut table = HashMap::new();
+        let (mut next, mut width) = (258, 9);
+        write(256, width, &mut out);
+        let mut string: Option<usize> = None;
+        for &b in data {
+            let current = match string {
+                None => {
+                    string = Some(b as usize);
+                    continue;
+                }
+                Some(current) => current,
+            };
+            if let Some(&code) = table.get(&(current, b)) {
+                string = Some(code);
+                continue;
+            }
+            write(current, width, &mut out);
+            table.insert((current, b), next);
+            next += 1;
+            if next == 4094 {
+                write(256, width, &mut out);
+                table.clear();
+                next = 258;
+                width = 9;
+            } else if next > (1 << width) - 1 {
+                width += 1;
+            }
+            string = Some(b as usize);
+        }
+        if let Some(current) = string {
+            write(current, width, &mut out);
+        }
+        write(257, width, &mut out);
+        if bits > 0 {
+            out.push((buffer << (8 - bits)) as u8);
+        }
+        out
+    }
+
+    fn round_trip(name: &str, raster: &Raster) -> Raster {
+        let path = env::temp_dir().join(format!("ans_tiff_{}.tif", name));
+        File::create(&path).unwrap().write_all(&encode(raster)).unwrap();
+        let tiff = TiledTiff::open(&path).unwrap();
+        let (width, height) = raster.dimensions();
+        tiff.read_raster(0, 0, 0, width, height).unwrap()
+    }
+
+    fn gradient(width: u32, height: u32, channels: usize, bits: u8) -> Raster {
+        let max = (1u32 << bits) - 1;
+        let data = (0..width as usize * height as usize * channels)
+            .map(|i| (i as u32 * 7919 % (max + 1)) as u16)
+            .collect();
+        Raster::from_raw(width, height, channels, bits, data).unwrap()
+    }
+
+    #[test]
+    fn decodes_lzw_stream() {
+        // Example of the TIFF 6.0 specification
+        let data = [0x80, 0x01, 0xe0, 0x40, 0x80, 0x44, 0x08, 0x0c, 0x06, 0x80, 0x80];
+        assert_eq!(lzw(&data).unwrap(), vec![7, 7, 7, 8, 8, 7, 7, 6, 6]);
+        let text = b"TOBEORNOTTOBEORTOBEORNOT";
+        assert_eq!(lzw(&lzw_encode(text)).unwrap(), text.to_vec());
+    }
+
+    #[test]
+    fn decodes_wide_lzw_codes() {
+        // Enough distinct strings to widen the codes to 12 bits and clear the table twice
+        let data = (0..200000u64).map(|i| (i * i / 7 % 251) as u8).collect::<Vec<_>>();
+        assert_eq!(lzw(&lzw_encode(&data)).unwrap(), data);
+        assert!(lzw(&[0x80, 0x7f, 0xf0]).is_err());
+    }
+
+    #[test]
+    fn encodes_rasters() {
+        for &(name, channels, bits) in &[("rgb16", 3, 16), ("gray8", 1, 8), ("five", 5, 16)] {
+            let raster = gradient(37, 23, channels, bits);
+            assert_eq!(round_trip(name, &raster), raster);
+        }
+    }
+
+    #[test]
+    fn pads_regions_outside() {
+        let raster = gradient(10, 8, 3, 8);
+        let path = env::temp_dir().join("ans_tiff_outside.tif");
+        File::create(&path).unwrap().write_all(&encode(&raster)).unwrap();
+        let region = TiledTiff::open(&path).unwrap().read_raster(0, 6, 4, 8, 8).unwrap();
+        assert_eq!(region.pixel(0, 0), raster.pixel(6, 4));
+        assert_eq!(region.pixel(3, 3), raster.pixel(9, 7));
+        assert_eq!(region.pixel(4, 3), &[0, 0, 0]);
+        assert_eq!(region.pixel(0, 4), &[0, 0, 0]);
+    }
+}
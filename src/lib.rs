@@ -0,0 +1,10 @@
+#![feature(step_by)]
+
+extern crate image;
+extern crate xml;
+extern crate rand;
+extern crate inflate;
+
+pub mod ans;
+pub mod img_reader;
+pub mod error;
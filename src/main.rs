@@ -1,19 +1,14 @@
-#![allow(dead_code)]
-#![feature(step_by)]
-
 extern crate image;
-extern crate xml;
-extern crate rand;
 extern crate time;
+extern crate augment_n_split;
 
-mod ans;
-mod img_reader;
-
-// use std::fs::File;
+use std::io::{self, Write};
 use std::path::PathBuf;
-// use std::collections::HashMap;
+use std::process;
 use time::PreciseTime;
 
+use augment_n_split::{ans, error, img_reader};
+
 use img_reader::{ImgReader, LabelType};
 
 use image::*;
@@ -22,6 +17,8 @@ use ans::ans_builder::AugmentSplitBuilder;
 use ans::augment_split::FindLabel;
 use ans::label::Label;
 use ans::color_values;
+use ans::return_type::OutputPolicy;
+use error::AnsResult;
 
 struct Split {
     ratio: Option<f32>,
@@ -70,11 +67,13 @@ impl FindLabel for Oversample {
 
 
 fn main() {
-    // let config_path = PathBuf::from("/home/robert/Projects/rust/AugmentNSplit/test_config.xml");
-
-    // let training_path = PathBuf::from("/media/robert/Lokaler Datenträger/BachelorArbeit/Bilder/images");
-    // let label_path = PathBuf::from("/media/robert/Lokaler Datenträger/BachelorArbeit/Bilder/mask_and");
+    if let Err(e) = run() {
+        let _ = writeln!(io::stderr(), "{}", e);
+        process::exit(1);
+    }
+}
 
+fn run() -> AnsResult<()> {
     let training_path = PathBuf::from("/media/robert/Lokaler \
                                        Datenträger/BachelorArbeit/Bilder/subset");
     let label_path = PathBuf::from("/media/robert/Lokaler \
@@ -83,16 +82,18 @@ fn main() {
     let label_type = LabelType::Img(label_path);
 
     let now = PreciseTime::now();
-    let mut augment_split = AugmentSplitBuilder::new()
+    let mut augment_split = try!(AugmentSplitBuilder::new()
         .set_img_dir(training_path)
         .set_label_type(label_type)
         .set_split_size(Some((224u32, 224u32)))
         .set_split_offset((Some(SplitOffset::Val(190u32)), Some(SplitOffset::Val(190u32))))
         .set_img_type(ImageFormat::PNG)
         .with_rotation()
+        .set_augmentation_config("test_config.xml")
+        .set_output_policy(OutputPolicy::Overwrite)
         .set_output_real("data/3Jul/train/real")
         .set_output_mask("data/3Jul/train/mask")
-        .build();
+        .build());
 
 
     let finish = PreciseTime::now();
@@ -100,7 +101,10 @@ fn main() {
     println!("{:?} ns to create Ans struct", duration.num_nanoseconds());
 
     let now = PreciseTime::now();
-    let mut img_reader = ImgReader::new(augment_split.get_imgdir(), augment_split.get_label_type());
+    let mut img_reader = try!(ImgReader::new(augment_split.get_imgdir(),
+                                             augment_split.get_label_type(),
+                                             augment_split.get_stain_normalization(),
+                                             augment_split.get_error_policy()));
     let finish = PreciseTime::now();
     let duration = now.to(finish);
     println!("{:?} ms to create img_reader", duration.num_milliseconds());
@@ -111,43 +115,24 @@ fn main() {
     let mut s = Split { ratio: None };
     let cv = color_values::ColorValues::white_luma();
 
-    augment_split.split(&mut img_reader, cv, &mut s);
+    let split_stats = try!(augment_split.split(&mut img_reader, &cv, &mut s));
 
     let mut os = Oversample { ratio: None };
-    augment_split.oversample(&mut img_reader, 0.0004, cv, &mut os);
+    let oversample_stats = try!(augment_split.oversample(&mut img_reader, 0.0004, &cv, &mut os));
     let finish = PreciseTime::now();
     let duration = now.to(finish);
     println!("{:?} ms to split images", duration.num_milliseconds());
 
-    // let now = PreciseTime::now();
-    // augment_split.build_sick(&mut img_reader);
-    // let finish = PreciseTime::now();
-    // let duration = now.to(finish);
-    // println!("{:?} ms to split images", duration.num_milliseconds());
-    // let now = PreciseTime::now();
-    // ans.build_healthy(&mut img_reader);
-    // let finish = PreciseTime::now();
-    // let duration = now.to(finish);
-    // println!("{:?} ms to split Healthy images", duration.num_milliseconds());
-    //
-    // let now = PreciseTime::now();
-    // ans.build_sick(&mut img_reader);
-    // let finish = PreciseTime::now();
-    // let duration = now.to(finish);
-    // println!("{:?} ms to split Sick images", duration.num_milliseconds());
-    //
-    // let path = Path::new("/media/robert/Lokaler Datenträger/BachelorArbeit/Bilder/training_data/pat18_im3_ACHD_0_960.png");
-    // let mut img = open(&path).unwrap().to_rgb();
-    // let img = imageops::crop(&mut img, 0, 0, 224, 224);
-    //
-    // let color_info = Ans::get_color([0, 0, 0], &img);
-    //
-    // let pixels = {
-    // let p = img.dimensions();
-    // p.0 * p.1
-    // };
-    //
-    // println!("Pixels: {}, black pixels {}, ratio {}", pixels, color_info.1, color_info.1 / pixels as f32);
-    //
+    print!("{}", split_stats);
+    print!("{}", oversample_stats);
+
+    // Source images left out because of the error policy
+    if !img_reader.report.is_empty() {
+        let _ = write!(io::stderr(), "{}", img_reader.report);
+    }
+    if !augment_split.get_report().is_empty() {
+        let _ = write!(io::stderr(), "{}", augment_split.get_report());
+    }
 
+    Ok(())
 }